@@ -0,0 +1,516 @@
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serenity::client::Context;
+use serenity::model::id::{ChannelId, GuildId, UserId};
+
+use crate::error::BotError;
+
+/// Limits raid alerts to one per cooldown window per guild.
+pub struct AlertCooldowns {
+    cooldown: Duration,
+    windows: Mutex<HashMap<GuildId, Window>>,
+}
+
+struct Window {
+    since: Instant,
+    /// Number of alerts suppressed since the window started.
+    suppressed: u32,
+}
+
+impl AlertCooldowns {
+    pub fn new(cooldown: Duration) -> Self {
+        Self {
+            cooldown,
+            windows: Mutex::default(),
+        }
+    }
+
+    pub fn cooldown(&self) -> Duration {
+        self.cooldown
+    }
+
+    /// Returns whether an alert should be sent now,
+    /// starting a new cooldown window if so.
+    ///
+    /// The returned `Instant` identifies the new window for `end_window`.
+    pub fn try_alert(&self, guild: GuildId) -> Option<Instant> {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+        match windows.get_mut(&guild) {
+            Some(window) if now.duration_since(window.since) < self.cooldown => {
+                window.suppressed += 1;
+                None
+            }
+            _ => {
+                windows.insert(
+                    guild,
+                    Window {
+                        since: now,
+                        suppressed: 0,
+                    },
+                );
+                Some(now)
+            }
+        }
+    }
+
+    /// Ends the cooldown window of the guild started at `since`,
+    /// returning the number of alerts suppressed during it.
+    ///
+    /// Does nothing if a newer window has already started.
+    pub fn end_window(&self, guild: GuildId, since: Instant) -> u32 {
+        let mut windows = self.windows.lock().unwrap();
+        match windows.get(&guild) {
+            Some(window) if window.since == since => {
+                let suppressed = window.suppressed;
+                windows.remove(&guild);
+                suppressed
+            }
+            _ => 0,
+        }
+    }
+}
+
+/// Tracks which guilds are currently being raided.
+#[derive(Default)]
+pub struct RaidStates {
+    raiding: Mutex<HashSet<GuildId>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RaidTransition {
+    /// The previous join was normal and this one is abnormal.
+    Started,
+    /// The previous join was abnormal and this one is normal.
+    Ended,
+    Unchanged,
+}
+
+impl RaidStates {
+    /// Returns the number of guilds whose latest join was abnormal.
+    pub fn count(&self) -> usize {
+        self.raiding.lock().unwrap().len()
+    }
+
+    /// Returns whether the latest join of the guild was abnormal.
+    pub fn is_raiding(&self, guild: GuildId) -> bool {
+        self.raiding.lock().unwrap().contains(&guild)
+    }
+
+    /// Records whether the latest join of the guild was abnormal.
+    pub fn update(&self, guild: GuildId, abnormal: bool) -> RaidTransition {
+        let mut raiding = self.raiding.lock().unwrap();
+        let changed = match abnormal {
+            true => raiding.insert(guild),
+            false => raiding.remove(&guild),
+        };
+        match (changed, abnormal) {
+            (true, true) => RaidTransition::Started,
+            (true, false) => RaidTransition::Ended,
+            (false, _) => RaidTransition::Unchanged,
+        }
+    }
+}
+
+/// Counts the consecutive abnormal joins of each guild.
+#[derive(Default)]
+pub struct AbnormalStreaks {
+    streaks: Mutex<HashMap<GuildId, u32>>,
+}
+
+impl AbnormalStreaks {
+    /// Records whether the latest join of the guild was abnormal,
+    /// returning the number of consecutive abnormal joins up to it.
+    pub fn update(&self, guild: GuildId, abnormal: bool) -> u32 {
+        let mut streaks = self.streaks.lock().unwrap();
+        if !abnormal {
+            streaks.remove(&guild);
+            return 0;
+        }
+        let streak = streaks.entry(guild).or_insert(0);
+        *streak += 1;
+        *streak
+    }
+}
+
+/// Counts the alerts of each guild held back until its quiet hours end.
+#[derive(Default)]
+pub struct HeldAlerts {
+    held: Mutex<HashMap<GuildId, u32>>,
+}
+
+impl HeldAlerts {
+    /// Holds back an alert, returning whether it is the first one held since the last release.
+    pub fn hold(&self, guild: GuildId) -> bool {
+        let mut held = self.held.lock().unwrap();
+        let count = held.entry(guild).or_insert(0);
+        *count += 1;
+        *count == 1
+    }
+
+    /// Returns the number of alerts held back since the last release.
+    pub fn release(&self, guild: GuildId) -> u32 {
+        self.held.lock().unwrap().remove(&guild).unwrap_or(0)
+    }
+}
+
+/// Channels that no longer exist are not retried for this long.
+const BAD_CHANNEL_WINDOW: Duration = Duration::from_secs(600);
+
+/// Sends alerts to channels, retrying transient failures.
+pub struct AlertDelivery {
+    retries: u32,
+    /// Channels found not to exist, with the time they were found so.
+    bad_channels: Mutex<HashMap<ChannelId, Instant>>,
+}
+
+impl AlertDelivery {
+    pub fn new(retries: u32) -> Self {
+        Self {
+            retries,
+            bad_channels: Mutex::default(),
+        }
+    }
+
+    /// Sends `content` to all `channels`.
+    ///
+    /// Failure to send to one channel does not prevent sending to the others.
+    /// Alerts for channels that no longer exist are DMed to the `fallback` admin instead, if any.
+    pub async fn send(
+        &self,
+        ctx: &Context,
+        guild: GuildId,
+        channels: &[ChannelId],
+        content: &str,
+        fallback: Option<UserId>,
+    ) {
+        self.deliver(
+            guild,
+            channels,
+            move |channel| self.try_send(ctx, channel, content),
+            move |channel| self.send_fallback(ctx, guild, channel, content, fallback),
+        )
+        .await
+    }
+
+    /// Sends an alert to `channels` with `send_to`,
+    /// or with `fall_back` for channels that no longer exist.
+    async fn deliver<S, SF, F, FF>(
+        &self,
+        guild: GuildId,
+        channels: &[ChannelId],
+        mut send_to: S,
+        mut fall_back: F,
+    ) where
+        S: FnMut(ChannelId) -> SF,
+        SF: Future<Output = Result<(), BotError>>,
+        F: FnMut(ChannelId) -> FF,
+        FF: Future<Output = ()>,
+    {
+        for &channel in channels {
+            if self.is_bad(channel) {
+                fall_back(channel).await;
+                continue;
+            }
+            match send_to(channel).await {
+                Ok(()) => (),
+                Err(err) if err.is_unknown_channel() => {
+                    log::warn!(
+                        "Alert channel {} of guild {} no longer exists, please update the config",
+                        channel,
+                        guild
+                    );
+                    self.bad_channels
+                        .lock()
+                        .unwrap()
+                        .insert(channel, Instant::now());
+                    fall_back(channel).await;
+                }
+                Err(err) => log::error!(
+                    "Cannot send alert to channel {} of guild {}: {}",
+                    channel,
+                    guild,
+                    err
+                ),
+            }
+        }
+    }
+
+    /// Sends `content` to one channel, retrying transient failures,
+    /// without falling back to the admin.
+    pub async fn try_send(
+        &self,
+        ctx: &Context,
+        channel: ChannelId,
+        content: &str,
+    ) -> Result<(), BotError> {
+        retrying(self.retries, || async {
+            channel.say(ctx, content).await?;
+            Ok(())
+        })
+        .await
+    }
+
+    fn is_bad(&self, channel: ChannelId) -> bool {
+        let mut bad_channels = self.bad_channels.lock().unwrap();
+        match bad_channels.get(&channel) {
+            Some(since) if since.elapsed() < BAD_CHANNEL_WINDOW => true,
+            Some(_) => {
+                bad_channels.remove(&channel);
+                false
+            }
+            None => false,
+        }
+    }
+
+    async fn send_fallback(
+        &self,
+        ctx: &Context,
+        guild: GuildId,
+        channel: ChannelId,
+        content: &str,
+        fallback: Option<UserId>,
+    ) {
+        let admin = match fallback {
+            Some(admin) => admin,
+            None => return,
+        };
+        let content = format!(
+            "Cannot send this alert to the missing channel {} of guild {}:\n{}",
+            channel, guild, content
+        );
+        let sent = retrying(self.retries, || async {
+            admin
+                .create_dm_channel(ctx)
+                .await?
+                .say(ctx, &content)
+                .await?;
+            Ok(())
+        })
+        .await;
+        if let Err(err) = sent {
+            log::error!("Cannot DM alert to admin {}: {}", admin, err);
+        }
+    }
+}
+
+/// Delay before the first retry in `retrying`, doubled after each retry.
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// Runs `f`, retrying up to `retries` times with exponential backoff
+/// as long as it fails with a transient error.
+pub async fn retrying<F, R, T>(retries: u32, mut f: F) -> Result<T, BotError>
+where
+    F: FnMut() -> R,
+    R: Future<Output = Result<T, BotError>>,
+{
+    let mut delay = RETRY_BASE_DELAY;
+    let mut attempt = 0;
+    loop {
+        match f().await {
+            Err(err) if err.is_transient() && attempt < retries => {
+                log::warn!("Retrying in {:?} after transient error: {}", delay, err);
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GUILD: GuildId = GuildId(1);
+
+    #[test]
+    fn held_alerts_are_summarized_once() {
+        let held = HeldAlerts::default();
+        assert!(held.hold(GUILD));
+        assert!(!held.hold(GUILD));
+        assert!(!held.hold(GUILD));
+        assert!(held.hold(GuildId(2)));
+        assert_eq!(held.release(GUILD), 3);
+        assert_eq!(held.release(GUILD), 0);
+        assert!(held.hold(GUILD));
+    }
+
+    #[test]
+    fn rapid_abnormal_joins_alert_once() {
+        let cooldowns = AlertCooldowns::new(Duration::from_secs(600));
+        let alerts: Vec<_> = (0..20).filter_map(|_| cooldowns.try_alert(GUILD)).collect();
+        assert_eq!(alerts.len(), 1);
+        // other guilds have their own windows
+        assert!(cooldowns.try_alert(GuildId(2)).is_some());
+
+        assert_eq!(cooldowns.end_window(GUILD, alerts[0]), 19);
+        assert!(cooldowns.try_alert(GUILD).is_some());
+    }
+
+    #[test]
+    fn stale_windows_are_not_ended() {
+        let cooldowns = AlertCooldowns::new(Duration::ZERO);
+        let first = cooldowns.try_alert(GUILD).unwrap();
+        std::thread::sleep(Duration::from_millis(1));
+        // the window is over, so the next alert starts a new one
+        let second = cooldowns.try_alert(GUILD).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(cooldowns.end_window(GUILD, first), 0);
+        assert!(cooldowns.try_alert(GUILD).is_some());
+    }
+
+    #[test]
+    fn raids_start_and_end_once() {
+        let raids = RaidStates::default();
+        assert_eq!(raids.update(GUILD, false), RaidTransition::Unchanged);
+        assert_eq!(raids.update(GUILD, true), RaidTransition::Started);
+        assert_eq!(raids.update(GUILD, true), RaidTransition::Unchanged);
+        assert!(raids.is_raiding(GUILD));
+        assert_eq!(raids.update(GuildId(2), true), RaidTransition::Started);
+        assert_eq!(raids.count(), 2);
+
+        assert_eq!(raids.update(GUILD, false), RaidTransition::Ended);
+        assert_eq!(raids.update(GUILD, false), RaidTransition::Unchanged);
+        assert!(!raids.is_raiding(GUILD));
+        assert_eq!(raids.count(), 1);
+    }
+
+    fn transient_error() -> BotError {
+        serenity::Error::Io(std::io::ErrorKind::ConnectionReset.into()).into()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn transient_failures_are_retried_with_backoff() {
+        let started = tokio::time::Instant::now();
+        let mut attempts = 0;
+        let result = retrying(3, || {
+            attempts += 1;
+            let attempt = attempts;
+            async move {
+                match attempt {
+                    1 | 2 => Err(transient_error()),
+                    _ => Ok(attempt),
+                }
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap(), 3);
+        // 1 second, then 2 seconds
+        assert_eq!(started.elapsed(), Duration::from_secs(3));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_are_bounded() {
+        let mut attempts = 0;
+        let result: Result<(), _> = retrying(2, || {
+            attempts += 1;
+            async { Err(transient_error()) }
+        })
+        .await;
+        assert!(result.unwrap_err().is_transient());
+        assert_eq!(attempts, 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn other_failures_are_not_retried() {
+        let mut attempts = 0;
+        let result: Result<(), _> = retrying(3, || {
+            attempts += 1;
+            async { Err(BotError::Io(std::io::ErrorKind::PermissionDenied.into())) }
+        })
+        .await;
+        assert!(!result.unwrap_err().is_transient());
+        assert_eq!(attempts, 1);
+    }
+
+    fn unknown_channel_error() -> BotError {
+        let response = serenity::http::error::ErrorResponse {
+            status_code: serenity::http::StatusCode::NOT_FOUND,
+            url: "https://discord.com/api/v10/channels/1/messages"
+                .parse()
+                .unwrap(),
+            error: serde_json::from_str(r#"{"code": 10003, "message": "Unknown Channel"}"#)
+                .unwrap(),
+        };
+        serenity::Error::Http(Box::new(serenity::http::HttpError::UnsuccessfulRequest(
+            response,
+        )))
+        .into()
+    }
+
+    #[tokio::test]
+    async fn missing_channels_fall_back_until_the_window_ends() {
+        let delivery = AlertDelivery::new(3);
+        let (missing, present) = (ChannelId(1), ChannelId(2));
+        let sent = Mutex::new(Vec::new());
+        let fell_back = Mutex::new(Vec::new());
+        for _ in 0..2 {
+            delivery
+                .deliver(
+                    GUILD,
+                    &[missing, present],
+                    |channel| {
+                        sent.lock().unwrap().push(channel);
+                        async move {
+                            match channel == missing {
+                                true => Err(unknown_channel_error()),
+                                false => Ok(()),
+                            }
+                        }
+                    },
+                    |channel| {
+                        fell_back.lock().unwrap().push(channel);
+                        async {}
+                    },
+                )
+                .await;
+        }
+        // the missing channel is only tried once within the window
+        assert_eq!(*sent.lock().unwrap(), [missing, present, present]);
+        assert_eq!(*fell_back.lock().unwrap(), [missing, missing]);
+        assert!(unknown_channel_error().is_unknown_channel());
+        assert!(!unknown_channel_error().is_transient());
+    }
+
+    #[tokio::test]
+    async fn other_failures_do_not_fall_back() {
+        let delivery = AlertDelivery::new(3);
+        let mut fell_back = false;
+        delivery
+            .deliver(
+                GUILD,
+                &[ChannelId(1)],
+                |_| async { Err(transient_error()) },
+                |_| {
+                    fell_back = true;
+                    async {}
+                },
+            )
+            .await;
+        assert!(!fell_back);
+        assert!(!delivery.is_bad(ChannelId(1)));
+    }
+
+    #[test]
+    fn alerts_wait_for_consecutive_abnormal_joins() {
+        const REQUIRED: u32 = 2;
+        let streaks = AbnormalStreaks::default();
+        let alerts = |abnormal| streaks.update(GUILD, abnormal) >= REQUIRED;
+
+        // a single abnormal join is noise
+        assert!(!alerts(true));
+        assert!(!alerts(false));
+        assert!(!alerts(true));
+        assert!(alerts(true));
+        assert!(alerts(true));
+        // other guilds have their own streaks
+        assert_eq!(streaks.update(GuildId(2), true), 1);
+        assert!(!alerts(false));
+        assert!(!alerts(true));
+    }
+}
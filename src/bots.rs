@@ -0,0 +1,110 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+
+use serenity::model::id::GuildId;
+
+/// Tracks which of the bots run by this process are in each guild,
+/// so that only one of them handles the events of a guild.
+///
+/// Bots are identified by their index in the `discord` config,
+/// and earlier bots take precedence.
+#[derive(Default)]
+pub struct BotPresence {
+    guilds: Mutex<HashMap<GuildId, BTreeSet<usize>>>,
+    /// The number of times all bots left each guild.
+    departures: Mutex<HashMap<GuildId, u64>>,
+}
+
+impl BotPresence {
+    pub fn join(&self, guild: GuildId, bot: usize) {
+        self.guilds
+            .lock()
+            .unwrap()
+            .entry(guild)
+            .or_default()
+            .insert(bot);
+    }
+
+    /// Returns the departure from the guild if it was the last bot in it.
+    pub fn leave(&self, guild: GuildId, bot: usize) -> Option<u64> {
+        let mut guilds = self.guilds.lock().unwrap();
+        let bots = guilds.get_mut(&guild)?;
+        bots.remove(&bot);
+        if !bots.is_empty() {
+            return None;
+        }
+        guilds.remove(&guild);
+        let mut departures = self.departures.lock().unwrap();
+        let departure = departures.entry(guild).or_default();
+        *departure += 1;
+        Some(*departure)
+    }
+
+    /// Returns whether no bot joined the guild again since `departure`,
+    /// including ones that left again later.
+    pub fn is_gone_since(&self, guild: GuildId, departure: u64) -> bool {
+        let guilds = self.guilds.lock().unwrap();
+        !guilds.contains_key(&guild)
+            && self.departures.lock().unwrap().get(&guild) == Some(&departure)
+    }
+
+    /// Returns whether any bot is known to be in the guild.
+    pub fn is_present(&self, guild: GuildId) -> bool {
+        self.guilds.lock().unwrap().contains_key(&guild)
+    }
+
+    /// Returns whether `bot` is the first bot in the guild.
+    ///
+    /// Before any bot is known to be in the guild, only the first bot handles it.
+    pub fn is_leader(&self, guild: GuildId, bot: usize) -> bool {
+        match self.guilds.lock().unwrap().get(&guild) {
+            Some(bots) => bots.iter().next() == Some(&bot),
+            None => bot == 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GUILD: GuildId = GuildId(1);
+
+    #[test]
+    fn earlier_bots_lead() {
+        let presence = BotPresence::default();
+        assert!(presence.is_leader(GUILD, 0));
+        assert!(!presence.is_leader(GUILD, 1));
+        presence.join(GUILD, 1);
+        presence.join(GUILD, 2);
+        assert!(presence.is_leader(GUILD, 1));
+        presence.join(GUILD, 0);
+        assert!(presence.is_leader(GUILD, 0));
+        assert!(!presence.is_leader(GUILD, 1));
+    }
+
+    #[test]
+    fn only_the_last_bot_departs() {
+        let presence = BotPresence::default();
+        presence.join(GUILD, 0);
+        presence.join(GUILD, 1);
+        assert_eq!(presence.leave(GUILD, 0), None);
+        assert!(presence.is_present(GUILD));
+        let departure = presence.leave(GUILD, 1).unwrap();
+        assert!(!presence.is_present(GUILD));
+        assert!(presence.is_gone_since(GUILD, departure));
+        assert_eq!(presence.leave(GUILD, 1), None);
+    }
+
+    #[test]
+    fn rejoining_supersedes_a_departure() {
+        let presence = BotPresence::default();
+        presence.join(GUILD, 0);
+        let first = presence.leave(GUILD, 0).unwrap();
+        presence.join(GUILD, 0);
+        assert!(!presence.is_gone_since(GUILD, first));
+        let second = presence.leave(GUILD, 0).unwrap();
+        assert!(!presence.is_gone_since(GUILD, first));
+        assert!(presence.is_gone_since(GUILD, second));
+    }
+}
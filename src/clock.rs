@@ -0,0 +1,64 @@
+use std::time::UNIX_EPOCH;
+
+/// Source of the current time for join statistics.
+pub trait Clock: Send + Sync {
+    /// Seconds since the unix epoch.
+    fn now_secs(&self) -> u64;
+
+    fn now_hour(&self) -> u64 {
+        self.now_secs() / 3600
+    }
+
+    fn now_minute(&self) -> u64 {
+        self.now_secs() / 60
+    }
+}
+
+/// Reads the system clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_secs(&self) -> u64 {
+        match UNIX_EPOCH.elapsed() {
+            Ok(elapsed) => elapsed.as_secs(),
+            Err(_) => {
+                // treated like any other backwards clock jump by the callers
+                log::warn!("System clock is earlier than unix epoch");
+                0
+            }
+        }
+    }
+}
+
+/// A clock that only moves when told to.
+#[cfg(test)]
+pub struct MockClock(std::sync::atomic::AtomicU64);
+
+#[cfg(test)]
+impl MockClock {
+    pub fn new(secs: u64) -> Self {
+        Self(secs.into())
+    }
+
+    pub fn advance(&self, secs: u64) {
+        self.0.fetch_add(secs, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    pub fn set(&self, secs: u64) {
+        self.0.store(secs, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now_secs(&self) -> u64 {
+        self.0.load(std::sync::atomic::Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+impl<C: Clock> Clock for std::sync::Arc<C> {
+    fn now_secs(&self) -> u64 {
+        (**self).now_secs()
+    }
+}
@@ -0,0 +1,80 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use serde::Deserialize;
+use serenity::model::id::GuildId;
+
+/// Detects many joining accounts created around the same time.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct CreationClusterConfig {
+    pub enabled: bool,
+    /// Number of recent joiners to remember per guild.
+    pub sample_size: usize,
+    /// Accounts created within this many hours of each other are in the same cluster.
+    pub window_hours: u64,
+    /// The alert mentions the cluster if at least this fraction of recent joiners are in it.
+    pub min_fraction: f64,
+}
+
+impl Default for CreationClusterConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            sample_size: 20,
+            window_hours: 24,
+            min_fraction: 0.5,
+        }
+    }
+}
+
+/// Account creation times of the recent joiners of each guild.
+#[derive(Default)]
+pub struct CreationTimes {
+    guilds: Mutex<HashMap<GuildId, VecDeque<i64>>>,
+}
+
+impl CreationTimes {
+    pub fn record(&self, config: &CreationClusterConfig, guild: GuildId, created_at: i64) {
+        let mut guilds = self.guilds.lock().unwrap();
+        let times = guilds.entry(guild).or_default();
+        times.push_back(created_at);
+        while times.len() > config.sample_size {
+            times.pop_front();
+        }
+    }
+
+    /// Describes the largest cluster of creation times among the recent joiners,
+    /// if it is large enough to be suspicious.
+    pub fn describe(&self, config: &CreationClusterConfig, guild: GuildId) -> Option<String> {
+        let mut times: Vec<_> = match self.guilds.lock().unwrap().get(&guild) {
+            Some(times) => times.iter().copied().collect(),
+            None => return None,
+        };
+        times.sort_unstable();
+
+        let clustered = largest_cluster(&times, (config.window_hours * 3600) as i64);
+        if times.len() < 2 || (clustered as f64) < (times.len() as f64) * config.min_fraction {
+            return None;
+        }
+        Some(format!(
+            "{} of last {} joiners created within {}h",
+            clustered,
+            times.len(),
+            config.window_hours
+        ))
+    }
+}
+
+/// Returns the largest number of sorted `times` that fit in a window of `window` length.
+pub fn largest_cluster(times: &[i64], window: i64) -> usize {
+    let mut start = 0;
+    let mut largest = 0;
+    for end in 0..times.len() {
+        while times[end] - times[start] > window {
+            start += 1;
+        }
+        largest = largest.max(end - start + 1);
+    }
+    largest
+}
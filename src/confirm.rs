@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serenity::model::id::{MessageId, UserId};
+
+/// Destructive admin commands are cancelled if not confirmed within this long.
+pub const CONFIRM_WINDOW: Duration = Duration::from_secs(30);
+
+/// Admin commands that only run after a confirmation.
+pub fn is_destructive(sub: &str) -> bool {
+    matches!(sub, "stop" | "reset" | "restore")
+}
+
+/// Destructive admin commands waiting for confirmation by the admin who ran them.
+///
+/// Commands from the control interface are keyed by `None`.
+#[derive(Default)]
+pub struct PendingConfirmations {
+    pending: Mutex<HashMap<Option<UserId>, Pending>>,
+}
+
+struct Pending {
+    command: String,
+    since: Instant,
+    /// The message asking for confirmation, which can be confirmed by reacting to it.
+    prompt: Option<MessageId>,
+}
+
+impl PendingConfirmations {
+    /// Starts waiting for confirmation of `command`,
+    /// replacing the previous command waiting for the same admin.
+    pub fn request(&self, user: Option<UserId>, command: String, now: Instant) {
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, pending| now.duration_since(pending.since) < CONFIRM_WINDOW);
+        pending.insert(
+            user,
+            Pending {
+                command,
+                since: now,
+                prompt: None,
+            },
+        );
+    }
+
+    /// Records the message asking the admin for confirmation,
+    /// returning whether a command without one is waiting for it.
+    pub fn set_prompt(&self, user: UserId, prompt: MessageId) -> bool {
+        match self.pending.lock().unwrap().get_mut(&Some(user)) {
+            Some(pending) if pending.prompt.is_none() => {
+                pending.prompt = Some(prompt);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns whether `command` was waiting for confirmation by the admin,
+    /// which stops waiting for it.
+    pub fn confirm(&self, user: Option<UserId>, command: &str, now: Instant) -> bool {
+        let mut pending = self.pending.lock().unwrap();
+        match pending.get(&user) {
+            Some(pending) if pending.command != command => return false,
+            Some(_) => (),
+            None => return false,
+        }
+        let pending = pending.remove(&user).expect("checked above");
+        now.duration_since(pending.since) < CONFIRM_WINDOW
+    }
+
+    /// Returns the command confirmed by the admin reacting to `prompt`,
+    /// which stops waiting for it.
+    pub fn confirm_prompt(&self, user: UserId, prompt: MessageId, now: Instant) -> Option<String> {
+        let mut pending = self.pending.lock().unwrap();
+        match pending.get(&Some(user)) {
+            Some(pending) if pending.prompt == Some(prompt) => (),
+            _ => return None,
+        }
+        let pending = pending.remove(&Some(user)).expect("checked above");
+        (now.duration_since(pending.since) < CONFIRM_WINDOW).then_some(pending.command)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADMIN: UserId = UserId(3);
+
+    #[test]
+    fn confirmed_commands_run_once() {
+        let pending = PendingConfirmations::default();
+        let now = Instant::now();
+        pending.request(Some(ADMIN), "stop".into(), now);
+        assert!(!pending.confirm(Some(ADMIN), "reset", now));
+        assert!(pending.confirm(Some(ADMIN), "stop", now + Duration::from_secs(29)));
+        assert!(!pending.confirm(Some(ADMIN), "stop", now + Duration::from_secs(29)));
+    }
+
+    #[test]
+    fn late_confirmations_are_refused() {
+        let pending = PendingConfirmations::default();
+        let now = Instant::now();
+        pending.request(Some(ADMIN), "stop".into(), now);
+        assert!(!pending.confirm(Some(ADMIN), "stop", now + CONFIRM_WINDOW));
+
+        pending.request(Some(ADMIN), "reset".into(), now);
+        assert!(pending.set_prompt(ADMIN, MessageId(5)));
+        assert_eq!(
+            pending.confirm_prompt(ADMIN, MessageId(5), now + CONFIRM_WINDOW),
+            None
+        );
+    }
+
+    #[test]
+    fn other_users_cannot_confirm() {
+        let pending = PendingConfirmations::default();
+        let now = Instant::now();
+        pending.request(Some(ADMIN), "stop".into(), now);
+        assert!(pending.set_prompt(ADMIN, MessageId(5)));
+        assert!(!pending.confirm(Some(UserId(4)), "stop", now));
+        assert!(!pending.confirm(None, "stop", now));
+        assert_eq!(pending.confirm_prompt(UserId(4), MessageId(5), now), None);
+        // reactions to other messages do not confirm it either
+        assert_eq!(pending.confirm_prompt(ADMIN, MessageId(6), now), None);
+
+        assert_eq!(
+            pending.confirm_prompt(ADMIN, MessageId(5), now).as_deref(),
+            Some("stop")
+        );
+    }
+}
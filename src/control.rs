@@ -0,0 +1,169 @@
+//! Local control interface for running admin commands without Discord.
+//!
+//! Clients send one command per line, e.g. `save`, `stop` or `stat <guild>`,
+//! and receive the reply followed by an empty line.
+//! There is no authentication: anyone who can connect is trusted,
+//! so only bind to a loopback address or a private socket path.
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, UnixListener};
+
+/// Where the control interface listens.
+pub enum ControlAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl ControlAddr {
+    /// Parses a TCP socket address, or else a UNIX socket path.
+    pub fn parse(addr: &str) -> Self {
+        match addr.parse() {
+            Ok(addr) => Self::Tcp(addr),
+            Err(_) => Self::Unix(PathBuf::from(addr)),
+        }
+    }
+}
+
+/// Serves the control interface on `addr` until `shutdown` completes,
+/// replying to each command line with the result of `handle`.
+pub async fn serve<F, R>(
+    addr: ControlAddr,
+    handle: F,
+    shutdown: impl Future<Output = ()>,
+) -> std::io::Result<()>
+where
+    F: Fn(String) -> R + Send + Sync + 'static,
+    R: Future<Output = String> + Send + 'static,
+{
+    let handle = Arc::new(handle);
+    tokio::pin!(shutdown);
+    match addr {
+        ControlAddr::Tcp(addr) => {
+            let listener = TcpListener::bind(addr).await?;
+            log::info!("Serving control interface on {}", addr);
+            loop {
+                tokio::select! {
+                    accepted = listener.accept() => {
+                        let (stream, _) = accepted?;
+                        tokio::spawn(session(stream, Arc::clone(&handle)));
+                    }
+                    () = &mut shutdown => break,
+                }
+            }
+        }
+        ControlAddr::Unix(path) => {
+            // a socket file left behind by a previous run prevents binding
+            let _ = std::fs::remove_file(&path);
+            let listener = UnixListener::bind(&path)?;
+            {
+                use std::os::unix::fs::PermissionsExt as _;
+                std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+            }
+            log::info!("Serving control interface on {}", path.display());
+            loop {
+                tokio::select! {
+                    accepted = listener.accept() => {
+                        let (stream, _) = accepted?;
+                        tokio::spawn(session(stream, Arc::clone(&handle)));
+                    }
+                    () = &mut shutdown => break,
+                }
+            }
+            let _ = std::fs::remove_file(&path);
+        }
+    }
+    log::info!("Control interface stopped");
+    Ok(())
+}
+
+async fn session<S, F, R>(stream: S, handle: Arc<F>)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: Fn(String) -> R,
+    R: Future<Output = String>,
+{
+    let (read, mut write) = tokio::io::split(stream);
+    let mut lines = BufReader::new(read).lines();
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => return,
+            Err(err) => {
+                log::debug!("Error reading control command: {}", err);
+                return;
+            }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut reply = handle(line).await;
+        reply.truncate(reply.trim_end().len());
+        reply += "\n\n";
+        if let Err(err) = write.write_all(reply.as_bytes()).await {
+            log::debug!("Error writing control reply: {}", err);
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::UnixStream;
+
+    #[tokio::test]
+    async fn commands_are_answered_over_a_unix_socket() {
+        let path =
+            std::env::temp_dir().join(format!("boredphoton-control-{}.sock", std::process::id()));
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve(
+            ControlAddr::Unix(path.clone()),
+            |cmd| async move { format!("ran `{}`\n", cmd) },
+            async {
+                let _ = stopped.await;
+            },
+        ));
+        let stream = loop {
+            match UnixStream::connect(&path).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::task::yield_now().await,
+            }
+        };
+        {
+            use std::os::unix::fs::PermissionsExt as _;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let (read, mut write) = tokio::io::split(stream);
+        write.write_all(b"save\n\n  \nstat 1\n").await.unwrap();
+        let mut lines = BufReader::new(read).lines();
+        let mut replies = Vec::new();
+        for _ in 0..4 {
+            replies.push(lines.next_line().await.unwrap().unwrap());
+        }
+        // blank lines are skipped, and each reply ends with an empty line
+        assert_eq!(replies, ["ran `save`", "", "ran `stat 1`", ""]);
+
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn addresses_are_tcp_or_unix() {
+        assert!(matches!(
+            ControlAddr::parse("127.0.0.1:9000"),
+            ControlAddr::Tcp(addr) if addr.port() == 9000
+        ));
+        assert!(matches!(
+            ControlAddr::parse("/run/boredphoton.sock"),
+            ControlAddr::Unix(path) if path == std::path::Path::new("/run/boredphoton.sock")
+        ));
+    }
+}
@@ -0,0 +1,113 @@
+use std::fmt;
+use std::io;
+
+use serenity::http::HttpError;
+
+/// Errors from handling an event or command.
+#[derive(Debug)]
+pub enum BotError {
+    Io(io::Error),
+    /// Boxed since serenity errors are much larger than the other variants.
+    Serenity(Box<serenity::Error>),
+    Serde(serde_json::Error),
+    Config(config::ConfigError),
+    /// A blocking task panicked or was cancelled.
+    Join(tokio::task::JoinError),
+}
+
+impl BotError {
+    /// Whether the error is likely to go away by itself,
+    /// such as a rate limit or an unavailable Discord server.
+    pub fn is_transient(&self) -> bool {
+        let err = match self {
+            BotError::Serenity(err) => err,
+            _ => return false,
+        };
+        match &**err {
+            serenity::Error::Http(err) => match &**err {
+                HttpError::Request(_) => true,
+                err => err
+                    .status_code()
+                    .is_some_and(|status| status.as_u16() == 429 || status.is_server_error()),
+            },
+            serenity::Error::Gateway(_)
+            | serenity::Error::Tungstenite(_)
+            | serenity::Error::Io(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Whether Discord reported that the channel does not exist,
+    /// e.g. because it was deleted.
+    pub fn is_unknown_channel(&self) -> bool {
+        match self {
+            BotError::Serenity(err) => match &**err {
+                serenity::Error::Http(err) => match &**err {
+                    HttpError::UnsuccessfulRequest(response) => {
+                        response.error.code == UNKNOWN_CHANNEL
+                    }
+                    _ => false,
+                },
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+}
+
+/// Discord JSON error code for an unknown channel.
+const UNKNOWN_CHANNEL: isize = 10003;
+
+impl fmt::Display for BotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BotError::Io(err) => write!(f, "IO error: {}", err),
+            BotError::Serenity(err) => write!(f, "Discord error: {}", err),
+            BotError::Serde(err) => write!(f, "serialization error: {}", err),
+            BotError::Config(err) => err.fmt(f),
+            BotError::Join(err) => write!(f, "task failed: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for BotError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BotError::Io(err) => Some(err),
+            BotError::Serenity(err) => Some(err),
+            BotError::Serde(err) => Some(err),
+            BotError::Config(err) => Some(err),
+            BotError::Join(err) => Some(err),
+        }
+    }
+}
+
+impl From<io::Error> for BotError {
+    fn from(err: io::Error) -> Self {
+        BotError::Io(err)
+    }
+}
+
+impl From<serenity::Error> for BotError {
+    fn from(err: serenity::Error) -> Self {
+        BotError::Serenity(Box::new(err))
+    }
+}
+
+impl From<serde_json::Error> for BotError {
+    fn from(err: serde_json::Error) -> Self {
+        BotError::Serde(err)
+    }
+}
+
+impl From<config::ConfigError> for BotError {
+    fn from(err: config::ConfigError) -> Self {
+        BotError::Config(err)
+    }
+}
+
+impl From<tokio::task::JoinError> for BotError {
+    fn from(err: tokio::task::JoinError) -> Self {
+        BotError::Join(err)
+    }
+}
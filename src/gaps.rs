@@ -0,0 +1,61 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Remembers when each shard of each bot lost its gateway connection.
+#[derive(Default)]
+pub struct Disconnections {
+    /// Unix time of the disconnection, by bot index and shard ID.
+    since: Mutex<HashMap<(usize, u64), u64>>,
+}
+
+impl Disconnections {
+    /// Records that the shard disconnected at `now`,
+    /// keeping the earlier time if it was already disconnected.
+    pub fn disconnected(&self, bot: usize, shard: u64, now: u64) {
+        self.since
+            .lock()
+            .unwrap()
+            .entry((bot, shard))
+            .or_insert(now);
+    }
+
+    /// Records that the shard connected,
+    /// returning the unix time it disconnected at, if it was disconnected.
+    pub fn connected(&self, bot: usize, shard: u64) -> Option<u64> {
+        self.since.lock().unwrap().remove(&(bot, shard))
+    }
+}
+
+/// Describes a monitoring gap between two unix times.
+pub fn describe_gap(from: u64, to: u64) -> String {
+    format!(
+        "Join monitoring had a gap from <t:{}:f> to <t:{}:f>, raids during this time may have been missed.",
+        from, to
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gaps_span_from_the_first_disconnection() {
+        let disconnections = Disconnections::default();
+        disconnections.disconnected(0, 1, 1_000);
+        disconnections.disconnected(0, 1, 1_500);
+        disconnections.disconnected(1, 1, 1_200);
+        assert_eq!(disconnections.connected(0, 1), Some(1_000));
+        assert_eq!(disconnections.connected(0, 1), None);
+        assert_eq!(disconnections.connected(0, 2), None);
+        assert_eq!(disconnections.connected(1, 1), Some(1_200));
+    }
+
+    #[test]
+    fn gap_descriptions_give_the_time_range() {
+        assert_eq!(
+            describe_gap(360_000_000, 360_003_600),
+            "Join monitoring had a gap from <t:360000000:f> to <t:360003600:f>, \
+             raids during this time may have been missed."
+        );
+    }
+}
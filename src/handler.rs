@@ -0,0 +1,4100 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use serenity::builder::CreateEmbed;
+use serenity::client::bridge::gateway::event::ShardStageUpdateEvent;
+use serenity::client::Context;
+use serenity::gateway::ConnectionStage;
+use serenity::model::application::command::{Command, CommandOptionType};
+use serenity::model::application::interaction::{Interaction, InteractionResponseType};
+use serenity::model::gateway::Ready;
+use serenity::model::prelude::{
+    ChannelId, GuildId, PartialGuild, Permissions, ResumedEvent, RoleId, Timestamp, User, UserId,
+    UserPublicFlags, VerificationLevel,
+};
+use serenity::model::{channel, guild};
+use serenity::prelude::GatewayIntents;
+
+use crate::alert::{
+    AbnormalStreaks, AlertCooldowns, AlertDelivery, HeldAlerts, RaidStates, RaidTransition,
+};
+use crate::bots::BotPresence;
+use crate::clock::{self, Clock as _};
+use crate::cluster::{CreationClusterConfig, CreationTimes};
+use crate::confirm::{self, PendingConfirmations, CONFIRM_WINDOW};
+#[cfg(feature = "control")]
+use crate::control;
+use crate::error::BotError;
+use crate::gaps::{describe_gap, Disconnections};
+#[cfg(feature = "health")]
+use crate::health;
+use crate::invites::InviteUses;
+use crate::joiners::{Joiner, JoinerLog};
+use crate::joins::*;
+use crate::logging;
+#[cfg(feature = "metrics")]
+use crate::metrics;
+use crate::monitoring::IgnoredGuilds;
+use crate::rate::{
+    CommandCooldownConfig, CommandCooldowns, CooldownCheck, JoinRates, RateLimitConfig,
+};
+use crate::rejoins::{RecentJoiners, RejoinDedupeConfig};
+use crate::schedule::{self, QuietHours, TimeZone};
+use crate::signal;
+#[cfg(feature = "redis")]
+use crate::store::RedisStore;
+#[cfg(feature = "sqlite")]
+use crate::store::SqliteStore;
+use crate::store::{JsonStore, Store};
+use crate::template::Template;
+use std::future::Future;
+
+type Result<T, E = BotError> = std::result::Result<T, E>;
+
+/// Runs the bots with the config loaded by `load_config` until all of them stop.
+pub async fn run() -> Result<()> {
+    let config = load_config()?;
+    logging::init(config.log_format);
+    serve(config).await
+}
+
+/// Runs the bots of `config` until all of them stop.
+async fn serve(config: Config) -> Result<()> {
+    config.validate()?;
+
+    let discord: Vec<_> = config
+        .discord
+        .iter()
+        .map(|discord| (discord.client_id, discord.token.clone(), discord.shards))
+        .collect();
+    let autosave_minutes = config.autosave_minutes;
+    let intents = config.intents.to_intents().expect("intents are validated");
+    let backup_count = config.backup_count;
+    #[cfg(feature = "metrics")]
+    let metrics_port = config.metrics_port;
+    #[cfg(feature = "health")]
+    let health_port = config.health_port;
+    #[cfg(feature = "control")]
+    let control_addr = config.control_addr.clone();
+    let handler = Arc::new(Handler::try_from(config)?);
+
+    let shutdown = Arc::new(tokio::sync::Notify::new());
+
+    let stopping = Arc::clone(&handler);
+    let shutdown_notify = Arc::clone(&shutdown);
+    tokio::spawn(async move {
+        tokio::select! {
+            () = signal::terminated() => log::info!("Termination signal received, saving guild data"),
+            () = stopping.stop.notified() => log::info!("Stop requested by an admin, saving guild data"),
+        }
+        shutdown_notify.notify_waiters();
+        let guild_joins = Arc::clone(&stopping.guild_joins);
+        // saving blocks on file IO until the process exits
+        let _ = tokio::task::spawn_blocking(move || guild_joins.save_and_exit()).await;
+    });
+
+    #[cfg(feature = "metrics")]
+    if let Some(port) = metrics_port {
+        let metrics = Arc::clone(&handler.metrics);
+        let shutdown = Arc::clone(&shutdown);
+        tokio::spawn(async move {
+            let addr = std::net::SocketAddr::from(([0, 0, 0, 0], port));
+            if let Err(err) = metrics::serve(addr, metrics, shutdown.notified()).await {
+                log::error!("Metrics server failed: {}", err);
+            }
+        });
+    }
+
+    #[cfg(feature = "health")]
+    if let Some(port) = health_port {
+        let ready = Arc::clone(&handler.ready);
+        let shutdown = Arc::clone(&shutdown);
+        tokio::spawn(async move {
+            let addr = std::net::SocketAddr::from(([0, 0, 0, 0], port));
+            if let Err(err) = health::serve(addr, ready, shutdown.notified()).await {
+                log::error!("Health check server failed: {}", err);
+            }
+        });
+    }
+
+    #[cfg(feature = "control")]
+    if let Some(addr) = control_addr {
+        let controlled = Arc::clone(&handler);
+        let shutdown = Arc::clone(&shutdown);
+        tokio::spawn(async move {
+            let handle = move |line: String| {
+                let handler = Arc::clone(&controlled);
+                async move {
+                    handler.audit(None, None, &line, true);
+                    handler
+                        .handle_admin(&line, None, None)
+                        .await
+                        .unwrap_or_else(|err| format!("Error: {}", err))
+                }
+            };
+            let addr = control::ControlAddr::parse(&addr);
+            if let Err(err) = control::serve(addr, handle, shutdown.notified()).await {
+                log::error!("Control interface failed: {}", err);
+            }
+        });
+    }
+
+    let guild_joins = Arc::clone(&handler.guild_joins);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(autosave_minutes.max(1) * 60));
+        interval.tick().await; // the first tick completes immediately
+        loop {
+            interval.tick().await;
+            let autosaved = Arc::clone(&guild_joins);
+            match tokio::task::spawn_blocking(move || autosaved.autosave()).await {
+                Ok(saved) => log::info!("Autosaved {} guilds", saved),
+                Err(err) => log::error!("Autosave task failed: {}", err),
+            }
+            if backup_count > 0 {
+                let backed_up = Arc::clone(&guild_joins);
+                match tokio::task::spawn_blocking(move || backed_up.backup(backup_count)).await {
+                    Ok(Ok(name)) => log::info!("Created backup {}", name),
+                    Ok(Err(err)) => log::error!("Error creating backup: {}", err),
+                    Err(err) => log::error!("Backup task failed: {}", err),
+                }
+            }
+        }
+    });
+
+    if handler.retention.enabled {
+        let pruning = Arc::clone(&handler);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+            // the first tick completes immediately, before the bots know their guilds
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let handler = Arc::clone(&pruning);
+                let pruned = tokio::task::spawn_blocking(move || {
+                    handler
+                        .guild_joins
+                        .prune_idle(|guild| handler.presence.is_present(guild))
+                })
+                .await;
+                match pruned {
+                    Ok(Ok(guilds)) => {
+                        for guild in guilds {
+                            log::info!("Deleted the data of idle guild {}", guild);
+                        }
+                    }
+                    Ok(Err(err)) => log::error!("Error pruning idle guilds: {}", err),
+                    Err(err) => log::error!("Prune task failed: {}", err),
+                }
+            }
+        });
+    }
+
+    // All bots and shards run in this process and share the same handler,
+    // so guild data is still loaded and saved by a single `GuildJoinsMap`.
+    let (stopped_send, mut stopped) = tokio::sync::mpsc::unbounded_channel();
+    for (index, (client_id, token, shards)) in discord.into_iter().enumerate() {
+        let mut client = serenity::Client::builder(token, intents)
+            .event_handler(Bot::new(index, client_id, Arc::clone(&handler)))
+            .await
+            .expect("Error connecting to discord");
+        let stopped_send = stopped_send.clone();
+        tokio::spawn(async move {
+            let result = match shards {
+                Some(shards) => client.start_shards(shards).await,
+                None => client.start_autosharded().await,
+            };
+            let _ = stopped_send.send((index, result));
+        });
+    }
+    drop(stopped_send);
+    // keep running while any bot is alive, so that the others take over for a failed one
+    let mut error = None;
+    while let Some((index, result)) = stopped.recv().await {
+        match result {
+            Ok(()) => log::warn!("Bot {} stopped", index),
+            Err(err) => {
+                log::error!("Bot {} failed: {}", index, err);
+                error = Some(err);
+            }
+        }
+    }
+    let guild_joins = Arc::clone(&handler.guild_joins);
+    match tokio::task::spawn_blocking(move || guild_joins.autosave()).await {
+        Ok(saved) => log::info!("Saved {} guilds after all bots stopped", saved),
+        Err(err) => log::error!("Save task failed: {}", err),
+    }
+    error.map_or(Ok(()), |err| Err(err.into()))
+}
+
+/// Config keys without a default value.
+const REQUIRED_CONFIG_KEYS: &[&str] = &[
+    "admin_ids",
+    "discord.client_id",
+    "discord.token",
+    "channels",
+];
+
+/// Config files probed in order if `CONFIG_PATH` is not set.
+const CONFIG_CANDIDATES: &[&str] = &["config.toml", "config.yaml", "config.json"];
+
+/// Loads the config from the file at `CONFIG_PATH`,
+/// or else the first existing file in `CONFIG_CANDIDATES`.
+///
+/// Environment variables prefixed with `BOREDPHOTON_` override values in the file,
+/// with `__` separating nested keys, e.g. `BOREDPHOTON_DISCORD__TOKEN`.
+fn load_config() -> Result<Config, config::ConfigError> {
+    let path = match std::env::var_os("CONFIG_PATH") {
+        Some(path) => Some(PathBuf::from(path)),
+        None => CONFIG_CANDIDATES
+            .iter()
+            .map(PathBuf::from)
+            .find(|path| path.is_file()),
+    };
+
+    let mut builder = config::Config::builder();
+    if let Some(path) = path {
+        builder = builder.add_source(config::File::from(path));
+    }
+    let config = builder
+        .add_source(
+            config::Environment::with_prefix("BOREDPHOTON")
+                .prefix_separator("_")
+                .separator("__")
+                .try_parsing(true),
+        )
+        .build()?;
+
+    // serde only reports the name of a missing field without its parent keys.
+    let multiple_bots = config.get::<Vec<config::Value>>("discord").is_ok();
+    for &key in REQUIRED_CONFIG_KEYS {
+        if multiple_bots && key.starts_with("discord.") {
+            continue;
+        }
+        if let Err(config::ConfigError::NotFound(_)) = config.get::<config::Value>(key) {
+            return Err(config::ConfigError::Message(format!(
+                "config: required field `{}` is missing",
+                key
+            )));
+        }
+    }
+
+    config
+        .try_deserialize()
+        .map_err(|err| config::ConfigError::Message(format!("config: {}", err)))
+}
+
+/// The options of the bots, usually loaded from a config file by `run`.
+#[derive(Deserialize)]
+pub struct Config {
+    admin_ids: Box<[UserId]>,
+    /// The bot account, or a list of accounts where earlier ones take precedence
+    /// in the guilds they share.
+    #[serde(deserialize_with = "one_or_many")]
+    discord: Vec<DiscordConfig>,
+    /// Whether to find the invite used by new members.
+    ///
+    /// This fetches the invites of a guild on every join and requires the Manage Server permission.
+    #[serde(default)]
+    invite_tracking: bool,
+    #[serde(default)]
+    invite: InviteConfig,
+    /// Prefix accepted in place of mentioning the bot, e.g. `!`.
+    command_prefix: Option<String>,
+    channels: HashMap<GuildId, AlertTarget>,
+    #[serde(default)]
+    threshold: ThresholdConfig,
+    #[serde(default)]
+    thresholds: HashMap<GuildId, ThresholdConfig>,
+    /// Number of hours of join history kept per guild.
+    #[serde(default = "default_backlog_size")]
+    backlog_size: usize,
+    /// Number of hours after which the weight of an hour in the moving average halves.
+    #[serde(default = "default_ewma_half_life_hours")]
+    ewma_half_life_hours: f64,
+    /// How quartiles and medians are computed.
+    #[serde(default)]
+    percentile_method: PercentileMethod,
+    /// Interval between periodic saves of all guild data.
+    #[serde(default = "default_autosave_minutes")]
+    autosave_minutes: u64,
+    /// Number of backups kept, created after each autosave.
+    ///
+    /// Automatic backups are disabled if this is 0.
+    #[serde(default)]
+    backup_count: usize,
+    /// Maximum number of guilds kept in memory, unlimited if absent.
+    ///
+    /// The least recently active guild is saved and unloaded when exceeded.
+    max_loaded_guilds: Option<usize>,
+    /// Minimum interval between saves of a guild when hours complete.
+    #[serde(default = "default_min_save_interval_seconds")]
+    min_save_interval_seconds: u64,
+    #[serde(default)]
+    auto_lockdown: LockdownConfig,
+    #[serde(default)]
+    auto_kick: AutoKickConfig,
+    #[serde(default)]
+    creation_cluster: CreationClusterConfig,
+    #[serde(default)]
+    rate_limit: RateLimitConfig,
+    #[serde(default)]
+    rejoin_dedupe: RejoinDedupeConfig,
+    #[serde(default)]
+    command_cooldown: CommandCooldownConfig,
+    #[serde(default)]
+    intents: IntentsConfig,
+    #[serde(default)]
+    retention: RetentionConfig,
+    /// Which bot accounts are not counted as joins.
+    #[serde(default)]
+    exclude_bot_joins: BotExclusion,
+    #[serde(default)]
+    log_format: logging::LogFormat,
+    /// Directory to store guild data in.
+    #[serde(default = "default_data_dir")]
+    data_dir: PathBuf,
+    /// Message of raid alerts, with the placeholders in `template::PLACEHOLDERS`.
+    #[serde(default = "default_alert_template")]
+    alert_template: String,
+    /// Guilds whose alert channels get a summary of the past day at local midnight,
+    /// with the time zones of their local time.
+    #[serde(default)]
+    daily_reports: HashMap<GuildId, TimeZone>,
+    /// Whether to notify alert channels of periods when joins were not observed,
+    /// because the bot was offline or disconnected from Discord.
+    #[serde(default)]
+    gap_alerts: bool,
+    /// Guilds whose joins are not monitored, unless enabled with `adm enable`.
+    #[serde(default)]
+    ignored_guilds: Vec<GuildId>,
+    /// Number of consecutive abnormal joins required before a guild is considered raided,
+    /// so that a single marginal join does not alert.
+    #[serde(default = "default_min_consecutive_abnormal")]
+    min_consecutive_abnormal: u32,
+    /// Minimum interval between raid alerts of a guild.
+    #[serde(default = "default_alert_cooldown_seconds")]
+    alert_cooldown_seconds: u64,
+    /// Number of times an alert is resent after a transient failure.
+    #[serde(default = "default_send_retries")]
+    send_retries: u32,
+    /// Channel to notify when saving guild data stops or resumes working.
+    ops_channel: Option<ChannelId>,
+    /// File to append a JSON line to for every admin command, in addition to the log.
+    audit_log: Option<PathBuf>,
+    /// Whether to DM the first admin the alerts that cannot be sent to the alert channels.
+    #[serde(default = "default_alert_fallback_dm")]
+    alert_fallback_dm: bool,
+    /// SQLite database to store guild data in instead of JSON files in `data_dir`, if any.
+    #[cfg(feature = "sqlite")]
+    sqlite_path: Option<PathBuf>,
+    /// Redis server to store guild data in instead of `data_dir`, if any,
+    /// which may be shared with other instances.
+    #[cfg(feature = "redis")]
+    redis_url: Option<String>,
+    /// Port to serve Prometheus metrics on, if any.
+    #[cfg(feature = "metrics")]
+    metrics_port: Option<u16>,
+    /// Port to serve `/healthz` and `/readyz` on, if any.
+    #[cfg(feature = "health")]
+    health_port: Option<u16>,
+    /// TCP address or UNIX socket path to accept admin commands on, if any.
+    #[cfg(feature = "control")]
+    control_addr: Option<String>,
+}
+
+/// The values of optional keys when absent, with no bots, admins or alert channels.
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            admin_ids: Box::new([]),
+            discord: Vec::new(),
+            invite_tracking: false,
+            invite: InviteConfig::default(),
+            command_prefix: None,
+            channels: HashMap::new(),
+            threshold: ThresholdConfig::default(),
+            thresholds: HashMap::new(),
+            backlog_size: default_backlog_size(),
+            ewma_half_life_hours: default_ewma_half_life_hours(),
+            percentile_method: PercentileMethod::default(),
+            autosave_minutes: default_autosave_minutes(),
+            backup_count: 0,
+            max_loaded_guilds: None,
+            min_save_interval_seconds: default_min_save_interval_seconds(),
+            auto_lockdown: LockdownConfig::default(),
+            auto_kick: AutoKickConfig::default(),
+            creation_cluster: CreationClusterConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            rejoin_dedupe: RejoinDedupeConfig::default(),
+            command_cooldown: CommandCooldownConfig::default(),
+            exclude_bot_joins: BotExclusion::default(),
+            intents: IntentsConfig::default(),
+            retention: RetentionConfig::default(),
+            log_format: logging::LogFormat::default(),
+            data_dir: default_data_dir(),
+            alert_template: default_alert_template(),
+            daily_reports: HashMap::new(),
+            gap_alerts: false,
+            ignored_guilds: Vec::new(),
+            min_consecutive_abnormal: default_min_consecutive_abnormal(),
+            alert_cooldown_seconds: default_alert_cooldown_seconds(),
+            send_retries: default_send_retries(),
+            ops_channel: None,
+            audit_log: None,
+            alert_fallback_dm: default_alert_fallback_dm(),
+            #[cfg(feature = "sqlite")]
+            sqlite_path: None,
+            #[cfg(feature = "redis")]
+            redis_url: None,
+            #[cfg(feature = "metrics")]
+            metrics_port: None,
+            #[cfg(feature = "health")]
+            health_port: None,
+            #[cfg(feature = "control")]
+            control_addr: None,
+        }
+    }
+}
+
+impl Config {
+    /// Rejects invalid values and warns about suspicious ones.
+    fn validate(&self) -> Result<(), config::ConfigError> {
+        #[cfg(all(feature = "sqlite", feature = "redis"))]
+        if self.sqlite_path.is_some() && self.redis_url.is_some() {
+            return Err(config::ConfigError::Message(
+                "config: only one of sqlite_path and redis_url may be set".into(),
+            ));
+        }
+        for (guild, target) in &self.channels {
+            if target.channels.iter().any(|channel| channel.0 == 0) {
+                return Err(config::ConfigError::Message(format!(
+                    "config: channels of guild {} contain channel ID 0",
+                    guild
+                )));
+            }
+            if target.quiet_hours.is_some_and(|quiet| !quiet.is_valid()) {
+                return Err(config::ConfigError::Message(format!(
+                    "config: quiet_hours of guild {} must be between 0 and 23 o'clock",
+                    guild
+                )));
+            }
+        }
+        if !(1..=MAX_TIMEOUT_MINUTES).contains(&self.auto_kick.timeout_minutes) {
+            return Err(config::ConfigError::Message(format!(
+                "config: auto_kick.timeout_minutes must be between 1 and {}",
+                MAX_TIMEOUT_MINUTES
+            )));
+        }
+        if self.auto_kick.soft_ban_delete_days > 7 {
+            return Err(config::ConfigError::Message(
+                "config: auto_kick.soft_ban_delete_days must be at most 7".into(),
+            ));
+        }
+        if self.backlog_size > MAX_BACKLOG_SIZE {
+            return Err(config::ConfigError::Message(format!(
+                "config: backlog_size must be at most {}",
+                MAX_BACKLOG_SIZE
+            )));
+        }
+        if let Err(err) = Template::parse(self.alert_template.clone()) {
+            return Err(config::ConfigError::Message(format!(
+                "config: alert_template: {}",
+                err
+            )));
+        }
+        match self.intents.to_intents() {
+            Err(err) => {
+                return Err(config::ConfigError::Message(format!(
+                    "config: intents: {}",
+                    err
+                )))
+            }
+            Ok(intents) if !intents.guild_members() => {
+                return Err(config::ConfigError::Message(
+                    "config: intents must include GUILD_MEMBERS, which joins are counted from"
+                        .into(),
+                ))
+            }
+            Ok(_) => (),
+        }
+        if self.discord.is_empty() {
+            return Err(config::ConfigError::Message(
+                "config: discord must contain at least one bot".into(),
+            ));
+        }
+        if self.ewma_half_life_hours.is_nan() || self.ewma_half_life_hours <= 0. {
+            return Err(config::ConfigError::Message(
+                "config: ewma_half_life_hours must be positive".into(),
+            ));
+        }
+        if self.admin_ids.is_empty() {
+            log::warn!("config: admin_ids is empty, nobody can run admin commands");
+        }
+        Ok(())
+    }
+}
+
+/// Deserializes either a single value or a list of values.
+fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+fn default_alert_template() -> String {
+    "{mention}{level}: abnormal server joins detected, stats = {stats}".into()
+}
+
+fn default_data_dir() -> PathBuf {
+    "data".into()
+}
+
+fn default_backlog_size() -> usize {
+    DEFAULT_BACKLOG_SIZE
+}
+
+fn default_ewma_half_life_hours() -> f64 {
+    168.
+}
+
+fn default_autosave_minutes() -> u64 {
+    5
+}
+
+fn default_min_save_interval_seconds() -> u64 {
+    60
+}
+
+fn default_min_consecutive_abnormal() -> u32 {
+    1
+}
+
+fn default_alert_cooldown_seconds() -> u64 {
+    600
+}
+
+fn default_send_retries() -> u32 {
+    3
+}
+
+fn default_alert_fallback_dm() -> bool {
+    true
+}
+
+/// Where to send the alerts of a guild.
+///
+/// Can also be configured as a single channel ID.
+#[derive(Deserialize)]
+#[serde(from = "AlertTargetConfig")]
+struct AlertTarget {
+    channels: Vec<ChannelId>,
+    /// The role to mention in alerts instead of `@here`.
+    role: Option<RoleId>,
+    /// Whether alerts mention anyone at all.
+    ping_on_alert: bool,
+    /// Alerts less severe than this do not mention anyone.
+    ping_severity: Severity,
+    /// The daily period during which only critical alerts are sent,
+    /// while the others are summarized when it ends.
+    quiet_hours: Option<QuietHours>,
+}
+
+impl AlertTarget {
+    /// Returns the mention to prefix alerts with, followed by a space if not empty.
+    fn mention(&self, severity: Severity) -> String {
+        if !self.ping_on_alert || severity < self.ping_severity {
+            return String::new();
+        }
+        match self.role {
+            Some(role) => format!("<@&{}> ", role),
+            None => "@here ".into(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AlertTargetConfig {
+    Channel(ChannelId),
+    Target {
+        channels: Vec<ChannelId>,
+        #[serde(default)]
+        role: Option<RoleId>,
+        #[serde(default = "default_ping_on_alert")]
+        ping_on_alert: bool,
+        #[serde(default = "default_min_severity")]
+        ping_severity: Severity,
+        #[serde(default)]
+        quiet_hours: Option<QuietHours>,
+    },
+}
+
+fn default_min_severity() -> Severity {
+    Severity::Elevated
+}
+
+fn default_ping_on_alert() -> bool {
+    true
+}
+
+impl From<AlertTargetConfig> for AlertTarget {
+    fn from(config: AlertTargetConfig) -> Self {
+        match config {
+            AlertTargetConfig::Channel(channel) => Self {
+                channels: vec![channel],
+                role: None,
+                ping_on_alert: true,
+                ping_severity: Severity::Elevated,
+                quiet_hours: None,
+            },
+            AlertTargetConfig::Target {
+                channels,
+                role,
+                ping_on_alert,
+                ping_severity,
+                quiet_hours,
+            } => Self {
+                channels,
+                role,
+                ping_on_alert,
+                ping_severity,
+                quiet_hours,
+            },
+        }
+    }
+}
+
+/// Raises the verification level of a guild when abnormal joins are detected.
+#[derive(Deserialize)]
+#[serde(default)]
+struct LockdownConfig {
+    enabled: bool,
+    /// The verification level to raise to, as the numeric value used by Discord.
+    level: VerificationLevel,
+    /// Less severe raids do not trigger a lockdown.
+    min_severity: Severity,
+}
+
+impl Default for LockdownConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            level: VerificationLevel::High,
+            min_severity: Severity::Elevated,
+        }
+    }
+}
+
+/// The gateway intents requested from Discord,
+/// in addition to the non-privileged ones.
+#[derive(Deserialize)]
+#[serde(default)]
+struct IntentsConfig {
+    /// Whether to read the content of messages for non-slash commands,
+    /// which requires the privileged Message Content intent.
+    commands_via_messages: bool,
+    /// Whether to receive member joins and leaves,
+    /// which requires the privileged Server Members intent.
+    track_members: bool,
+    /// Names of further intents, e.g. `GUILD_PRESENCES`.
+    extra: Vec<String>,
+}
+
+impl Default for IntentsConfig {
+    fn default() -> Self {
+        Self {
+            commands_via_messages: true,
+            track_members: true,
+            extra: Vec::new(),
+        }
+    }
+}
+
+impl IntentsConfig {
+    fn to_intents(&self) -> Result<GatewayIntents, String> {
+        let mut intents = GatewayIntents::non_privileged();
+        if self.commands_via_messages {
+            intents |= GatewayIntents::MESSAGE_CONTENT;
+        }
+        if self.track_members {
+            intents |= GatewayIntents::GUILD_MEMBERS;
+        }
+        for name in &self.extra {
+            intents |= intent_by_name(name).ok_or_else(|| format!("unknown intent {}", name))?;
+        }
+        Ok(intents)
+    }
+}
+
+fn intent_by_name(name: &str) -> Option<GatewayIntents> {
+    Some(match name.to_uppercase().as_str() {
+        "GUILDS" => GatewayIntents::GUILDS,
+        "GUILD_MEMBERS" => GatewayIntents::GUILD_MEMBERS,
+        "GUILD_BANS" => GatewayIntents::GUILD_BANS,
+        "GUILD_EMOJIS_AND_STICKERS" => GatewayIntents::GUILD_EMOJIS_AND_STICKERS,
+        "GUILD_INTEGRATIONS" => GatewayIntents::GUILD_INTEGRATIONS,
+        "GUILD_WEBHOOKS" => GatewayIntents::GUILD_WEBHOOKS,
+        "GUILD_INVITES" => GatewayIntents::GUILD_INVITES,
+        "GUILD_VOICE_STATES" => GatewayIntents::GUILD_VOICE_STATES,
+        "GUILD_PRESENCES" => GatewayIntents::GUILD_PRESENCES,
+        "GUILD_MESSAGES" => GatewayIntents::GUILD_MESSAGES,
+        "GUILD_MESSAGE_REACTIONS" => GatewayIntents::GUILD_MESSAGE_REACTIONS,
+        "GUILD_MESSAGE_TYPING" => GatewayIntents::GUILD_MESSAGE_TYPING,
+        "DIRECT_MESSAGES" => GatewayIntents::DIRECT_MESSAGES,
+        "DIRECT_MESSAGE_REACTIONS" => GatewayIntents::DIRECT_MESSAGE_REACTIONS,
+        "DIRECT_MESSAGE_TYPING" => GatewayIntents::DIRECT_MESSAGE_TYPING,
+        "MESSAGE_CONTENT" => GatewayIntents::MESSAGE_CONTENT,
+        "GUILD_SCHEDULED_EVENTS" => GatewayIntents::GUILD_SCHEDULED_EVENTS,
+        "AUTO_MODERATION_CONFIGURATION" => GatewayIntents::AUTO_MODERATION_CONFIGURATION,
+        "AUTO_MODERATION_EXECUTION" => GatewayIntents::AUTO_MODERATION_EXECUTION,
+        _ => return None,
+    })
+}
+
+/// Bot accounts whose joins are left out of the statistics.
+///
+/// Bot joins are counted separately in `Stat::bots` regardless.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum BotExclusion {
+    /// Bots are counted like everyone else.
+    None,
+    /// Bots verified by Discord, which are normally added by admins.
+    #[default]
+    Verified,
+    /// All bot accounts.
+    All,
+}
+
+impl BotExclusion {
+    fn excludes(self, user: &User) -> bool {
+        let verified = user
+            .public_flags
+            .is_some_and(|flags| flags.contains(UserPublicFlags::VERIFIED_BOT));
+        match self {
+            Self::None => false,
+            Self::Verified => user.bot && verified,
+            Self::All => user.bot,
+        }
+    }
+}
+
+/// The query of the link that `invite` replies with.
+#[derive(Deserialize)]
+#[serde(default)]
+struct InviteConfig {
+    /// OAuth2 scopes requested when adding the bot.
+    scopes: Vec<String>,
+    /// The permissions integer requested when adding the bot,
+    /// or the permissions needed by the enabled features if absent.
+    permissions: Option<u64>,
+}
+
+impl Default for InviteConfig {
+    fn default() -> Self {
+        Self {
+            scopes: vec!["bot".into(), "applications.commands".into()],
+            permissions: None,
+        }
+    }
+}
+
+/// Deletes the stored data of guilds that the bots are no longer in.
+#[derive(Deserialize)]
+#[serde(default)]
+struct RetentionConfig {
+    enabled: bool,
+    /// Data of a guild is kept this long after the last bot leaves it,
+    /// in case the bot is added back.
+    grace_hours: u64,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            grace_hours: 24 * 7,
+        }
+    }
+}
+
+/// Acts on newly created accounts that join while abnormal joins are detected,
+/// kicking them by default.
+#[derive(Deserialize)]
+#[serde(default)]
+struct AutoKickConfig {
+    enabled: bool,
+    action: MitigationAction,
+    /// Accounts younger than this are acted on.
+    max_account_age_hours: u64,
+    /// Less severe raids do not trigger the action.
+    min_severity: Severity,
+    /// Duration of `MitigationAction::Timeout`, at most 28 days.
+    timeout_minutes: u64,
+    /// Days of messages deleted by `MitigationAction::SoftBan`, at most 7.
+    soft_ban_delete_days: u8,
+}
+
+impl Default for AutoKickConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            action: MitigationAction::Kick,
+            max_account_age_hours: 24,
+            min_severity: Severity::Elevated,
+            timeout_minutes: 60,
+            soft_ban_delete_days: 1,
+        }
+    }
+}
+
+/// Longest timeout allowed by Discord.
+const MAX_TIMEOUT_MINUTES: u64 = 28 * 24 * 60;
+
+/// What is done to new accounts that join during abnormal joins.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum MitigationAction {
+    None,
+    /// Removes the member, who can rejoin immediately.
+    Kick,
+    /// Stops the member from sending messages or reacting for `timeout_minutes`.
+    Timeout,
+    /// Bans and immediately unbans the member to delete their recent messages.
+    SoftBan,
+}
+
+impl MitigationAction {
+    fn past_tense(self) -> &'static str {
+        match self {
+            Self::None => "Ignored",
+            Self::Kick => "Kicked",
+            Self::Timeout => "Timed out",
+            Self::SoftBan => "Soft-banned",
+        }
+    }
+
+    fn infinitive(self) -> &'static str {
+        match self {
+            Self::None => "ignore",
+            Self::Kick => "kick",
+            Self::Timeout => "time out",
+            Self::SoftBan => "soft-ban",
+        }
+    }
+
+    /// The permission the bot needs to take the action.
+    fn permission(self) -> Permissions {
+        match self {
+            Self::None => Permissions::empty(),
+            Self::Kick => Permissions::KICK_MEMBERS,
+            Self::Timeout => Permissions::MODERATE_MEMBERS,
+            Self::SoftBan => Permissions::BAN_MEMBERS,
+        }
+    }
+}
+
+/// A `MitigationAction` with the parameters it is taken with.
+#[derive(Debug, PartialEq, Eq)]
+enum Mitigation {
+    Kick,
+    Timeout { until: Timestamp },
+    SoftBan { delete_days: u8 },
+}
+
+#[derive(Deserialize)]
+struct DiscordConfig {
+    client_id: u64,
+    token: String,
+    /// Number of shards to connect with, or as many as Discord recommends if unset.
+    shards: Option<u64>,
+}
+
+/// Config values that `adm reload` can change without restarting.
+struct Reloadable {
+    admin_ids: Box<[UserId]>,
+    channels: HashMap<GuildId, AlertTarget>,
+    threshold: ThresholdConfig,
+    thresholds: HashMap<GuildId, ThresholdConfig>,
+}
+
+pub struct Handler {
+    reloadable: RwLock<Arc<Reloadable>>,
+    /// The client ID and token of each bot, which cannot be reloaded.
+    discord: Vec<(u64, String)>,
+    presence: BotPresence,
+    /// Notified by `adm stop` to shut down like on a termination signal.
+    stop: tokio::sync::Notify,
+    command_prefix: Option<String>,
+    guild_joins: Arc<GuildJoinsMap>,
+    backlog_size: usize,
+    ewma_alpha: f64,
+    percentile_method: PercentileMethod,
+    backup_count: usize,
+    lockdown: LockdownConfig,
+    auto_kick: AutoKickConfig,
+    creation_cluster: CreationClusterConfig,
+    creation_times: CreationTimes,
+    rate_limit: RateLimitConfig,
+    join_rates: JoinRates,
+    rejoin_dedupe: RejoinDedupeConfig,
+    recent_joiners: RecentJoiners,
+    command_cooldown: CommandCooldownConfig,
+    command_cooldowns: CommandCooldowns,
+    exclude_bot_joins: BotExclusion,
+    retention: RetentionConfig,
+    joiner_log: JoinerLog,
+    ignored: IgnoredGuilds,
+    alert_template: Template,
+    gap_alerts: bool,
+    disconnections: Disconnections,
+    daily_reports: HashMap<GuildId, TimeZone>,
+    audit_log: Option<PathBuf>,
+    ops_channel: Option<ChannelId>,
+    /// When the handler, and thus the process, started.
+    started: Instant,
+    /// Verification levels of guilds before they were locked down.
+    locked_down: Mutex<HashMap<GuildId, VerificationLevel>>,
+    alert_cooldowns: Arc<AlertCooldowns>,
+    delivery: Arc<AlertDelivery>,
+    /// Whether alerts for missing channels are DMed to the first admin.
+    alert_fallback_dm: bool,
+    held_alerts: Arc<HeldAlerts>,
+    confirmations: PendingConfirmations,
+    invite_tracking: bool,
+    /// The query of invite links, without the client ID.
+    invite_query: String,
+    invite_uses: InviteUses,
+    raid_states: RaidStates,
+    min_consecutive_abnormal: u32,
+    abnormal_streaks: AbnormalStreaks,
+    /// Guilds whose latest leave completed a mass join and leave.
+    nuke_states: RaidStates,
+    #[cfg(feature = "metrics")]
+    metrics: Arc<metrics::Metrics>,
+    /// Whether the gateway is ready and the bot is in any guild.
+    #[cfg(feature = "health")]
+    ready: Arc<std::sync::atomic::AtomicBool>,
+}
+
+impl Handler {
+    /// Returns the thresholds set at runtime for the guild,
+    /// or else the configured thresholds.
+    async fn threshold(&self, guild: GuildId) -> Result<ThresholdConfig> {
+        let threshold = self.with_joins(move |gj| gj.threshold(guild)).await?;
+        Ok(threshold.unwrap_or_else(|| {
+            let settings = self.settings();
+            settings
+                .thresholds
+                .get(&guild)
+                .unwrap_or(&settings.threshold)
+                .clone()
+        }))
+    }
+
+    /// Records an admin command run by `user`, or through the control interface if `None`.
+    fn audit(&self, user: Option<UserId>, guild: Option<GuildId>, command: &str, allowed: bool) {
+        let source = match user {
+            Some(user) => user.to_string(),
+            None => "control interface".into(),
+        };
+        let level = match allowed {
+            true => log::Level::Info,
+            false => log::Level::Warn,
+        };
+        let fields = serde_json::json!({
+            "event": "admin_command",
+            "user_id": user.map(|user| user.to_string()),
+            "guild_id": guild.map(|guild| guild.to_string()),
+            "command": command,
+            "allowed": allowed,
+        });
+        logging::event(
+            level,
+            format_args!(
+                "Admin command `{}` from {} {}",
+                command,
+                source,
+                if allowed { "run" } else { "denied" }
+            ),
+            fields.clone(),
+        );
+        if let Some(path) = &self.audit_log {
+            let mut entry = fields;
+            entry["timestamp"] = clock::SystemClock.now_secs().into();
+            if let Err(err) = logging::append_audit(path, &entry) {
+                log::error!("Cannot write to audit log {}: {}", path.display(), err);
+            }
+        }
+    }
+
+    fn settings(&self) -> Arc<Reloadable> {
+        Arc::clone(&self.reloadable.read().unwrap())
+    }
+
+    /// Returns the admin to DM alerts for missing channels to, if any.
+    fn fallback_admin(&self) -> Option<UserId> {
+        match self.alert_fallback_dm {
+            true => self.settings().admin_ids.first().copied(),
+            false => None,
+        }
+    }
+
+    /// Returns whether `cmd` is about the server it is run in, `guild`, and monitoring is disabled there.
+    fn is_disabled_for(&self, cmd: &str, guild: Option<GuildId>) -> bool {
+        // `stat` checks the server it is about, which may be another one
+        let guild_command = matches!(cmd, "raids" | "serverinfo" | "rank" | "whois-recent");
+        guild_command && guild.is_some_and(|guild| self.ignored.is_ignored(guild))
+    }
+
+    fn is_admin(&self, user: UserId) -> bool {
+        self.settings().admin_ids.contains(&user)
+    }
+
+    /// Reloads the config values in `Reloadable`.
+    fn reload(&self) -> Result<String> {
+        let config = load_config()?;
+        config.validate()?;
+        let Config {
+            admin_ids,
+            discord,
+            channels,
+            threshold,
+            thresholds,
+            ..
+        } = config;
+        let credentials: Vec<_> = discord
+            .into_iter()
+            .map(|discord| (discord.client_id, discord.token))
+            .collect();
+        if credentials != self.discord {
+            log::warn!("The Discord credentials in the config changed, restart to use them");
+        }
+        *self.reloadable.write().unwrap() = Arc::new(Reloadable {
+            admin_ids,
+            channels,
+            threshold,
+            thresholds,
+        });
+        log::info!("Reloaded the config");
+        Ok("Reloaded admins, alert channels and thresholds from the config".to_owned())
+    }
+
+    /// Runs an operation on `guild_joins` on the blocking thread pool.
+    ///
+    /// `GuildJoinsMap` may perform file IO while holding its locks,
+    /// which must not block the async executor.
+    async fn with_joins<F, R>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(&GuildJoinsMap) -> io::Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let guild_joins = Arc::clone(&self.guild_joins);
+        Ok(tokio::task::spawn_blocking(move || f(&guild_joins)).await??)
+    }
+
+    async fn add_joins(&self, guild: GuildId, delta: u32) -> Result<Stat> {
+        let stat = self.with_joins(move |gj| gj.add(guild, delta)).await?;
+        #[cfg(feature = "metrics")]
+        self.metrics.observe(guild, &stat);
+        Ok(stat)
+    }
+
+    /// Returns the guild queried by `stat`, which admins may name before the subcommand,
+    /// or the reply refusing it to other users.
+    fn stat_target<'a>(
+        &self,
+        args: &mut Peekable<impl Iterator<Item = &'a str>>,
+        author: UserId,
+        current: Option<GuildId>,
+    ) -> Result<Option<GuildId>, &'static str> {
+        let target = match args.peek().and_then(|arg| arg.parse().ok()) {
+            Some(guild) => GuildId(guild),
+            None => return Ok(current),
+        };
+        args.next();
+        match self.is_admin(author) {
+            true => Ok(Some(target)),
+            false => Err("Only admins can query the stats of other servers."),
+        }
+    }
+
+    async fn stat_reply(&self, guild: GuildId) -> Result<String> {
+        let stat = self.add_joins(guild, 0).await?;
+        Ok(format!("Stats:\n{}", stat))
+    }
+
+    /// Runs an admin command of `user` that does not need a Discord context.
+    ///
+    /// Destructive commands only run when sent again with a trailing `confirm`.
+    /// `user` is `None` for commands from the control interface.
+    async fn handle_admin(
+        &self,
+        cmd: &str,
+        guild: Option<GuildId>,
+        user: Option<UserId>,
+    ) -> Result<String> {
+        let mut args: Vec<_> = cmd.split_whitespace().collect();
+        let sub = match args.is_empty() {
+            true => return Ok(ADMIN_HELP.into()),
+            false => args.remove(0).to_lowercase(),
+        };
+
+        if confirm::is_destructive(&sub) {
+            let confirmed = args
+                .last()
+                .is_some_and(|arg| arg.eq_ignore_ascii_case("confirm"));
+            if confirmed {
+                args.pop();
+            }
+            let command = std::iter::once(sub.as_str())
+                .chain(args.iter().copied())
+                .collect::<Vec<_>>()
+                .join(" ");
+            // the control interface takes commands without the prefix and cannot react
+            let (prefix, react) = match user {
+                Some(_) => ("adm ", format!("React {} or send", CONFIRM_EMOJI)),
+                None => ("", "Send".to_owned()),
+            };
+            let now = Instant::now();
+            if !confirmed {
+                self.confirmations.request(user, command.clone(), now);
+                return Ok(format!(
+                    "`{}{}` cannot be undone. {} `{}{} confirm` within {} seconds to confirm.",
+                    prefix,
+                    command,
+                    react,
+                    prefix,
+                    command,
+                    CONFIRM_WINDOW.as_secs()
+                ));
+            }
+            if !self.confirmations.confirm(user, &command, now) {
+                return Ok(format!(
+                    "Nothing to confirm. Send `{}{}` first, then confirm it within {} seconds.",
+                    prefix,
+                    command,
+                    CONFIRM_WINDOW.as_secs()
+                ));
+            }
+        }
+
+        self.run_admin(&sub, args, guild).await
+    }
+
+    /// Runs a parsed admin command without asking for confirmation.
+    ///
+    /// Server commands apply to the server whose id is given as their last argument,
+    /// or else to `guild`.
+    async fn run_admin(
+        &self,
+        sub: &str,
+        args: Vec<&str>,
+        guild: Option<GuildId>,
+    ) -> Result<String> {
+        let guild_arg = |index: usize| {
+            args.get(index)
+                .and_then(|arg| arg.parse().ok())
+                .map(GuildId)
+                .or(guild)
+        };
+        const NO_GUILD: &str = "Please specify the server id.";
+
+        let reply = match sub {
+            "reload" => self.reload()?,
+            "save" => {
+                self.with_joins(|gj| gj.save()).await?;
+                "Saved the join data of all servers".to_owned()
+            }
+            "stat" => match guild_arg(0) {
+                Some(guild) => self.stat_reply(guild).await?,
+                None => NO_GUILD.to_owned(),
+            },
+            "reset" => match guild_arg(0) {
+                Some(guild) => self.reset_reply(guild).await?,
+                None => NO_GUILD.to_owned(),
+            },
+            "backup" => {
+                // manual backups are never pruned if automatic backups are disabled
+                let keep = match self.backup_count {
+                    0 => usize::MAX,
+                    count => count,
+                };
+                let name = self.with_joins(move |gj| gj.backup(keep)).await?;
+                format!("Created backup `{}`", name)
+            }
+            "restore" => match args.first() {
+                Some(&name) => {
+                    let name = name.to_owned();
+                    let restored = name.clone();
+                    self.with_joins(move |gj| gj.restore(&name)).await?;
+                    format!("Restored backup `{}`", restored)
+                }
+                None => "Usage: `adm restore <backup>`".to_owned(),
+            },
+            "threshold" => {
+                let multiplier = args.first().and_then(|arg| arg.parse().ok());
+                let offset = args.get(1).and_then(|arg| arg.parse().ok());
+                match (multiplier, offset, guild_arg(2)) {
+                    (_, _, None) => NO_GUILD.to_owned(),
+                    (Some(multiplier), Some(offset), Some(guild))
+                        if f64::is_finite(multiplier)
+                            && f64::is_finite(offset)
+                            && multiplier > 0.
+                            && offset >= 0. =>
+                    {
+                        self.set_threshold_reply(guild, multiplier, offset).await?
+                    }
+                    _ => "Usage: `adm threshold <multiplier> <offset>`, where multiplier is positive and offset is not negative".to_owned(),
+                }
+            }
+            "expect" if args.first() == Some(&"clear") => match guild_arg(1) {
+                Some(guild) => {
+                    self.with_joins(move |gj| gj.set_expectation(guild, None))
+                        .await?;
+                    "The expected joins have been cleared.".into()
+                }
+                None => NO_GUILD.to_owned(),
+            },
+            "expect" => {
+                let joins = args.first().and_then(|arg| arg.parse::<u32>().ok());
+                let duration = args.get(1).and_then(|arg| parse_duration(arg));
+                match (joins, duration, guild_arg(2)) {
+                    (_, _, None) => NO_GUILD.to_owned(),
+                    (Some(joins), Some(duration), Some(guild)) => {
+                        let until = clock::SystemClock.now_secs() + duration.as_secs();
+                        let expectation = Expectation { joins, until };
+                        self.with_joins(move |gj| gj.set_expectation(guild, Some(expectation)))
+                            .await?;
+                        format!(
+                            "Up to {} more joins per hour are expected until <t:{}:f>.",
+                            joins, until
+                        )
+                    }
+                    _ => EXPECT_USAGE.to_owned(),
+                }
+            }
+            "seed" => match (args.first().and_then(|arg| arg.parse().ok()), guild_arg(1)) {
+                (_, None) => NO_GUILD.to_owned(),
+                (Some(joins), Some(guild)) => {
+                    let seeded = self.with_joins(move |gj| gj.seed(guild, joins)).await?;
+                    format!(
+                        "Seeded {} hours without data with {} joins per hour. They are replaced as real hours are recorded.",
+                        seeded, joins
+                    )
+                }
+                (None, _) => "Usage: `adm seed <joins per hour>`".to_owned(),
+            },
+            "simulate" => {
+                let multiplier = args.first().and_then(|arg| arg.parse().ok());
+                let offset = args.get(1).and_then(|arg| arg.parse().ok());
+                match (multiplier, offset, guild_arg(2)) {
+                    (_, _, None) => NO_GUILD.to_owned(),
+                    (Some(multiplier), Some(offset), Some(guild)) => {
+                        self.simulate_reply(guild, multiplier, offset).await?
+                    }
+                    _ => "Usage: `adm simulate <multiplier> <offset>`".to_owned(),
+                }
+            }
+            toggle @ ("enable" | "disable") => match guild_arg(0) {
+                Some(guild) => {
+                    let enabled = toggle == "enable";
+                    self.ignored.set_enabled(guild, enabled)?;
+                    format!(
+                        "Monitoring {} in server {}",
+                        if enabled { "enabled" } else { "disabled" },
+                        guild
+                    )
+                }
+                None => NO_GUILD.to_owned(),
+            },
+            "stop" => {
+                self.stop.notify_one();
+                "Saving all data and stopping".to_owned()
+            }
+            _ => "Unknown admin command, see `help`".to_owned(),
+        };
+        Ok(reply)
+    }
+
+    /// Summarizes the joins of the day up to now.
+    async fn daily_report(&self, guild: GuildId) -> Result<String> {
+        Ok(format!(
+            "Daily summary\n{}{}",
+            self.window_reply(guild, 24, 0).await?,
+            self.stat_reply(guild).await?
+        ))
+    }
+
+    async fn stat_embed(&self, guild: GuildId) -> Result<CreateEmbed> {
+        let stat = self.add_joins(guild, 0).await?;
+        let severity = stat.severity(&self.threshold(guild).await?);
+        Ok(stat.to_embed(severity))
+    }
+
+    async fn stat_json_reply(&self, guild: GuildId) -> Result<String> {
+        let stat = self.add_joins(guild, 0).await?;
+        Ok(format!(
+            "```json\n{}\n```",
+            serde_json::to_string_pretty(&stat)?
+        ))
+    }
+
+    async fn serverinfo_reply(&self, ctx: &Context, guild: GuildId) -> Result<String> {
+        let stat = self.add_joins(guild, 0).await?;
+        // the cached count is exact, the count from the API is approximate
+        let cached = ctx.cache.guild_field(guild, |guild| guild.member_count);
+        let members = match cached {
+            Some(count) => Some((count.to_string(), count)),
+            None => match ctx.http.get_guild_with_counts(guild.0).await {
+                Ok(partial) => partial
+                    .approximate_member_count
+                    .map(|count| (format!("about {}", count), count)),
+                Err(err) => {
+                    log::warn!("Cannot fetch member count of guild {}: {}", guild, err);
+                    None
+                }
+            },
+        };
+        Ok(match members {
+            Some((described, count)) if count > 0 => format!(
+                "This server has {} members.\nThere were {} joins in the past hour ({:.2}% of members).",
+                described,
+                stat.current,
+                f64::from(stat.current) / count as f64 * 100.
+            ),
+            _ => format!(
+                "The member count of this server is unavailable.\nThere were {} joins in the past hour.",
+                stat.current
+            ),
+        })
+    }
+
+    /// Summarizes the current hour across all loaded guilds,
+    /// naming guilds with `guild_name` if it knows them.
+    async fn global_reply(&self, guild_name: impl Fn(GuildId) -> Option<String>) -> Result<String> {
+        let joins = self.with_joins(|gj| Ok(gj.current_joins())).await?;
+        let total: u64 = joins.iter().map(|&(_, current)| u64::from(current)).sum();
+        let mut reply = format!(
+            "There were {} joins in the past hour across {} servers.\n{} servers are being raided.\n",
+            total,
+            joins.len(),
+            self.raid_states.count()
+        );
+        if let Some(&(guild, current)) = joins.iter().max_by_key(|&&(_, current)| current) {
+            let name = guild_name(guild).unwrap_or_else(|| guild.to_string());
+            reply += &format!("The busiest server is {} with {} joins.", name, current);
+        }
+        Ok(reply)
+    }
+
+    /// Lists each loaded guild with its joins in the current hour, busiest first,
+    /// naming guilds with `guild_name` if it knows them.
+    async fn guilds_reply(&self, guild_name: impl Fn(GuildId) -> Option<String>) -> Result<String> {
+        let mut joins = self.with_joins(|gj| Ok(gj.current_joins())).await?;
+        if joins.is_empty() {
+            return Ok("No servers are loaded.".into());
+        }
+        joins.sort_by_key(|&(guild, current)| (std::cmp::Reverse(current), guild));
+
+        let settings = self.settings();
+        let mut reply = format!("{} loaded servers:\n", joins.len());
+        for (guild, current) in joins {
+            let name = guild_name(guild).unwrap_or_else(|| "unknown".into());
+            reply += &format!("`{}` {}: {} joins this hour", guild, name, current);
+            if self.raid_states.is_raiding(guild) {
+                reply += ", abnormal";
+            }
+            if self.ignored.is_ignored(guild) {
+                reply += ", monitoring disabled";
+            }
+            if settings
+                .channels
+                .get(&guild)
+                .is_none_or(|target| target.channels.is_empty())
+            {
+                reply += ", no alert channel";
+            }
+            reply += "\n";
+        }
+        Ok(reply)
+    }
+
+    /// Compares the joins of each loaded guild to the other guilds.
+    async fn fleet_reply(&self, ctx: &Context) -> Result<String> {
+        let joins = self.with_joins(|gj| Ok(gj.current_joins())).await?;
+        if joins.is_empty() {
+            return Ok("No servers are loaded.".into());
+        }
+        let fleet = fleet_stat(&joins, self.percentile_method);
+        let mut reply = format!(
+            "Joins in the past hour across {} servers: lower quartile {:.1}, median {:.1}, upper quartile {:.1}, max {:.0}\n",
+            fleet.guilds, fleet.lq, fleet.median, fleet.uq, fleet.max
+        );
+        if fleet.outliers.is_empty() {
+            reply += &format!("No server has more than {:.1} joins.", fleet.fence);
+            return Ok(reply);
+        }
+        reply += &format!("Servers with more than {:.1} joins:\n", fleet.fence);
+        let more = fleet.outliers.len().saturating_sub(MAX_FLEET_OUTLIERS);
+        for (guild, current) in fleet.outliers.into_iter().take(MAX_FLEET_OUTLIERS) {
+            let name = ctx
+                .cache
+                .guild_field(guild, |guild| guild.name.clone())
+                .unwrap_or_else(|| "unknown".into());
+            reply += &format!("`{}` {}: {} joins\n", guild, name, current);
+        }
+        if more > 0 {
+            reply += &format!("and {} more", more);
+        }
+        Ok(reply)
+    }
+
+    async fn rank_reply(&self, guild: GuildId) -> Result<String> {
+        let (current, rank) = self.with_joins(move |gj| gj.rank(guild)).await?;
+        Ok(match rank {
+            Some(rank) => format!(
+                "This hour's {} joins is higher than {:.0}% of recorded hours.",
+                current,
+                rank * 100.
+            ),
+            None => format!(
+                "This hour has {} joins, but no previous hours have been recorded yet.",
+                current
+            ),
+        })
+    }
+
+    async fn raids_reply(&self, guild: GuildId, count: usize) -> Result<String> {
+        // keeps the reply within the message length limit
+        let count = count.clamp(1, 20);
+        let raids = self.with_joins(move |gj| gj.raids(guild, count)).await?;
+        if raids.is_empty() {
+            return Ok("No raids have been detected in this server.".into());
+        }
+        let mut reply = String::from("Recent raids:\n");
+        for raid in raids.iter().rev() {
+            reply += &format!(
+                "<t:{}:f>: {}, peak of {} joins/h (upper quartile {:.1})\n",
+                raid.timestamp, raid.kind, raid.peak, raid.stat.uq
+            );
+        }
+        Ok(reply)
+    }
+
+    async fn window_reply(&self, guild: GuildId, from: usize, to: usize) -> Result<String> {
+        let window = self
+            .with_joins(move |gj| gj.window(guild, from, to))
+            .await?;
+        let window = match window {
+            Some(window) => window,
+            None => {
+                return Ok(format!(
+                    "No data that far back, only the past {} hours are kept.",
+                    self.backlog_size
+                ))
+            }
+        };
+        let mut reply = format!(
+            "From <t:{}:f> to <t:{}:f>:\n{} joins over {} hours with data, {:.3} joins/h on average\n",
+            window.start_hour * 3600,
+            window.end_hour * 3600,
+            window.total,
+            window.n,
+            window.mean
+        );
+        if let Some((hour, joins)) = window.peak {
+            reply += &format!("Busiest hour: {} joins at <t:{}:f>\n", joins, hour * 3600);
+        }
+        Ok(reply)
+    }
+
+    async fn history_reply(&self, guild: GuildId, hours: usize) -> Result<String> {
+        let hours = hours.clamp(1, self.backlog_size);
+        let log = self.with_joins(move |gj| gj.history(guild, hours)).await?;
+        let (log, per_char) = downsample(&log, SPARKLINE_WIDTH);
+        let scale = match per_char {
+            1 => String::new(),
+            per_char => format!(", {} hours per character", per_char),
+        };
+        Ok(format!(
+            "Joins in the past {} hours (`?` = no data{}):\n```\n{}\n```",
+            hours,
+            scale,
+            sparkline(&log)
+        ))
+    }
+
+    async fn forecast_reply(&self, guild: GuildId) -> Result<String> {
+        let stat = self.add_joins(guild, 0).await?;
+        let threshold = self.threshold(guild).await?;
+        let projected = project_hour(stat.current, stat.elapsed_minutes);
+        let limit = stat.hourly_limit(&threshold);
+        let mut reply = format!(
+            "{} joins in the first {} minutes of this hour project to {:.1} joins by the end of the hour, against a limit of {:.1}.",
+            stat.current, stat.elapsed_minutes, projected, limit
+        );
+        if stat.elapsed_minutes < threshold.early_min_minutes {
+            reply += &format!(
+                "\nThe projection is not used for detection until {} minutes have elapsed.",
+                threshold.early_min_minutes
+            );
+        } else if projected > limit * threshold.early_factor {
+            reply += "\nThis hour is trending abnormal.";
+        }
+        Ok(reply)
+    }
+
+    fn whois_recent_reply(&self, guild: GuildId, count: usize) -> String {
+        let count = count.clamp(1, MAX_WHOIS_RECENT);
+        let (joiners, recorded) = self.joiner_log.latest(guild, count);
+        if joiners.is_empty() {
+            return "No joins have been recorded since the bot started.".into();
+        }
+        let mut reply = format!("The latest {} joins, newest first:\n", joiners.len());
+        for joiner in &joiners {
+            reply += &format!(
+                "<@{}> joined <t:{}:R>, account created <t:{}:R>\n",
+                joiner.user, joiner.joined_at, joiner.created_at
+            );
+        }
+        if joiners.len() < recorded {
+            reply += &format!(
+                "{} older joins are not shown, at most {} are listed.\n",
+                recorded - joiners.len(),
+                count
+            );
+        }
+        reply
+    }
+
+    async fn peak_reply(&self, guild: GuildId) -> Result<String> {
+        let peak = self.with_joins(move |gj| gj.peak(guild)).await?;
+        Ok(match peak {
+            Some(peak) => format!(
+                "The busiest hour ever had {} joins, starting <t:{}:f>.",
+                peak.joins,
+                peak.hour * 3600
+            ),
+            None => "No joins have been recorded in a completed hour yet.".into(),
+        })
+    }
+
+    async fn recent_reply(&self, guild: GuildId, hours: usize) -> Result<String> {
+        let hours = hours.clamp(1, self.backlog_size);
+        let (current_hour, log) = self.with_joins(move |gj| gj.recent(guild, hours)).await?;
+        Ok(format!(
+            "Joins in each of the past {} hours, newest first:\n{}",
+            hours,
+            timeline(current_hour, &log)
+        ))
+    }
+
+    async fn simulate_reply(&self, guild: GuildId, multiplier: f64, offset: f64) -> Result<String> {
+        let hours = self.backlog_size;
+        let log = self.with_joins(move |gj| gj.history(guild, hours)).await?;
+        let threshold = ThresholdConfig {
+            method: DetectionMethod::Quartile,
+            multiplier,
+            offset,
+            ..self.threshold(guild).await?
+        };
+        let (flagged, replayed) =
+            simulate(&log, &threshold, self.ewma_alpha, self.percentile_method);
+        Ok(format!(
+            "With multiplier {} and offset {}, {} of {} recorded hours would have been abnormal.",
+            multiplier, offset, flagged, replayed
+        ))
+    }
+
+    async fn set_threshold_reply(
+        &self,
+        guild: GuildId,
+        multiplier: f64,
+        offset: f64,
+    ) -> Result<String> {
+        let threshold = ThresholdConfig {
+            method: DetectionMethod::Quartile,
+            multiplier,
+            offset,
+            ..self.threshold(guild).await?
+        };
+        self.with_joins(move |gj| gj.set_threshold(guild, threshold))
+            .await?;
+        Ok(format!(
+            "Joins are now abnormal if `current > upper quartile * {} + {}`.",
+            multiplier, offset
+        ))
+    }
+
+    async fn reset_reply(&self, guild: GuildId) -> Result<String> {
+        self.with_joins(move |gj| gj.reset(guild)).await?;
+        Ok("Join history has been reset.".into())
+    }
+
+    /// Runs `/reset` for `user`, who may only reset the guild if `allowed`,
+    /// once they run it again with `confirm` set within `CONFIRM_WINDOW`.
+    async fn slash_reset(
+        &self,
+        guild: GuildId,
+        user: UserId,
+        allowed: bool,
+        confirmed: bool,
+    ) -> Result<String> {
+        if !allowed {
+            return Ok("You do not have permission to use this command.".into());
+        }
+        // the same command as `adm reset <guild>`
+        let command = format!("reset {}", guild);
+        let now = Instant::now();
+        if !confirmed {
+            self.confirmations.request(Some(user), command, now);
+            return Ok(format!(
+                "Resetting the join history cannot be undone. Run `/reset confirm: True` within {} seconds to confirm.",
+                CONFIRM_WINDOW.as_secs()
+            ));
+        }
+        if !self.confirmations.confirm(Some(user), &command, now) {
+            return Ok(format!(
+                "Nothing to confirm. Run `/reset` first, then confirm it within {} seconds.",
+                CONFIRM_WINDOW.as_secs()
+            ));
+        }
+        self.reset_reply(guild).await
+    }
+
+    /// Raises the verification level of the guild if auto lockdown is enabled.
+    ///
+    /// Returns a note to append to the alert message.
+    async fn lock_down(
+        &self,
+        ctx: &Context,
+        guild: &PartialGuild,
+        severity: Severity,
+    ) -> Option<String> {
+        let level = self.lockdown.level;
+        if !self.lockdown.enabled
+            || severity < self.lockdown.min_severity
+            || guild.verification_level >= level
+        {
+            return None;
+        }
+
+        {
+            let mut locked_down = self.locked_down.lock().unwrap();
+            if locked_down.contains_key(&guild.id) {
+                return None;
+            }
+            locked_down.insert(guild.id, guild.verification_level);
+        }
+
+        let mut guild_id = guild.id;
+        match guild_id
+            .edit(&ctx.http, |edit| edit.verification_level(level))
+            .await
+        {
+            Ok(_) => Some(format!(
+                "\nVerification level has been raised to {:?}. Use `adm unlock` to restore it.",
+                level
+            )),
+            Err(err) => {
+                log::warn!("Cannot lock down guild {}: {}", &guild.name, err);
+                self.locked_down.lock().unwrap().remove(&guild.id);
+                Some(format!("\nFailed to raise verification level: {}", err))
+            }
+        }
+    }
+
+    /// Returns the action to take on a member joining at unix time `now`
+    /// during joins of `severity`, whose account is `age_hours` old,
+    /// if auto kick is enabled and their account is too new.
+    fn mitigation(&self, severity: Severity, age_hours: u64, now: i64) -> Option<Mitigation> {
+        if !self.auto_kick.enabled
+            || severity < self.auto_kick.min_severity
+            || age_hours >= self.auto_kick.max_account_age_hours
+        {
+            return None;
+        }
+        Some(match self.auto_kick.action {
+            MitigationAction::None => return None,
+            MitigationAction::Kick => Mitigation::Kick,
+            MitigationAction::Timeout => Mitigation::Timeout {
+                until: Timestamp::from_unix_timestamp(
+                    now + self.auto_kick.timeout_minutes as i64 * 60,
+                )
+                .expect("timeout_minutes is validated to be at most 28 days"),
+            },
+            MitigationAction::SoftBan => Mitigation::SoftBan {
+                delete_days: self.auto_kick.soft_ban_delete_days,
+            },
+        })
+    }
+
+    /// Takes the configured action on the member if auto kick is enabled and their account is too new.
+    ///
+    /// Returns a note to post in the alert channel.
+    async fn mitigate(
+        &self,
+        ctx: &Context,
+        member: &guild::Member,
+        severity: Severity,
+    ) -> Option<String> {
+        let now = Timestamp::now().unix_timestamp();
+        let age_secs = now - member.user.created_at().unix_timestamp();
+        let age_hours = age_secs.max(0) as u64 / 3600;
+        let mitigation = self.mitigation(severity, age_hours, now)?;
+        let action = self.auto_kick.action;
+
+        let user = member.user.id;
+        let guild = member.guild_id;
+        let result: Result<()> = async {
+            match mitigation {
+                Mitigation::Kick => member.kick_with_reason(ctx, MITIGATION_REASON).await?,
+                Mitigation::Timeout { until } => {
+                    guild
+                        .edit_member(ctx, user, |edit| {
+                            edit.disable_communication_until_datetime(until)
+                        })
+                        .await?;
+                }
+                Mitigation::SoftBan { delete_days } => {
+                    member
+                        .ban_with_reason(ctx, delete_days, MITIGATION_REASON)
+                        .await?;
+                    if let Err(err) = guild.unban(ctx, user).await {
+                        log::error!(
+                            "Banned {} from guild {} but cannot unban them: {}",
+                            user,
+                            guild,
+                            err
+                        );
+                        return Err(err.into());
+                    }
+                }
+            }
+            Ok(())
+        }
+        .await;
+
+        match result {
+            Ok(()) => {
+                log::info!(
+                    "{} {} ({}) in guild {}: {}, account created {} hours ago",
+                    action.past_tense(),
+                    user,
+                    &member.user.tag(),
+                    guild,
+                    MITIGATION_REASON,
+                    age_hours
+                );
+                Some(format!(
+                    "{} new account {} (<@{}>), created {} hours ago.",
+                    action.past_tense(),
+                    user,
+                    user,
+                    age_hours
+                ))
+            }
+            Err(err) => {
+                log::warn!(
+                    "Cannot {} {} in guild {}: {}",
+                    action.infinitive(),
+                    user,
+                    guild,
+                    err
+                );
+                Some(format!(
+                    "Failed to {} new account {} (<@{}>): {}",
+                    action.infinitive(),
+                    user,
+                    user,
+                    err
+                ))
+            }
+        }
+    }
+
+    /// Fetches the invites of the guild,
+    /// returning the invite most used since the previous fetch.
+    ///
+    /// Returns `None` if invite tracking is disabled or the invites cannot be fetched.
+    async fn track_invites(&self, ctx: &Context, guild: GuildId) -> Option<String> {
+        if !self.invite_tracking {
+            return None;
+        }
+        match guild.invites(&ctx.http).await {
+            Ok(invites) => {
+                let uses = invites
+                    .into_iter()
+                    .map(|invite| (invite.code, invite.uses))
+                    .collect();
+                self.invite_uses.update(guild, uses)
+            }
+            Err(err) => {
+                log::debug!("Cannot fetch invites of guild {}: {}", guild, err);
+                None
+            }
+        }
+    }
+
+    /// Notifies the ops channel if saving guild data stopped or resumed working.
+    async fn report_degraded(&self, ctx: &Context) {
+        let (channel, degraded) = match (self.ops_channel, self.guild_joins.take_degraded_change())
+        {
+            (Some(channel), Some(degraded)) => (channel, degraded),
+            _ => return,
+        };
+        let content = match degraded {
+            true => "Saving guild data keeps failing, joins are only counted in memory until it works again. Please check the data directory.",
+            false => "Saving guild data works again.",
+        };
+        if let Err(err) = self.delivery.try_send(ctx, channel, content).await {
+            log::error!("Cannot notify ops channel {}: {}", channel, err);
+        }
+    }
+
+    async fn send_alert(&self, ctx: &Context, guild: GuildId, content: &str) {
+        if let Some(target) = self.settings().channels.get(&guild) {
+            self.delivery
+                .send(ctx, guild, &target.channels, content, self.fallback_admin())
+                .await;
+        }
+    }
+
+    /// Sends `TEST_ALERT` to each alert channel of the guild with `send`,
+    /// reporting the result for each channel.
+    async fn test_alert<S, F>(&self, guild: GuildId, mut send: S) -> String
+    where
+        S: FnMut(ChannelId) -> F,
+        F: Future<Output = Result<()>>,
+    {
+        let settings = self.settings();
+        let target = match settings.channels.get(&guild) {
+            Some(target) if !target.channels.is_empty() => target,
+            _ => return "No alert channels are configured for this server.".into(),
+        };
+        let mut reply = String::from("Test alert results:\n");
+        for &channel in &target.channels {
+            match send(channel).await {
+                Ok(()) => reply += &format!("<#{}>: sent\n", channel),
+                Err(err) => reply += &format!("<#{}>: failed: {}\n", channel, err),
+            }
+        }
+        reply
+    }
+
+    /// Posts a summary of the alerts suppressed when the current alert cooldown window ends.
+    fn schedule_alert_summary(&self, ctx: &Context, guild: GuildId, since: Instant) {
+        let channels = match self.settings().channels.get(&guild) {
+            Some(target) => target.channels.clone(),
+            None => return,
+        };
+        let cooldowns = Arc::clone(&self.alert_cooldowns);
+        let delivery = Arc::clone(&self.delivery);
+        let fallback = self.fallback_admin();
+        let ctx = ctx.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(cooldowns.cooldown()).await;
+            let suppressed = cooldowns.end_window(guild, since);
+            if suppressed > 0 {
+                let summary = format!(
+                    "Raid ongoing: {} more abnormal joins in the past {} seconds.",
+                    suppressed,
+                    cooldowns.cooldown().as_secs()
+                );
+                delivery
+                    .send(&ctx, guild, &channels, &summary, fallback)
+                    .await;
+            }
+        });
+    }
+
+    /// Holds back a non-critical alert of the guild during quiet hours ending at `until`,
+    /// summarizing the alerts held back when they end.
+    fn hold_alert(&self, ctx: &Context, guild: GuildId, until: u64) {
+        let channels = match self.settings().channels.get(&guild) {
+            Some(target) => target.channels.clone(),
+            None => return,
+        };
+        if !self.held_alerts.hold(guild) {
+            return;
+        }
+        let held_alerts = Arc::clone(&self.held_alerts);
+        let delivery = Arc::clone(&self.delivery);
+        let fallback = self.fallback_admin();
+        let ctx = ctx.clone();
+        let now = clock::SystemClock.now_secs();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(until.saturating_sub(now))).await;
+            let held = held_alerts.release(guild);
+            if held > 0 {
+                let summary = format!(
+                    "{} elevated alerts were held back during quiet hours.",
+                    held
+                );
+                delivery
+                    .send(&ctx, guild, &channels, &summary, fallback)
+                    .await;
+            }
+        });
+    }
+
+    async fn unlock(&self, ctx: &Context, mut guild: GuildId) -> Result<String> {
+        let previous = self.locked_down.lock().unwrap().remove(&guild);
+        match previous {
+            Some(level) => {
+                if let Err(err) = guild
+                    .edit(&ctx.http, |edit| edit.verification_level(level))
+                    .await
+                {
+                    self.locked_down.lock().unwrap().insert(guild, level);
+                    return Err(err.into());
+                }
+                Ok(format!(
+                    "Verification level has been restored to {:?}.",
+                    level
+                ))
+            }
+            None => Ok("This server has not been locked down.".into()),
+        }
+    }
+}
+
+/// The message sent by `adm testalert`.
+const TEST_ALERT: &str = "TEST ALERT: this is a test of raid alert delivery, no raid was detected.";
+
+/// Splits a command into its lowercase name and its arguments,
+/// ignoring any whitespace around and between them.
+fn split_command(content: &str) -> (String, std::str::SplitWhitespace<'_>) {
+    let mut args = content.split_whitespace();
+    // mobile clients capitalize the first word
+    let cmd = args.next().unwrap_or_default().to_lowercase();
+    (cmd, args)
+}
+
+/// Builds a `Handler` from values set in code rather than read from a config file.
+///
+/// Options without a setter keep their default config values.
+#[derive(Default)]
+pub struct HandlerBuilder {
+    config: Config,
+}
+
+impl HandlerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a bot account, which takes precedence after the ones added before.
+    pub fn bot(mut self, client_id: u64, token: impl Into<String>) -> Self {
+        self.config.discord.push(DiscordConfig {
+            client_id,
+            token: token.into(),
+            shards: None,
+        });
+        self
+    }
+
+    pub fn admin_ids(mut self, admin_ids: impl IntoIterator<Item = UserId>) -> Self {
+        self.config.admin_ids = admin_ids.into_iter().collect();
+        self
+    }
+
+    /// Sends the alerts of `guild` to `channels`, mentioning `@here`.
+    pub fn channels(mut self, guild: GuildId, channels: Vec<ChannelId>) -> Self {
+        let target = AlertTarget {
+            channels,
+            role: None,
+            ping_on_alert: default_ping_on_alert(),
+            ping_severity: default_min_severity(),
+            quiet_hours: None,
+        };
+        self.config.channels.insert(guild, target);
+        self
+    }
+
+    /// Accepts commands starting with `prefix` in addition to mentions of the bot.
+    pub fn command_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.config.command_prefix = Some(prefix.into());
+        self
+    }
+
+    pub fn data_dir(mut self, data_dir: impl Into<PathBuf>) -> Self {
+        self.config.data_dir = data_dir.into();
+        self
+    }
+
+    /// Sets the thresholds of guilds without their own thresholds.
+    pub fn threshold(mut self, threshold: ThresholdConfig) -> Self {
+        self.config.threshold = threshold;
+        self
+    }
+
+    /// Sets the thresholds of one guild.
+    pub fn guild_threshold(mut self, guild: GuildId, threshold: ThresholdConfig) -> Self {
+        self.config.thresholds.insert(guild, threshold);
+        self
+    }
+
+    /// Runs the bots added with `bot` until all of them stop,
+    /// leaving logging to the caller.
+    pub async fn run(self) -> Result<()> {
+        serve(self.config).await
+    }
+
+    /// Creates the data directory if necessary and constructs the handler.
+    pub fn build(self) -> io::Result<Handler> {
+        let Config {
+            admin_ids,
+            discord,
+            channels,
+            threshold,
+            thresholds,
+            backlog_size,
+            ewma_half_life_hours,
+            percentile_method,
+            backup_count,
+            max_loaded_guilds,
+            min_save_interval_seconds,
+            auto_lockdown,
+            auto_kick,
+            creation_cluster,
+            rate_limit,
+            rejoin_dedupe,
+            alert_cooldown_seconds,
+            send_retries,
+            alert_fallback_dm,
+            invite_tracking,
+            command_prefix,
+            data_dir,
+            ignored_guilds,
+            alert_template,
+            gap_alerts,
+            daily_reports,
+            invite,
+            audit_log,
+            min_consecutive_abnormal,
+            ops_channel,
+            command_cooldown,
+            exclude_bot_joins,
+            retention,
+            #[cfg(feature = "sqlite")]
+            sqlite_path,
+            #[cfg(feature = "redis")]
+            redis_url,
+            ..
+        } = self.config;
+
+        if !data_dir.exists() {
+            fs::create_dir_all(&data_dir)?;
+        }
+        check_writable(&data_dir)?;
+        let store: Box<dyn Store> = Box::new(JsonStore::new(data_dir.clone()));
+        #[cfg(feature = "sqlite")]
+        let store: Box<dyn Store> = match sqlite_path {
+            Some(path) => Box::new(open_sqlite(&path, &data_dir)?),
+            None => store,
+        };
+        #[cfg(feature = "redis")]
+        let store: Box<dyn Store> = match redis_url {
+            Some(url) => Box::new(RedisStore::open(&url)?),
+            None => store,
+        };
+        let ignored = IgnoredGuilds::load(
+            ignored_guilds.into_iter().collect(),
+            data_dir.join("monitoring.json"),
+        )?;
+
+        let permissions = invite.permissions.map_or_else(
+            || {
+                let mut permissions = Permissions::VIEW_CHANNEL
+                    | Permissions::SEND_MESSAGES
+                    | Permissions::EMBED_LINKS
+                    | Permissions::ATTACH_FILES;
+                if auto_lockdown.enabled || invite_tracking {
+                    permissions |= Permissions::MANAGE_GUILD;
+                }
+                if auto_kick.enabled {
+                    permissions |= auto_kick.action.permission();
+                }
+                permissions
+            },
+            Permissions::from_bits_truncate,
+        );
+        let invite_query = format!(
+            "scope={}&permissions={}",
+            invite.scopes.join("%20"),
+            permissions.bits()
+        );
+        Ok(Handler {
+            discord: discord
+                .into_iter()
+                .map(|discord| (discord.client_id, discord.token))
+                .collect(),
+            presence: BotPresence::default(),
+            stop: tokio::sync::Notify::new(),
+            command_prefix: command_prefix.filter(|prefix| !prefix.is_empty()),
+            guild_joins: Arc::new(GuildJoinsMap::new(
+                store,
+                Box::new(clock::SystemClock),
+                backlog_size,
+                ewma_alpha(ewma_half_life_hours),
+                percentile_method,
+                max_loaded_guilds,
+                Duration::from_secs(min_save_interval_seconds),
+            )),
+            reloadable: RwLock::new(Arc::new(Reloadable {
+                admin_ids,
+                channels,
+                threshold,
+                thresholds,
+            })),
+            backlog_size,
+            ewma_alpha: ewma_alpha(ewma_half_life_hours),
+            percentile_method,
+            backup_count,
+            lockdown: auto_lockdown,
+            auto_kick,
+            creation_cluster,
+            creation_times: CreationTimes::default(),
+            rate_limit,
+            join_rates: JoinRates::default(),
+            rejoin_dedupe,
+            recent_joiners: RecentJoiners::default(),
+            command_cooldown,
+            command_cooldowns: CommandCooldowns::default(),
+            exclude_bot_joins,
+            retention,
+            joiner_log: JoinerLog::default(),
+            ignored,
+            gap_alerts,
+            disconnections: Disconnections::default(),
+            daily_reports,
+            audit_log,
+            ops_channel,
+            started: Instant::now(),
+            alert_template: Template::parse(alert_template).map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("alert_template: {}", err),
+                )
+            })?,
+            locked_down: Mutex::default(),
+            alert_cooldowns: Arc::new(AlertCooldowns::new(Duration::from_secs(
+                alert_cooldown_seconds,
+            ))),
+            delivery: Arc::new(AlertDelivery::new(send_retries)),
+            alert_fallback_dm,
+            held_alerts: Arc::default(),
+            confirmations: PendingConfirmations::default(),
+            invite_tracking,
+            invite_query,
+            invite_uses: InviteUses::default(),
+            raid_states: RaidStates::default(),
+            min_consecutive_abnormal,
+            abnormal_streaks: AbnormalStreaks::default(),
+            nuke_states: RaidStates::default(),
+            #[cfg(feature = "metrics")]
+            metrics: Arc::default(),
+            #[cfg(feature = "health")]
+            ready: Arc::default(),
+        })
+    }
+}
+
+impl TryFrom<Config> for Handler {
+    type Error = io::Error;
+
+    fn try_from(config: Config) -> io::Result<Self> {
+        HandlerBuilder { config }.build()
+    }
+}
+
+/// Opens the SQLite database at `path`.
+///
+/// An empty database first imports the JSON files in `data_dir`,
+/// so that switching to SQLite keeps the existing data.
+#[cfg(feature = "sqlite")]
+fn open_sqlite(path: &Path, data_dir: &Path) -> io::Result<SqliteStore> {
+    let store = SqliteStore::open(path)?;
+    if store.guilds()?.is_empty() {
+        let imported = store.import_json(data_dir)?;
+        if imported > 0 {
+            log::info!(
+                "Imported {} guilds from {} into {}",
+                imported,
+                data_dir.display(),
+                path.display()
+            );
+        }
+    }
+    Ok(store)
+}
+
+/// Fails early if the data directory cannot be written to,
+/// rather than on the first save.
+fn check_writable(dir: &Path) -> io::Result<()> {
+    let probe = dir.join(".write-test");
+    fs::write(&probe, b"")
+        .and_then(|()| fs::remove_file(&probe))
+        .map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("data directory {} is not writable: {}", dir.display(), err),
+            )
+        })
+}
+
+/// One of the bot accounts run by this process, sharing the `Handler` with the others.
+struct Bot {
+    /// Index of the bot in the `discord` config.
+    index: usize,
+    /// Mentions of the bot that start a command, with and without the nickname marker.
+    mention_matches: Vec<String>,
+    invite_link: String,
+    /// When this bot last started a new gateway session.
+    connected_at: Mutex<Option<Instant>>,
+    /// Whether the daily report tasks have been started,
+    /// since the cache may become ready again after reconnecting.
+    daily_reports_started: AtomicBool,
+    handler: Arc<Handler>,
+}
+
+impl Bot {
+    fn new(index: usize, client_id: u64, handler: Arc<Handler>) -> Self {
+        Self {
+            index,
+            mention_matches: vec![format!("<@!{}>", client_id), format!("<@{}>", client_id)],
+            invite_link: format!(
+                "https://discord.com/oauth2/authorize?client_id={}&{}",
+                client_id, handler.invite_query
+            ),
+            connected_at: Mutex::default(),
+            daily_reports_started: AtomicBool::new(false),
+            handler,
+        }
+    }
+
+    /// Starts posting the daily report of each guild in `daily_reports` at its local midnight.
+    ///
+    /// Each bot schedules all reports, but only the leader of the guild at that time posts it.
+    fn start_daily_reports(&self, ctx: &Context) {
+        if self.daily_reports_started.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        for (&guild, &offset) in &self.daily_reports {
+            let handler = Arc::clone(&self.handler);
+            let index = self.index;
+            let ctx = ctx.clone();
+            tokio::spawn(async move {
+                loop {
+                    let now = clock::SystemClock.now_secs();
+                    let next = schedule::next_midnight(now, offset);
+                    tokio::time::sleep(Duration::from_secs(next - now)).await;
+                    if !handler.presence.is_leader(guild, index) {
+                        continue;
+                    }
+                    match handler.daily_report(guild).await {
+                        Ok(report) => handler.send_alert(&ctx, guild, &report).await,
+                        Err(err) => {
+                            log::error!("Cannot create daily report of guild {}: {}", guild, err)
+                        }
+                    }
+                }
+            });
+        }
+    }
+
+    /// Deletes the data of a guild that all bots left at `departure`,
+    /// unless one of them is added back within the grace period.
+    fn schedule_deletion(&self, guild: GuildId, departure: u64) {
+        log::info!(
+            "Deleting the data of guild {} in {} hours unless the bot is added back",
+            guild,
+            self.retention.grace_hours
+        );
+        let handler = Arc::clone(&self.handler);
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(handler.retention.grace_hours * 3600)).await;
+            // a later departure has its own grace period
+            if !handler.presence.is_gone_since(guild, departure) {
+                return;
+            }
+            match handler.with_joins(move |gj| gj.delete(guild)).await {
+                Ok(()) => log::info!("Deleted the data of guild {}", guild),
+                Err(err) => log::error!("Cannot delete the data of guild {}: {}", guild, err),
+            }
+        });
+    }
+
+    /// Returns whether this bot handles the events of the guild
+    /// rather than another bot in the same guild.
+    fn is_leader(&self, guild: GuildId) -> bool {
+        self.presence.is_leader(guild, self.index)
+    }
+
+    /// Whether this bot records the joins and leaves of the guild,
+    /// which is not the case for ignored guilds or guilds led by another bot.
+    fn monitors(&self, guild: GuildId) -> bool {
+        !self.ignored.is_ignored(guild) && self.is_leader(guild)
+    }
+
+    /// Returns the command after the bot mention or `command_prefix`,
+    /// or `None` if the message is not a command.
+    ///
+    /// The mention may be followed by any whitespace, such as a newline, or end the message.
+    /// Commands with the prefix are only handled by the leader of the guild
+    /// so that bots in the same guild do not all reply.
+    fn strip_prefix<'a>(&self, content: &'a str, guild: Option<GuildId>) -> Option<&'a str> {
+        let mentioned = self.mention_matches.iter().find_map(|prefix| {
+            let rest = content.strip_prefix(prefix.as_str())?;
+            match rest.chars().next() {
+                None => Some(rest),
+                Some(next) if next.is_whitespace() => Some(rest.trim_start()),
+                Some(_) => None,
+            }
+        });
+        mentioned.or_else(|| {
+            let prefix = self.command_prefix.as_deref()?;
+            if guild.is_some_and(|guild| !self.is_leader(guild)) {
+                return None;
+            }
+            content.strip_prefix(prefix)
+        })
+    }
+
+    fn uptime_reply(&self) -> String {
+        let mut reply = format!("Up for {}", format_duration(self.started.elapsed()));
+        if let Some(connected_at) = *self.connected_at.lock().unwrap() {
+            reply += &format!(
+                ", connected to Discord for {}",
+                format_duration(connected_at.elapsed())
+            );
+        }
+        reply + "."
+    }
+
+    fn invite_reply(&self) -> String {
+        format!("Invite link: {}", &self.invite_link)
+    }
+}
+
+impl std::ops::Deref for Bot {
+    type Target = Handler;
+
+    fn deref(&self) -> &Handler {
+        &self.handler
+    }
+}
+
+#[async_trait::async_trait]
+impl serenity::client::EventHandler for Bot {
+    async fn resume(&self, _ctx: Context, _resumed: ResumedEvent) {
+        // resumed sessions replay the missed events, so no joins are lost
+        log::info!("Resumed gateway session of bot {}", self.index);
+    }
+
+    async fn shard_stage_update(&self, ctx: Context, update: ShardStageUpdateEvent) {
+        let shard = update.shard_id.0;
+        if update.new == ConnectionStage::Connected {
+            let since = match self.disconnections.connected(self.index, shard) {
+                Some(since) => since,
+                None => return,
+            };
+            let now = clock::SystemClock.now_secs();
+            log::info!(
+                "Shard {} of bot {} reconnected after {} seconds",
+                shard,
+                self.index,
+                now.saturating_sub(since)
+            );
+            if !self.gap_alerts {
+                return;
+            }
+            let note = describe_gap(since, now);
+            let shard_count = ctx.cache.shard_count();
+            for guild in ctx.cache.guilds() {
+                if serenity::utils::shard_id(guild, shard_count) == shard && self.is_leader(guild) {
+                    self.send_alert(&ctx, guild, &note).await;
+                }
+            }
+        } else if update.old == ConnectionStage::Connected {
+            log::warn!(
+                "Shard {} of bot {} disconnected from the gateway",
+                shard,
+                self.index
+            );
+            self.disconnections
+                .disconnected(self.index, shard, clock::SystemClock.now_secs());
+        }
+    }
+
+    async fn guild_create(&self, _ctx: Context, guild: guild::Guild, _is_new: bool) {
+        self.presence.join(guild.id, self.index);
+    }
+
+    async fn guild_delete(
+        &self,
+        _ctx: Context,
+        incomplete: guild::UnavailableGuild,
+        _full: Option<guild::Guild>,
+    ) {
+        // unavailable guilds are only down temporarily
+        if !incomplete.unavailable {
+            let departure = self.presence.leave(incomplete.id, self.index);
+            if let Some(departure) = departure.filter(|_| self.retention.enabled) {
+                self.schedule_deletion(incomplete.id, departure);
+            }
+        }
+    }
+
+    async fn ready(&self, ctx: Context, ready: Ready) {
+        trying(|| async {
+            log::info!("Connected as {}", &ready.user.name);
+            *self.connected_at.lock().unwrap() = Some(Instant::now());
+            // each shard is only ready with its own guilds
+            #[cfg(feature = "health")]
+            self.ready.fetch_or(
+                !ready.guilds.is_empty(),
+                std::sync::atomic::Ordering::Relaxed,
+            );
+            if ctx.shard_id != 0 {
+                // global commands only need to be registered once
+                return Ok(());
+            }
+            Command::set_global_application_commands(&ctx.http, |commands| {
+                commands
+                    .create_application_command(|command| {
+                        command
+                            .name("stat")
+                            .description("Show join statistics of this server")
+                            .dm_permission(false)
+                    })
+                    .create_application_command(|command| {
+                        command
+                            .name("invite")
+                            .description("Get the link to invite this bot to a server")
+                    })
+                    .create_application_command(|command| {
+                        command
+                            .name("reset")
+                            .description("Clear the join history of this server")
+                            .dm_permission(false)
+                            .default_member_permissions(Permissions::MANAGE_GUILD)
+                            .create_option(|option| {
+                                option
+                                    .name("confirm")
+                                    .description(format!(
+                                        "Confirm a reset requested in the last {} seconds",
+                                        CONFIRM_WINDOW.as_secs()
+                                    ))
+                                    .kind(CommandOptionType::Boolean)
+                                    .required(false)
+                            })
+                    })
+            })
+            .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn cache_ready(&self, ctx: Context, guilds: Vec<GuildId>) {
+        self.start_daily_reports(&ctx);
+
+        // record the initial use counts so that the first join can be attributed
+        let alerted: Vec<_> = self.settings().channels.keys().copied().collect();
+        for guild in alerted {
+            self.track_invites(&ctx, guild).await;
+        }
+
+        let semaphore = Arc::new(tokio::sync::Semaphore::new(PRIME_CONCURRENCY));
+        let tasks: Vec<_> = guilds
+            .into_iter()
+            .map(|guild| {
+                let semaphore = Arc::clone(&semaphore);
+                let guild_joins = Arc::clone(&self.guild_joins);
+                tokio::spawn(async move {
+                    let _permit = semaphore.acquire().await;
+                    let result = tokio::task::spawn_blocking(move || guild_joins.prime(guild))
+                        .await
+                        .map_err(io::Error::from)
+                        .and_then(|result| result);
+                    (guild, result)
+                })
+            })
+            .collect();
+
+        let (mut loaded, mut failed) = (0, 0);
+        for task in tasks {
+            match task.await {
+                Ok((_, Ok(()))) => loaded += 1,
+                Ok((guild, Err(err))) => {
+                    log::warn!("Cannot preload data of guild {}: {}", guild, err);
+                    failed += 1;
+                }
+                Err(err) => {
+                    log::warn!("Preload task failed: {}", err);
+                    failed += 1;
+                }
+            }
+        }
+        log::info!("Preloaded {} guilds, {} failed", loaded, failed);
+    }
+
+    async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
+        trying(|| async {
+            let command = match interaction {
+                Interaction::ApplicationCommand(command) => command,
+                _ => return Ok(()),
+            };
+
+            let content = match (command.data.name.as_str(), command.guild_id) {
+                ("invite", _) => self.invite_reply(),
+                ("stat", Some(guild)) if self.ignored.is_ignored(guild) => {
+                    MONITORING_DISABLED.into()
+                }
+                ("stat", Some(guild)) => {
+                    let embed = self.stat_embed(guild).await?;
+                    command
+                        .create_interaction_response(&ctx.http, |response| {
+                            response
+                                .kind(InteractionResponseType::ChannelMessageWithSource)
+                                .interaction_response_data(|data| data.add_embed(embed))
+                        })
+                        .await?;
+                    return Ok(());
+                }
+                ("reset", Some(guild)) => {
+                    // Discord already hides the command from members without MANAGE_GUILD,
+                    // but the configured admins may use it regardless.
+                    let has_permission = command
+                        .member
+                        .as_ref()
+                        .and_then(|member| member.permissions)
+                        .is_some_and(|permissions| permissions.manage_guild());
+                    let allowed = has_permission || self.is_admin(command.user.id);
+                    let confirmed = command
+                        .data
+                        .options
+                        .iter()
+                        .find(|option| option.name == "confirm")
+                        .and_then(|option| option.value.as_ref())
+                        .and_then(serde_json::Value::as_bool)
+                        .unwrap_or(false);
+                    let content = self
+                        .slash_reset(guild, command.user.id, allowed, confirmed)
+                        .await?;
+                    command
+                        .create_interaction_response(&ctx.http, |response| {
+                            response
+                                .kind(InteractionResponseType::ChannelMessageWithSource)
+                                .interaction_response_data(|data| {
+                                    data.content(content).ephemeral(true)
+                                })
+                        })
+                        .await?;
+                    return Ok(());
+                }
+                _ => return Ok(()),
+            };
+
+            command
+                .create_interaction_response(&ctx.http, |response| {
+                    response
+                        .kind(InteractionResponseType::ChannelMessageWithSource)
+                        .interaction_response_data(|data| data.content(content))
+                })
+                .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn guild_member_addition(&self, ctx: Context, member: guild::Member) {
+        trying(|| async {
+            let guild_id = member.guild_id;
+            if !self.monitors(guild_id) {
+                return Ok(());
+            }
+            let guild = guild::Guild::get(&ctx, guild_id).await?;
+
+            let rejoined = self.rejoin_dedupe.enabled
+                && self.recent_joiners.record(
+                    &self.rejoin_dedupe,
+                    guild_id,
+                    member.user.id,
+                    Instant::now(),
+                );
+            if rejoined {
+                log::debug!(
+                    "User {} rejoined guild {} within the dedupe window",
+                    member.user.id,
+                    &guild.name
+                );
+            }
+
+            let excluded = self.exclude_bot_joins.excludes(&member.user);
+            let delta = u32::from(!rejoined && !excluded);
+            let stat = match member.user.bot {
+                true => {
+                    let stat = self
+                        .with_joins(move |gj| gj.add_bot(guild_id, delta))
+                        .await?;
+                    #[cfg(feature = "metrics")]
+                    self.metrics.observe(guild_id, &stat);
+                    stat
+                }
+                false => self.add_joins(guild_id, delta).await?,
+            };
+            self.report_degraded(&ctx).await;
+            if self.gap_alerts {
+                let gap = self
+                    .with_joins(move |gj| gj.take_offline_gap(guild_id))
+                    .await?;
+                if let Some((from, to)) = gap {
+                    self.send_alert(&ctx, guild_id, &describe_gap(from * 3600, to * 3600))
+                        .await;
+                }
+            }
+            self.joiner_log.record(
+                guild_id,
+                Joiner {
+                    user: member.user.id,
+                    joined_at: clock::SystemClock.now_secs(),
+                    created_at: member.user.created_at().unix_timestamp(),
+                },
+            );
+            let invite = self.track_invites(&ctx, guild_id).await;
+            if self.creation_cluster.enabled {
+                let created_at = member.user.created_at().unix_timestamp();
+                self.creation_times
+                    .record(&self.creation_cluster, guild_id, created_at);
+            }
+
+            // the rate of an expected influx is expected to be high too
+            let rate_exceeded = self.rate_limit.enabled
+                && delta > 0
+                && stat.expected == 0
+                && self
+                    .join_rates
+                    .record(&self.rate_limit, guild_id, Instant::now());
+            let mut severity = stat.severity(&self.threshold(guild_id).await?);
+            if rate_exceeded {
+                severity = severity.max(Severity::Elevated);
+            }
+            let streak = self
+                .abnormal_streaks
+                .update(guild_id, severity != Severity::Normal);
+            if streak > 0 && streak < self.min_consecutive_abnormal {
+                log::debug!(
+                    "Abnormal join {} of {} required in guild {}",
+                    streak,
+                    self.min_consecutive_abnormal,
+                    &guild.name
+                );
+                severity = Severity::Normal;
+            }
+            let is_abnormal = severity != Severity::Normal;
+            let transition = self.raid_states.update(guild_id, is_abnormal);
+            logging::event(
+                log::Level::Info,
+                format_args!("Guild {} stats: {:?}", &guild.name, &stat),
+                serde_json::json!({
+                    "event": "join",
+                    "guild_id": guild_id.to_string(),
+                    "guild": &guild.name,
+                    "current": stat.current,
+                    "mean": stat.mean,
+                    "uq": stat.uq,
+                    "is_abnormal": is_abnormal,
+                    "severity": severity,
+                }),
+            );
+
+            if is_abnormal {
+                logging::event(
+                    log::Level::Warn,
+                    format_args!("Abnormal joins detected in guild {}", &guild.name),
+                    serde_json::json!({
+                        "event": "raid_detected",
+                        "rate_exceeded": rate_exceeded,
+                        "severity": severity,
+                        "guild_id": guild_id.to_string(),
+                        "guild": &guild.name,
+                        "current": stat.current,
+                        "mean": stat.mean,
+                        "uq": stat.uq,
+                    }),
+                );
+                #[cfg(feature = "metrics")]
+                self.metrics.raid_detected(guild_id);
+                let started = transition == RaidTransition::Started;
+                let snapshot = stat.clone();
+                self.with_joins(move |gj| gj.record_raid(guild_id, started, snapshot))
+                    .await?;
+                let lockdown_note = self.lock_down(&ctx, &guild, severity).await;
+                let kick_note = self.mitigate(&ctx, &member, severity).await;
+                if let Some(target) = self.settings().channels.get(&guild_id) {
+                    let quiet_until = target
+                        .quiet_hours
+                        .filter(|_| severity < Severity::Critical)
+                        .and_then(|quiet| quiet.end_if_quiet(clock::SystemClock.now_secs()));
+                    if let Some(until) = quiet_until {
+                        self.hold_alert(&ctx, guild_id, until);
+                        if let Some(note) = lockdown_note {
+                            self.send_alert(&ctx, guild_id, &note).await;
+                        }
+                    } else if let Some(since) = self.alert_cooldowns.try_alert(guild_id) {
+                        let mut alert = self.alert_template.render(|name| match name {
+                            "mention" => target.mention(severity),
+                            "level" => match severity {
+                                Severity::Critical => "CRITICAL ALERT".into(),
+                                _ => "ALERT".into(),
+                            },
+                            "guild" => guild.name.clone(),
+                            "current" => stat.current.to_string(),
+                            "uq" => format!("{:.3}", stat.uq),
+                            "mean" => format!("{:.3}", stat.mean),
+                            "median" => format!("{:.3}", stat.median),
+                            "stats" => stat.to_string(),
+                            _ => String::new(),
+                        });
+                        if !alert.ends_with('\n') {
+                            alert.push('\n');
+                        }
+                        if rate_exceeded {
+                            alert += &format!(
+                                "More than {} joins in the past {} minutes\n",
+                                self.rate_limit.max_joins, self.rate_limit.window_minutes
+                            );
+                        }
+                        match &invite {
+                            Some(code) => alert += &format!("Likely invite: `{}`\n", code),
+                            None if self.invite_tracking && guild.vanity_url_code.is_some() => {
+                                alert += "Likely invite: the vanity URL\n";
+                            }
+                            None => (),
+                        }
+                        if self.creation_cluster.enabled {
+                            if let Some(cluster) = self
+                                .creation_times
+                                .describe(&self.creation_cluster, guild_id)
+                            {
+                                alert += &format!("Suspicious account ages: {}\n", cluster);
+                            }
+                        }
+                        if let Some(note) = &lockdown_note {
+                            alert += note;
+                        }
+                        self.send_alert(&ctx, guild_id, &alert).await;
+                        self.schedule_alert_summary(&ctx, guild_id, since);
+                    } else if let Some(note) = lockdown_note {
+                        self.send_alert(&ctx, guild_id, &note).await;
+                    }
+                    if let Some(note) = kick_note {
+                        self.send_alert(&ctx, guild_id, &note).await;
+                    }
+                }
+            }
+            if transition == RaidTransition::Ended {
+                log::info!("Joins in guild {} are back to normal", &guild.name);
+                self.send_alert(
+                    &ctx,
+                    guild_id,
+                    &format!(
+                        "Join rate back to normal (current {}, upper quartile {:.1})",
+                        stat.current, stat.uq
+                    ),
+                )
+                .await;
+            }
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn guild_member_removal(
+        &self,
+        ctx: Context,
+        guild_id: GuildId,
+        _user: User,
+        _member: Option<guild::Member>,
+    ) {
+        trying(|| async {
+            if !self.monitors(guild_id) {
+                return Ok(());
+            }
+            let stat = self.with_joins(move |gj| gj.add_leave(guild_id, 1)).await?;
+            log::debug!("Guild {} stats after leave: {:?}", guild_id, &stat);
+
+            let is_nuke = stat.is_nuke(&self.threshold(guild_id).await?);
+            if self.nuke_states.update(guild_id, is_nuke) == RaidTransition::Started {
+                logging::event(
+                    log::Level::Warn,
+                    format_args!("Mass join and leave detected in guild {}", guild_id),
+                    serde_json::json!({
+                        "event": "nuke_detected",
+                        "guild_id": guild_id.to_string(),
+                        "joins": stat.nuke_window.joins,
+                        "leaves": stat.nuke_window.leaves,
+                    }),
+                );
+                let window = stat.nuke_window;
+                self.with_joins(move |gj| gj.record_nuke(guild_id, stat))
+                    .await?;
+                if let Some(target) = self.settings().channels.get(&guild_id) {
+                    let alert = format!(
+                        "{}ALERT: mass join+leave pattern detected, {} joins followed by {} leaves recently",
+                        target.mention(Severity::Elevated),
+                        window.joins,
+                        window.leaves
+                    );
+                    self.send_alert(&ctx, guild_id, &alert).await;
+                }
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn reaction_add(&self, ctx: Context, reaction: channel::Reaction) {
+        trying(|| async {
+            let user = match reaction.user_id {
+                Some(user) if reaction.emoji.unicode_eq(CONFIRM_EMOJI) => user,
+                _ => return Ok(()),
+            };
+            let command =
+                match self
+                    .confirmations
+                    .confirm_prompt(user, reaction.message_id, Instant::now())
+                {
+                    Some(command) => command,
+                    None => return Ok(()),
+                };
+            let mut args: Vec<_> = command.split_whitespace().collect();
+            let sub = args.remove(0);
+            self.audit(Some(user), reaction.guild_id, &command, true);
+            let reply = self.run_admin(sub, args, reaction.guild_id).await?;
+            reaction.channel_id.say(&ctx, reply).await?;
+            Ok(())
+        })
+        .await;
+    }
+
+    async fn message(&self, ctx: Context, message: channel::Message) {
+        trying(|| async {
+            let guild = message.guild(&ctx);
+            let channel = message.channel(&ctx).await;
+            if let (Some(guild), Ok(channel::Channel::Guild(channel))) = (guild, channel) {
+                log::debug!(
+                    "Message from {} #{}: <{}> {}",
+                    &guild.name,
+                    &channel.name,
+                    &message.author.name,
+                    &message.content
+                );
+            }
+
+            if let Some(content) = self.strip_prefix(&message.content, message.guild_id) {
+                if self.command_cooldown.enabled && !self.is_admin(message.author.id) {
+                    let check = self.command_cooldowns.check(
+                        &self.command_cooldown,
+                        message.author.id,
+                        Instant::now(),
+                    );
+                    match check {
+                        CooldownCheck::Accepted => (),
+                        CooldownCheck::IgnoredFirst if self.command_cooldown.notify => {
+                            let reply = format!(
+                                "Please wait {} seconds between commands.",
+                                self.command_cooldown.seconds
+                            );
+                            message.reply(&ctx, reply).await?;
+                            return Ok(());
+                        }
+                        CooldownCheck::IgnoredFirst | CooldownCheck::Ignored => return Ok(()),
+                    }
+                }
+                let (cmd, mut args) = split_command(content);
+                if self.is_disabled_for(&cmd, message.guild_id) {
+                    message.reply(&ctx, MONITORING_DISABLED).await?;
+                    return Ok(());
+                }
+                match cmd.as_str() {
+                    "help" => {
+                        let mut help = String::from(HELP);
+                        if self.is_admin(message.author.id) {
+                            help += ADMIN_HELP;
+                        }
+                        message.reply(&ctx, help).await?;
+                    }
+                    "invite" => {
+                        message.reply(&ctx, self.invite_reply()).await?;
+                    }
+                    "version" => {
+                        let reply = match option_env!("GIT_HASH") {
+                            Some(hash) => format!("boredphoton {} ({})", VERSION, hash),
+                            None => format!("boredphoton {}", VERSION),
+                        };
+                        message.reply(&ctx, reply).await?;
+                    }
+                    "uptime" => {
+                        message.reply(&ctx, self.uptime_reply()).await?;
+                    }
+                    "raids" => {
+                        if let Some(guild) = message.guild_id {
+                            let count = args.next().and_then(|arg| arg.parse().ok());
+                            let reply = self.raids_reply(guild, count.unwrap_or(10)).await?;
+                            message.reply(&ctx, reply).await?;
+                        }
+                    }
+                    "serverinfo" => {
+                        if let Some(guild) = message.guild_id {
+                            let reply = self.serverinfo_reply(&ctx, guild).await?;
+                            message.reply(&ctx, reply).await?;
+                        }
+                    }
+                    "whois-recent" => {
+                        if let Some(guild) = message.guild_id {
+                            // the joiners are only shown to those who could act on them
+                            let can_moderate = match message.member(&ctx).await {
+                                Ok(member) => member.permissions(&ctx).is_ok_and(|permissions| {
+                                    permissions.kick_members() || permissions.manage_guild()
+                                }),
+                                Err(_) => false,
+                            };
+                            let reply = if can_moderate || self.is_admin(message.author.id) {
+                                let count = args.next().and_then(|arg| arg.parse().ok());
+                                self.whois_recent_reply(guild, count.unwrap_or(10))
+                            } else {
+                                "You do not have permission to use this command.".into()
+                            };
+                            message
+                                .channel_id
+                                .send_message(&ctx, |reply_message| {
+                                    reply_message
+                                        .content(reply)
+                                        .allowed_mentions(|mentions| mentions.empty_parse())
+                                        .reference_message(&message)
+                                })
+                                .await?;
+                        }
+                    }
+                    "rank" => {
+                        if let Some(guild) = message.guild_id {
+                            let reply = self.rank_reply(guild).await?;
+                            message.reply(&ctx, reply).await?;
+                        }
+                    }
+                    "stat" => {
+                        let mut args = args.peekable();
+                        let target = match self.stat_target(
+                            &mut args,
+                            message.author.id,
+                            message.guild_id,
+                        ) {
+                            Ok(target) => target,
+                            Err(reply) => {
+                                message.reply(&ctx, reply).await?;
+                                return Ok(());
+                            }
+                        };
+                        if let Some(guild) = target {
+                            if self.ignored.is_ignored(guild) {
+                                message.reply(&ctx, MONITORING_DISABLED).await?;
+                                return Ok(());
+                            }
+                            let reply = match args.next().map(str::to_lowercase).as_deref() {
+                                Some("json") => self.stat_json_reply(guild).await?,
+                                Some("day") => {
+                                    match args.next().and_then(|arg| arg.parse::<usize>().ok()) {
+                                        Some(days) if days > 0 => {
+                                            self.window_reply(guild, days * 24, (days - 1) * 24)
+                                                .await?
+                                        }
+                                        _ => "Usage: `stat day <days ago>`".into(),
+                                    }
+                                }
+                                Some("range") => {
+                                    let from = args.next().and_then(|arg| arg.parse().ok());
+                                    let to = args.next().and_then(|arg| arg.parse().ok());
+                                    match (from, to) {
+                                        (Some(from), Some(to)) if from > to => {
+                                            self.window_reply(guild, from, to).await?
+                                        }
+                                        _ => "Usage: `stat range <from hours ago> <to hours ago>`"
+                                            .into(),
+                                    }
+                                }
+                                Some("history") => {
+                                    let hours = args.next().and_then(|arg| arg.parse().ok());
+                                    self.history_reply(guild, hours.unwrap_or(24)).await?
+                                }
+                                Some("text") => self.stat_reply(guild).await?,
+                                Some("peak") => self.peak_reply(guild).await?,
+                                Some("forecast") => self.forecast_reply(guild).await?,
+                                Some("recent") => {
+                                    let hours = args.next().and_then(|arg| arg.parse().ok());
+                                    let reply =
+                                        self.recent_reply(guild, hours.unwrap_or(24)).await?;
+                                    if reply.len() > MESSAGE_LIMIT {
+                                        let file = channel::AttachmentType::Bytes {
+                                            data: reply.into_bytes().into(),
+                                            filename: format!("recent-joins-{}.txt", guild),
+                                        };
+                                        message
+                                            .channel_id
+                                            .send_message(&ctx, |reply| {
+                                                reply
+                                                    .content("Too many hours to list in a message:")
+                                                    .add_file(file)
+                                                    .reference_message(&message)
+                                            })
+                                            .await?;
+                                        return Ok(());
+                                    }
+                                    reply
+                                }
+                                _ => {
+                                    let embed = self.stat_embed(guild).await?;
+                                    message
+                                        .channel_id
+                                        .send_message(&ctx, |reply| {
+                                            reply.reference_message(&message).set_embed(embed)
+                                        })
+                                        .await?;
+                                    return Ok(());
+                                }
+                            };
+                            message.reply(&ctx, reply).await?;
+                        }
+                    }
+                    "adm" => {
+                        let adm_args: Vec<_> = args.collect();
+                        let allowed = self.is_admin(message.author.id);
+                        self.audit(
+                            Some(message.author.id),
+                            message.guild_id,
+                            &adm_args.join(" "),
+                            allowed,
+                        );
+                        if !allowed {
+                            return Ok(());
+                        }
+                        match adm_args.first().map(|arg| arg.to_lowercase()).as_deref() {
+                            Some("unlock") => {
+                                if let Some(guild) = message.guild_id {
+                                    let reply = self.unlock(&ctx, guild).await?;
+                                    message.reply(&ctx, reply).await?;
+                                }
+                            }
+                            Some("testalert") => {
+                                if let Some(guild) = message.guild_id {
+                                    let reply = self
+                                        .test_alert(guild, |channel| {
+                                            self.delivery.try_send(&ctx, channel, TEST_ALERT)
+                                        })
+                                        .await;
+                                    message.reply(&ctx, reply).await?;
+                                }
+                            }
+                            Some("global") => {
+                                let reply = self
+                                    .global_reply(|guild| {
+                                        ctx.cache.guild_field(guild, |guild| guild.name.clone())
+                                    })
+                                    .await?;
+                                message.reply(&ctx, reply).await?;
+                            }
+                            Some("fleet-stat") => {
+                                let reply = self.fleet_reply(&ctx).await?;
+                                message
+                                    .channel_id
+                                    .send_message(&ctx, |reply_message| {
+                                        reply_message
+                                            .content(reply)
+                                            .allowed_mentions(|mentions| mentions.empty_parse())
+                                            .reference_message(&message)
+                                    })
+                                    .await?;
+                            }
+                            Some("guilds") => {
+                                let reply = self
+                                    .guilds_reply(|guild| {
+                                        ctx.cache.guild_field(guild, |guild| guild.name.clone())
+                                    })
+                                    .await?;
+                                message
+                                    .channel_id
+                                    .send_message(&ctx, |reply_message| {
+                                        if reply.len() > MESSAGE_LIMIT {
+                                            let file = channel::AttachmentType::Bytes {
+                                                data: reply.into_bytes().into(),
+                                                filename: "guilds.txt".into(),
+                                            };
+                                            reply_message
+                                                .content("Too many servers to list in a message:")
+                                                .add_file(file);
+                                        } else {
+                                            reply_message.content(reply);
+                                        }
+                                        reply_message
+                                            .allowed_mentions(|mentions| mentions.empty_parse())
+                                            .reference_message(&message)
+                                    })
+                                    .await?;
+                            }
+                            Some("export") => {
+                                if let Some(guild) = message.guild_id {
+                                    let csv = self.with_joins(move |gj| gj.export(guild)).await?;
+                                    let file = channel::AttachmentType::Bytes {
+                                        data: csv.into_bytes().into(),
+                                        filename: format!("joins-{}.csv", guild),
+                                    };
+                                    message
+                                        .channel_id
+                                        .send_message(&ctx, |reply| {
+                                            reply
+                                                .content("Hourly joins of this server:")
+                                                .add_file(file)
+                                                .reference_message(&message)
+                                        })
+                                        .await?;
+                                }
+                            }
+                            _ => {
+                                let reply = self
+                                    .handle_admin(
+                                        &adm_args.join(" "),
+                                        message.guild_id,
+                                        Some(message.author.id),
+                                    )
+                                    .await?;
+                                let sent = message.reply(&ctx, reply).await?;
+                                if self.confirmations.set_prompt(message.author.id, sent.id) {
+                                    sent.react(
+                                        &ctx,
+                                        channel::ReactionType::Unicode(CONFIRM_EMOJI.into()),
+                                    )
+                                    .await?;
+                                }
+                            }
+                        }
+                    }
+                    _ => (),
+                }
+            }
+
+            Ok(())
+        })
+        .await;
+    }
+}
+
+/// Reacting to a confirmation prompt with this confirms the destructive admin command.
+const CONFIRM_EMOJI: &str = "\u{2705}";
+
+/// Interval between deletions of the data of idle guilds, if `retention` is enabled.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(24 * 3600);
+
+/// Audit log reason of the actions taken by auto kick.
+const MITIGATION_REASON: &str = "Account too new during abnormal joins";
+
+/// Maximum number of guilds loaded concurrently on startup.
+const PRIME_CONCURRENCY: usize = 16;
+
+const HELP: &str = "Commands:
+`help`: show this message
+`invite`: get the link to invite this bot to a server
+`version`: show the version of this bot
+`uptime`: show how long this bot has been running
+`stat`: show join statistics of this server
+`stat json`: show join statistics of this server as JSON
+`stat text`: show the join statistics of this server as plain text
+`stat peak`: show the busiest hour ever recorded in this server
+`stat forecast`: project the joins of the current hour from its elapsed minutes
+`stat recent [hours]`: list the joins in each recent hour of this server
+`stat history [hours]`: show a chart of hourly joins in this server
+`stat day <days>`: summarize the joins in the 24 hours starting this many days ago
+`stat range <from> <to>`: summarize the joins from and to this many hours ago
+`rank`: compare joins in this hour to previous hours
+`serverinfo`: compare joins in this hour to the member count of this server
+`whois-recent [count]`: list the latest members who joined this server, for moderators
+`raids [count]`: list the latest raids detected in this server
+";
+
+const ADMIN_HELP: &str = "Admin commands:
+`adm save`: save the join data of all servers
+`adm reload`: reload the admins, alert channels and thresholds from the config
+`adm stat [server id]`: show the join statistics of this or the given server
+`stat <server id> [subcommand]`: run any `stat` command for the given server
+`adm reset`: clear the join history of this server
+`adm unlock`: restore the verification level changed by auto lockdown
+`adm backup`: save and back up the join data of all servers
+`adm restore <backup>`: replace the join data with the named backup
+`adm testalert`: send a test alert to the alert channels of this server
+`adm global`: summarize the joins of all servers
+`adm fleet-stat`: compare the joins of each server to the other servers
+`adm guilds`: list the joins and status of all loaded servers
+`adm export`: upload the hourly joins of this server as CSV
+`adm threshold <multiplier> <offset>`: set the detection thresholds of this server
+`adm expect <joins> <duration>`: expect up to this many more joins per hour in this server for a duration like `90m`, `3h` or `2d`
+`adm expect clear`: stop expecting more joins in this server
+`adm seed <joins per hour> [server id]`: estimate the joins of the hours without data, such as before the bot joined
+`adm simulate <multiplier> <offset>`: count the recorded hours these thresholds would have flagged
+`adm enable [server id]`: monitor joins in this or the given server
+`adm disable [server id]`: stop monitoring joins in this or the given server
+`adm stop`: save all data and stop the bot
+`adm stop`, `adm reset` and `adm restore` must be confirmed within 30 seconds by reacting \u{2705} or sending them again followed by `confirm`
+";
+
+const EXPECT_USAGE: &str =
+    "Usage: `adm expect <joins per hour> <duration>`, e.g. `adm expect 100 3h`";
+
+/// Formats a duration with its two largest units, e.g. `3d 4h` or `5m 12s`.
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (days, hours, minutes, secs) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60);
+    if days > 0 {
+        format!("{}d {}h", days, hours)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, minutes)
+    } else if minutes > 0 {
+        format!("{}m {}s", minutes, secs)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+/// Parses a duration like `90m`, `3h` or `2d`, in hours if no unit is given.
+fn parse_duration(s: &str) -> Option<Duration> {
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => s.split_at(index),
+        None => (s, "h"),
+    };
+    let unit_secs = match unit {
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => return None,
+    };
+    let secs = number.parse::<u64>().ok()?.checked_mul(unit_secs)?;
+    Some(Duration::from_secs(secs))
+}
+
+/// The version of this build.
+///
+/// `version` also shows the git commit if `GIT_HASH` is set when building,
+/// e.g. `GIT_HASH=$(git rev-parse --short HEAD) cargo build`.
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Maximum length of a Discord message.
+const MESSAGE_LIMIT: usize = 2000;
+
+/// The most characters in the sparkline of `stat history`,
+/// which leaves room for the rest of the reply within `MESSAGE_LIMIT`.
+const SPARKLINE_WIDTH: usize = 500;
+
+/// Maximum number of servers listed by `adm fleet-stat`, which fit in a message.
+const MAX_FLEET_OUTLIERS: usize = 20;
+
+/// Maximum number of joins listed by `whois-recent`, which fit in a message.
+const MAX_WHOIS_RECENT: usize = 20;
+
+const MONITORING_DISABLED: &str = "Join monitoring is disabled in this server.";
+
+async fn trying<F, R>(f: F)
+where
+    F: FnOnce() -> R,
+    R: Future<Output = Result<()>>,
+{
+    match f().await {
+        Ok(()) => (),
+        Err(err) if err.is_transient() => {
+            log::warn!("Transient error handling event: {}", err);
+        }
+        Err(err) => {
+            log::error!("Error handling event: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn builder_builds_handler() {
+        let data_dir = TempDir::new("handler");
+        let guild = GuildId(1);
+        let other = GuildId(2);
+        let handler = HandlerBuilder::new()
+            .bot(10, "token")
+            .admin_ids([UserId(3)])
+            .channels(guild, vec![ChannelId(4)])
+            .data_dir(&data_dir)
+            .guild_threshold(
+                guild,
+                ThresholdConfig {
+                    multiplier: 3.,
+                    ..ThresholdConfig::default()
+                },
+            )
+            .build()
+            .unwrap();
+
+        assert!(data_dir.is_dir());
+        assert!(handler.is_admin(UserId(3)));
+        assert!(!handler.is_admin(UserId(4)));
+        let settings = handler.settings();
+        assert_eq!(settings.channels[&guild].channels, [ChannelId(4)]);
+        assert!(!settings.channels.contains_key(&other));
+        assert_eq!(settings.thresholds[&guild].multiplier, 3.);
+        assert!(!settings.thresholds.contains_key(&other));
+    }
+
+    #[test]
+    fn builder_fails_on_unwritable_data_dir() {
+        // a file cannot be used as the data directory
+        let data_dir = TempDir::new("handler-file");
+        fs::write(&data_dir, b"").unwrap();
+        assert!(HandlerBuilder::new().data_dir(&data_dir).build().is_err());
+    }
+
+    /// Held by tests that set the environment variables read by `load_config`.
+    static CONFIG_ENV: Mutex<()> = Mutex::new(());
+
+    /// A path under the system temporary directory, removed with its contents when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            Self(std::env::temp_dir().join(format!(
+                "boredphoton-{}-{}-{}",
+                name,
+                std::process::id(),
+                NEXT.fetch_add(1, Ordering::Relaxed)
+            )))
+        }
+
+        /// Builds a handler with the default config and its data in this directory.
+        fn handler(&self) -> Handler {
+            HandlerBuilder::new().data_dir(self).build().unwrap()
+        }
+    }
+
+    impl std::ops::Deref for TempDir {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl AsRef<Path> for TempDir {
+        fn as_ref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl From<&TempDir> for PathBuf {
+        fn from(dir: &TempDir) -> Self {
+            dir.0.clone()
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            // tests may also put a file here, or nothing at all
+            let _ = match self.0.is_dir() {
+                true => fs::remove_dir_all(&self.0),
+                false => fs::remove_file(&self.0),
+            };
+        }
+    }
+
+    #[tokio::test]
+    async fn unlisted_guilds_use_the_default_threshold() {
+        let data_dir = TempDir::new("default-threshold");
+        let handler = HandlerBuilder::new()
+            .data_dir(&data_dir)
+            .threshold(ThresholdConfig {
+                multiplier: 4.,
+                ..ThresholdConfig::default()
+            })
+            .guild_threshold(
+                GuildId(1),
+                ThresholdConfig {
+                    multiplier: 3.,
+                    ..ThresholdConfig::default()
+                },
+            )
+            .build()
+            .unwrap();
+        assert_eq!(handler.threshold(GuildId(1)).await.unwrap().multiplier, 3.);
+        assert_eq!(handler.threshold(GuildId(2)).await.unwrap().multiplier, 4.);
+    }
+
+    #[test]
+    fn environment_overrides_the_config_file() {
+        let data_dir = TempDir::new("config");
+        fs::create_dir_all(&data_dir).unwrap();
+        let path = data_dir.join("config.toml");
+        fs::write(
+            &path,
+            r#"
+                admin_ids = [3]
+                backlog_size = 48
+
+                [discord]
+                client_id = 10
+                token = "from file"
+
+                [channels]
+                1 = 4
+            "#,
+        )
+        .unwrap();
+
+        let _env = CONFIG_ENV.lock().unwrap();
+        std::env::set_var("CONFIG_PATH", &path);
+        std::env::set_var("BOREDPHOTON_DISCORD__TOKEN", "from env");
+        std::env::set_var("BOREDPHOTON_BACKLOG_SIZE", "72");
+        let config = load_config();
+        std::env::remove_var("CONFIG_PATH");
+        std::env::remove_var("BOREDPHOTON_DISCORD__TOKEN");
+        std::env::remove_var("BOREDPHOTON_BACKLOG_SIZE");
+
+        let config = config.unwrap();
+        assert_eq!(config.discord.len(), 1);
+        assert_eq!(config.discord[0].client_id, 10);
+        assert_eq!(config.discord[0].token, "from env");
+        assert_eq!(config.backlog_size, 72);
+        assert_eq!(&*config.admin_ids, [UserId(3)]);
+        assert_eq!(config.channels[&GuildId(1)].channels, [ChannelId(4)]);
+    }
+
+    #[tokio::test]
+    async fn detected_raids_are_listed() {
+        let data_dir = TempDir::new("raids");
+        let handler = data_dir.handler();
+        let guild = GuildId(1);
+        assert_eq!(
+            handler.raids_reply(guild, 10).await.unwrap(),
+            "No raids have been detected in this server."
+        );
+
+        handler
+            .with_joins(move |gj| {
+                let stat = gj.add(guild, 20)?;
+                gj.record_raid(guild, true, stat)
+            })
+            .await
+            .unwrap();
+        let reply = handler.raids_reply(guild, 10).await.unwrap();
+        assert!(reply.starts_with("Recent raids:\n<t:"), "{}", reply);
+        assert!(
+            reply.ends_with(": abnormal joins, peak of 20 joins/h (upper quartile 0.0)\n"),
+            "{}",
+            reply
+        );
+    }
+
+    #[test]
+    fn commands_ignore_case_and_extra_whitespace() {
+        let (cmd, args) = split_command("Stat");
+        assert_eq!((cmd.as_str(), args.count()), ("stat", 0));
+        let (cmd, args) = split_command("  invite ");
+        assert_eq!((cmd.as_str(), args.count()), ("invite", 0));
+        let (cmd, args) = split_command("ADM   save\n");
+        assert_eq!(cmd, "adm");
+        assert_eq!(args.collect::<Vec<_>>(), ["save"]);
+        let (cmd, args) = split_command("");
+        assert_eq!((cmd.as_str(), args.count()), ("", 0));
+    }
+
+    #[tokio::test]
+    async fn admin_subcommands_ignore_case_and_extra_whitespace() {
+        let data_dir = TempDir::new("admin-case");
+        let handler = data_dir.handler();
+        assert_eq!(
+            handler.handle_admin("  SAVE  ", None, None).await.unwrap(),
+            "Saved the join data of all servers"
+        );
+    }
+
+    #[test]
+    fn commands_start_with_a_mention_or_the_prefix() {
+        let data_dir = TempDir::new("prefix");
+        let handler = HandlerBuilder::new()
+            .data_dir(&data_dir)
+            .command_prefix("!")
+            .build()
+            .unwrap();
+        let bot = Bot::new(0, 10, Arc::new(handler));
+
+        assert_eq!(bot.strip_prefix("<@10> stat", None), Some("stat"));
+        assert_eq!(bot.strip_prefix("<@!10>\nstat", None), Some("stat"));
+        assert_eq!(bot.strip_prefix("<@10>", None), Some(""));
+        assert_eq!(bot.strip_prefix("!stat", None), Some("stat"));
+        // other bots and plain messages
+        assert_eq!(bot.strip_prefix("<@100> stat", None), None);
+        assert_eq!(bot.strip_prefix("<@10>stat", None), None);
+        assert_eq!(bot.strip_prefix("stat", None), None);
+
+        let handler = data_dir.handler();
+        let bot = Bot::new(0, 10, Arc::new(handler));
+        assert_eq!(bot.strip_prefix("!stat", None), None);
+        assert_eq!(bot.strip_prefix("<@10> stat", None), Some("stat"));
+    }
+
+    #[tokio::test]
+    async fn global_stats_sum_all_guilds() {
+        let data_dir = TempDir::new("global");
+        let handler = data_dir.handler();
+        let no_names = |_| None;
+        assert_eq!(
+            handler.global_reply(no_names).await.unwrap(),
+            "There were 0 joins in the past hour across 0 servers.\n0 servers are being raided.\n"
+        );
+
+        handler
+            .with_joins(|gj| {
+                gj.add(GuildId(1), 5)?;
+                gj.add(GuildId(2), 12)?;
+                gj.add(GuildId(3), 1)
+            })
+            .await
+            .unwrap();
+        handler.raid_states.update(GuildId(2), true);
+        let reply = handler
+            .global_reply(|guild| (guild == GuildId(2)).then(|| "Busy".to_owned()))
+            .await
+            .unwrap();
+        assert_eq!(
+            reply,
+            "There were 18 joins in the past hour across 3 servers.\n\
+             1 servers are being raided.\n\
+             The busiest server is Busy with 12 joins."
+        );
+    }
+
+    #[test]
+    fn alerts_mention_only_when_enabled() {
+        let target = |json| serde_json::from_str::<AlertTarget>(json).unwrap();
+
+        let plain = target("4");
+        assert_eq!(plain.mention(Severity::Elevated), "@here ");
+        assert_eq!(plain.mention(Severity::Critical), "@here ");
+
+        let silent = target(r#"{"channels": [4], "ping_on_alert": false}"#);
+        assert_eq!(silent.mention(Severity::Elevated), "");
+        assert_eq!(silent.mention(Severity::Critical), "");
+
+        let critical = target(r#"{"channels": [4], "ping_severity": "critical", "role": 7}"#);
+        assert_eq!(critical.mention(Severity::Elevated), "");
+        assert_eq!(critical.mention(Severity::Critical), "<@&7> ");
+    }
+
+    #[tokio::test]
+    async fn windows_beyond_the_backlog_are_refused() {
+        let data_dir = TempDir::new("window");
+        let handler = data_dir.handler();
+        let days = DEFAULT_BACKLOG_SIZE / 24 + 1;
+        assert_eq!(
+            handler
+                .window_reply(GuildId(1), days * 24, (days - 1) * 24)
+                .await
+                .unwrap(),
+            format!(
+                "No data that far back, only the past {} hours are kept.",
+                DEFAULT_BACKLOG_SIZE
+            )
+        );
+        let reply = handler.window_reply(GuildId(1), 24, 0).await.unwrap();
+        assert!(
+            reply.contains("0 joins over 0 hours with data"),
+            "{}",
+            reply
+        );
+    }
+
+    #[tokio::test]
+    async fn disabled_guilds_are_not_monitored() {
+        let data_dir = TempDir::new("disabled");
+        let handler = data_dir.handler();
+        let bot = Bot::new(0, 10, Arc::new(handler));
+        let guild = GuildId(1);
+        assert!(bot.monitors(guild));
+
+        assert_eq!(
+            bot.handle_admin("disable 1", None, None).await.unwrap(),
+            "Monitoring disabled in server 1"
+        );
+        assert!(!bot.monitors(guild));
+        assert!(!data_dir.join("1.json").exists());
+        bot.with_joins(|gj| gj.save()).await.unwrap();
+        assert!(!data_dir.join("1.json").exists());
+
+        bot.handle_admin("enable 1", None, None).await.unwrap();
+        assert!(bot.monitors(guild));
+    }
+
+    #[tokio::test]
+    async fn guild_commands_are_refused_in_disabled_guilds() {
+        let data_dir = TempDir::new("disabledcommands");
+        let handler = data_dir.handler();
+        let bot = Bot::new(0, 10, Arc::new(handler));
+        let guild = GuildId(1);
+        bot.handle_admin("disable 1", None, None).await.unwrap();
+
+        for cmd in ["raids", "serverinfo", "rank", "whois-recent"] {
+            assert!(bot.is_disabled_for(cmd, Some(guild)), "{}", cmd);
+            assert!(!bot.is_disabled_for(cmd, Some(GuildId(2))), "{}", cmd);
+            assert!(!bot.is_disabled_for(cmd, None), "{}", cmd);
+        }
+        assert!(!bot.is_disabled_for("help", Some(guild)));
+    }
+
+    #[test]
+    fn reload_picks_up_changed_thresholds() {
+        let data_dir = TempDir::new("reload");
+        let handler = HandlerBuilder::new()
+            .bot(10, "token")
+            .data_dir(&data_dir)
+            .build()
+            .unwrap();
+        assert_eq!(handler.settings().threshold.multiplier, 2.);
+        let path = data_dir.join("config.toml");
+        fs::write(
+            &path,
+            r#"
+                admin_ids = [3]
+
+                [discord]
+                client_id = 10
+                token = "token"
+
+                [channels]
+                1 = 4
+
+                [threshold]
+                multiplier = 3.0
+
+                [thresholds.1]
+                multiplier = 5.0
+            "#,
+        )
+        .unwrap();
+
+        let reply = {
+            let _env = CONFIG_ENV.lock().unwrap();
+            std::env::set_var("CONFIG_PATH", &path);
+            let reply = handler.reload();
+            std::env::remove_var("CONFIG_PATH");
+            reply
+        };
+        assert_eq!(
+            reply.unwrap(),
+            "Reloaded admins, alert channels and thresholds from the config"
+        );
+        let settings = handler.settings();
+        assert_eq!(settings.threshold.multiplier, 3.);
+        assert_eq!(settings.thresholds[&GuildId(1)].multiplier, 5.);
+        assert_eq!(settings.channels[&GuildId(1)].channels, [ChannelId(4)]);
+        assert!(handler.is_admin(UserId(3)));
+        assert_eq!(handler.fallback_admin(), Some(UserId(3)));
+    }
+
+    #[tokio::test]
+    async fn test_alerts_report_each_channel() {
+        let data_dir = TempDir::new("testalert");
+        let guild = GuildId(1);
+        let handler = HandlerBuilder::new()
+            .data_dir(&data_dir)
+            .channels(guild, vec![ChannelId(4), ChannelId(5)])
+            .build()
+            .unwrap();
+        let sent = Mutex::new(Vec::new());
+        let reply = handler
+            .test_alert(guild, |channel| {
+                sent.lock().unwrap().push(channel);
+                async move {
+                    match channel {
+                        ChannelId(4) => Ok(()),
+                        _ => Err(BotError::Io(io::ErrorKind::PermissionDenied.into())),
+                    }
+                }
+            })
+            .await;
+        assert_eq!(*sent.lock().unwrap(), [ChannelId(4), ChannelId(5)]);
+        assert_eq!(
+            reply,
+            "Test alert results:\n<#4>: sent\n<#5>: failed: IO error: permission denied\n"
+        );
+        assert!(TEST_ALERT.starts_with("TEST ALERT"));
+
+        let reply = handler.test_alert(GuildId(2), |_| async { Ok(()) }).await;
+        assert_eq!(reply, "No alert channels are configured for this server.");
+    }
+
+    #[test]
+    fn bad_alert_templates_fail_validation() {
+        let mut config = HandlerBuilder::new().bot(10, "token").config;
+        assert!(config.validate().is_ok());
+        config.alert_template = "{mention}{joins}".into();
+        let err = config.validate().err().unwrap();
+        assert!(err
+            .to_string()
+            .starts_with("config: alert_template: unknown placeholder `{joins}`"));
+    }
+
+    #[test]
+    fn invite_links_request_the_configured_permissions() {
+        let data_dir = TempDir::new("invite");
+        let handler = data_dir.handler();
+        let bot = Bot::new(0, 10, Arc::new(handler));
+        // view channels, send messages, embed links and attach files
+        assert_eq!(
+            bot.invite_reply(),
+            "Invite link: https://discord.com/oauth2/authorize?client_id=10\
+             &scope=bot%20applications.commands&permissions=52224"
+        );
+
+        let mut builder = HandlerBuilder::new().data_dir(&data_dir);
+        builder.config.invite = InviteConfig {
+            scopes: vec!["bot".into()],
+            permissions: Some(8),
+        };
+        let bot = Bot::new(0, 10, Arc::new(builder.build().unwrap()));
+        assert!(bot
+            .invite_link
+            .ends_with("?client_id=10&scope=bot&permissions=8"));
+    }
+
+    #[test]
+    fn config_files_and_the_builder_build_alike() {
+        let data_dir = TempDir::new("try-from");
+        let config: Config = config::Config::builder()
+            .add_source(config::File::from_str(
+                &format!(
+                    r#"
+                        admin_ids = [3]
+                        data_dir = "{}"
+
+                        [discord]
+                        client_id = 10
+                        token = "token"
+
+                        [channels]
+                        1 = 4
+
+                        [thresholds.1]
+                        multiplier = 3.0
+                    "#,
+                    data_dir.display()
+                ),
+                config::FileFormat::Toml,
+            ))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+        let from_config = Handler::try_from(config).unwrap();
+        let from_builder = HandlerBuilder::new()
+            .bot(10, "token")
+            .admin_ids([UserId(3)])
+            .channels(GuildId(1), vec![ChannelId(4)])
+            .data_dir(&data_dir)
+            .guild_threshold(
+                GuildId(1),
+                ThresholdConfig {
+                    multiplier: 3.,
+                    ..ThresholdConfig::default()
+                },
+            )
+            .build()
+            .unwrap();
+
+        for handler in [&from_config, &from_builder] {
+            assert_eq!(handler.discord, [(10, "token".to_owned())]);
+            assert!(handler.is_admin(UserId(3)));
+            let settings = handler.settings();
+            assert_eq!(settings.channels[&GuildId(1)].channels, [ChannelId(4)]);
+            assert_eq!(settings.thresholds[&GuildId(1)].multiplier, 3.);
+            assert_eq!(settings.threshold.multiplier, 2.);
+        }
+    }
+
+    #[test]
+    fn admin_commands_are_audited() {
+        let dir = TempDir::new("audit");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.jsonl");
+        let mut builder = HandlerBuilder::new()
+            .bot(10, "token")
+            .admin_ids([UserId(3)])
+            .data_dir(&dir);
+        builder.config.audit_log = Some(path.clone());
+        let handler = builder.build().unwrap();
+
+        handler.audit(Some(UserId(3)), Some(GuildId(1)), "save", true);
+        handler.audit(Some(UserId(4)), Some(GuildId(1)), "stop", false);
+
+        let entries: Vec<serde_json::Value> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(entries.len(), 2);
+        for (entry, (user, command, allowed)) in entries
+            .iter()
+            .zip([("3", "save", true), ("4", "stop", false)])
+        {
+            assert_eq!(entry["event"], "admin_command");
+            assert_eq!(entry["user_id"], user);
+            assert_eq!(entry["guild_id"], "1");
+            assert_eq!(entry["command"], command);
+            assert_eq!(entry["allowed"], allowed);
+            assert!(entry["timestamp"].as_u64().unwrap() > 0);
+        }
+    }
+
+    #[test]
+    fn only_admins_query_other_guilds() {
+        let data_dir = TempDir::new("stat-target");
+        let handler = HandlerBuilder::new()
+            .bot(10, "token")
+            .admin_ids([UserId(3)])
+            .data_dir(&data_dir)
+            .build()
+            .unwrap();
+        let target = |content: &'static str, author| {
+            let mut args = content.split_whitespace().peekable();
+            let target = handler.stat_target(&mut args, UserId(author), Some(GuildId(1)));
+            (target, args.next())
+        };
+
+        assert_eq!(
+            target("123 json", 3),
+            (Ok(Some(GuildId(123))), Some("json"))
+        );
+        assert_eq!(target("json", 3), (Ok(Some(GuildId(1))), Some("json")));
+        assert_eq!(target("json", 4), (Ok(Some(GuildId(1))), Some("json")));
+        assert!(target("123 json", 4).0.is_err());
+    }
+
+    #[test]
+    fn bot_exclusion_follows_the_config() {
+        let mut human = User::default();
+        human.bot = false;
+        let unverified = User::default();
+        let mut verified = User::default();
+        verified.public_flags = Some(UserPublicFlags::VERIFIED_BOT);
+        let excluded = |exclusion: BotExclusion| {
+            [&human, &unverified, &verified].map(|user| exclusion.excludes(user))
+        };
+        assert_eq!(excluded(BotExclusion::None), [false, false, false]);
+        assert_eq!(excluded(BotExclusion::Verified), [false, false, true]);
+        assert_eq!(excluded(BotExclusion::All), [false, true, true]);
+    }
+
+    #[test]
+    fn durations_show_their_two_largest_units() {
+        let format = |secs| format_duration(Duration::from_secs(secs));
+        assert_eq!(format(0), "0s");
+        assert_eq!(format(59), "59s");
+        assert_eq!(format(5 * 60 + 12), "5m 12s");
+        assert_eq!(format(3600), "1h 0m");
+        assert_eq!(format(3 * 86400 + 4 * 3600 + 59 * 60), "3d 4h");
+    }
+
+    #[test]
+    fn intents_follow_the_config() {
+        let default = GatewayIntents::non_privileged()
+            | GatewayIntents::MESSAGE_CONTENT
+            | GatewayIntents::GUILD_MEMBERS;
+        assert_eq!(IntentsConfig::default().to_intents(), Ok(default));
+
+        let slash_only = IntentsConfig {
+            commands_via_messages: false,
+            extra: vec!["guild_presences".into()],
+            ..IntentsConfig::default()
+        };
+        assert_eq!(
+            slash_only.to_intents(),
+            Ok(GatewayIntents::non_privileged()
+                | GatewayIntents::GUILD_MEMBERS
+                | GatewayIntents::GUILD_PRESENCES)
+        );
+
+        let unknown = IntentsConfig {
+            extra: vec!["EVERYTHING".into()],
+            ..IntentsConfig::default()
+        };
+        assert!(unknown.to_intents().is_err());
+
+        let mut config = HandlerBuilder::new().bot(10, "token").config;
+        assert!(config.validate().is_ok());
+        config.intents.track_members = false;
+        assert!(config.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn guild_listing_shows_the_status_of_each_guild() {
+        let data_dir = TempDir::new("guilds");
+        let handler = HandlerBuilder::new()
+            .data_dir(&data_dir)
+            .channels(GuildId(1), vec![ChannelId(4)])
+            .channels(GuildId(2), vec![ChannelId(5)])
+            .build()
+            .unwrap();
+        let no_names = |_| None;
+        assert_eq!(
+            handler.guilds_reply(no_names).await.unwrap(),
+            "No servers are loaded."
+        );
+
+        handler
+            .with_joins(|gj| {
+                gj.add(GuildId(1), 5)?;
+                gj.add(GuildId(2), 12)?;
+                gj.add(GuildId(3), 5)
+            })
+            .await
+            .unwrap();
+        handler.raid_states.update(GuildId(2), true);
+        handler.ignored.set_enabled(GuildId(1), false).unwrap();
+        let reply = handler
+            .guilds_reply(|guild| (guild == GuildId(2)).then(|| "Busy".to_owned()))
+            .await
+            .unwrap();
+        assert_eq!(
+            reply,
+            "3 loaded servers:\n\
+             `2` Busy: 12 joins this hour, abnormal\n\
+             `1` unknown: 5 joins this hour, monitoring disabled\n\
+             `3` unknown: 5 joins this hour, no alert channel\n"
+        );
+    }
+
+    #[test]
+    fn adversarial_mentions_do_not_panic() {
+        let data_dir = TempDir::new("adversarial");
+        let handler = data_dir.handler();
+        let bot = Bot::new(0, 10, Arc::new(handler));
+
+        assert_eq!(bot.strip_prefix("", None), None);
+        assert_eq!(bot.strip_prefix("<@", None), None);
+        assert_eq!(bot.strip_prefix("<@10", None), None);
+        assert_eq!(bot.strip_prefix("> <@10> stat", None), None);
+        assert_eq!(bot.strip_prefix("stat <@10> ", None), None);
+        assert_eq!(bot.strip_prefix("<@10>> stat", None), None);
+        assert_eq!(bot.strip_prefix("<@10>é", None), None);
+        assert_eq!(bot.strip_prefix("<@10> ", None), Some(""));
+        assert_eq!(bot.strip_prefix("<@10>\u{a0}stat", None), Some("stat"));
+        assert_eq!(
+            bot.strip_prefix("<@!10>  <@10> stat", None),
+            Some("<@10> stat")
+        );
+    }
+
+    #[test]
+    fn mitigation_takes_the_configured_action() {
+        let data_dir = TempDir::new("mitigation");
+        let handler_with = |action| {
+            let mut builder = HandlerBuilder::new().bot(10, "token").data_dir(&data_dir);
+            builder.config.auto_kick = AutoKickConfig {
+                enabled: true,
+                action,
+                timeout_minutes: 30,
+                soft_ban_delete_days: 2,
+                ..AutoKickConfig::default()
+            };
+            builder.build().unwrap()
+        };
+        let now = 1_700_000_000;
+
+        let handler = handler_with(MitigationAction::Timeout);
+        assert_eq!(
+            handler.mitigation(Severity::Elevated, 1, now),
+            Some(Mitigation::Timeout {
+                until: Timestamp::from_unix_timestamp(now + 30 * 60).unwrap()
+            })
+        );
+        // old accounts and mild raids are left alone
+        assert_eq!(handler.mitigation(Severity::Elevated, 24, now), None);
+        assert_eq!(handler.mitigation(Severity::Normal, 1, now), None);
+
+        let handler = handler_with(MitigationAction::SoftBan);
+        assert_eq!(
+            handler.mitigation(Severity::Critical, 0, now),
+            Some(Mitigation::SoftBan { delete_days: 2 })
+        );
+        let handler = handler_with(MitigationAction::Kick);
+        assert_eq!(
+            handler.mitigation(Severity::Critical, 0, now),
+            Some(Mitigation::Kick)
+        );
+        let handler = handler_with(MitigationAction::None);
+        assert_eq!(handler.mitigation(Severity::Critical, 0, now), None);
+
+        let handler = HandlerBuilder::new()
+            .bot(10, "token")
+            .data_dir(&data_dir)
+            .build()
+            .unwrap();
+        assert_eq!(handler.mitigation(Severity::Critical, 0, now), None);
+    }
+
+    #[tokio::test]
+    async fn slash_reset_waits_for_confirmation() {
+        let data_dir = TempDir::new("slash-reset");
+        let handler = data_dir.handler();
+        let guild = GuildId(1);
+        let (user, other) = (UserId(3), UserId(4));
+        handler
+            .with_joins(move |gj| gj.add(guild, 5))
+            .await
+            .unwrap();
+        let current = || handler.with_joins(move |gj| gj.add(guild, 0));
+
+        let reply = handler.slash_reset(guild, user, false, true).await.unwrap();
+        assert!(reply.contains("permission"));
+        let reply = handler.slash_reset(guild, user, true, false).await.unwrap();
+        assert!(reply.contains("confirm"));
+        assert_eq!(current().await.unwrap().current, 5);
+        // only the user who ran it can confirm it
+        let reply = handler.slash_reset(guild, other, true, true).await.unwrap();
+        assert!(reply.starts_with("Nothing to confirm"));
+        assert_eq!(current().await.unwrap().current, 5);
+
+        let reply = handler.slash_reset(guild, user, true, true).await.unwrap();
+        assert_eq!(reply, "Join history has been reset.");
+        assert_eq!(current().await.unwrap().current, 0);
+    }
+}
@@ -0,0 +1,107 @@
+//! Liveness and readiness probes, served over HTTP.
+//!
+//! - `GET /healthz` responds 200 as long as the process is running.
+//! - `GET /readyz` responds 200 once the bot has connected to Discord
+//!   and is in at least one guild, and 503 before that.
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Serves the probes on `addr` until `shutdown` completes.
+///
+/// `ready` is set by the event handler when the gateway is ready.
+pub async fn serve(
+    addr: SocketAddr,
+    ready: Arc<AtomicBool>,
+    shutdown: impl Future<Output = ()>,
+) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    log::info!("Serving health checks on {}", addr);
+
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, _) = accepted?;
+                let ready = ready.load(Ordering::Relaxed);
+                tokio::spawn(async move {
+                    if let Err(err) = respond(stream, ready).await {
+                        log::debug!("Error serving health check: {}", err);
+                    }
+                });
+            }
+            () = &mut shutdown => {
+                log::info!("Health check server stopped");
+                return Ok(());
+            }
+        }
+    }
+}
+
+async fn respond(mut stream: TcpStream, ready: bool) -> std::io::Result<()> {
+    let mut buf = [0; 1024];
+    let n = stream.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..n]);
+    let mut parts = request.split_whitespace();
+
+    let status = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/healthz")) => "200 OK",
+        (Some("GET"), Some("/readyz")) if ready => "200 OK",
+        (Some("GET"), Some("/readyz")) => "503 Service Unavailable",
+        _ => "404 Not Found",
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        status
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn get(addr: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response.lines().next().unwrap().to_owned()
+    }
+
+    #[tokio::test]
+    async fn readiness_follows_the_flag() {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let ready = Arc::new(AtomicBool::new(false));
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve(addr, Arc::clone(&ready), async {
+            let _ = stopped.await;
+        }));
+        // wait for the server to bind
+        while TcpStream::connect(addr).await.is_err() {
+            tokio::task::yield_now().await;
+        }
+
+        assert_eq!(get(addr, "/healthz").await, "HTTP/1.1 200 OK");
+        assert_eq!(
+            get(addr, "/readyz").await,
+            "HTTP/1.1 503 Service Unavailable"
+        );
+        ready.store(true, Ordering::Relaxed);
+        assert_eq!(get(addr, "/readyz").await, "HTTP/1.1 200 OK");
+        assert_eq!(get(addr, "/metrics").await, "HTTP/1.1 404 Not Found");
+
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
+}
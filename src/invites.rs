@@ -0,0 +1,31 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use serenity::model::id::GuildId;
+
+/// Remembers the use counts of the invites of each guild
+/// to find out which invite new members joined with.
+#[derive(Default)]
+pub struct InviteUses {
+    uses: Mutex<HashMap<GuildId, HashMap<String, u64>>>,
+}
+
+impl InviteUses {
+    /// Replaces the known use counts of the guild's invites,
+    /// returning the invite whose uses increased the most since the previous update.
+    ///
+    /// Returns `None` on the first update of a guild or if no uses increased,
+    /// e.g. because the member joined through the vanity URL.
+    pub fn update(&self, guild: GuildId, invites: HashMap<String, u64>) -> Option<String> {
+        let previous = self.uses.lock().unwrap().insert(guild, invites.clone())?;
+        invites
+            .into_iter()
+            .map(|(code, uses)| {
+                let increase = uses.saturating_sub(previous.get(&code).copied().unwrap_or(0));
+                (code, increase)
+            })
+            .filter(|&(_, increase)| increase > 0)
+            .max_by_key(|&(_, increase)| increase)
+            .map(|(code, _)| code)
+    }
+}
@@ -0,0 +1,93 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use serenity::model::id::{GuildId, UserId};
+
+/// Number of joins kept per guild.
+const JOINER_LOG_SIZE: usize = 100;
+
+/// A member join kept for moderators to review.
+#[derive(Clone, Copy)]
+pub struct Joiner {
+    pub user: UserId,
+    /// Unix time of the join.
+    pub joined_at: u64,
+    /// Unix time the account was created.
+    pub created_at: i64,
+}
+
+/// The latest `JOINER_LOG_SIZE` joins of each guild, kept in memory only.
+#[derive(Default)]
+pub struct JoinerLog {
+    guilds: Mutex<HashMap<GuildId, VecDeque<Joiner>>>,
+}
+
+impl JoinerLog {
+    /// Records a join, evicting the oldest one if the log of the guild is full.
+    pub fn record(&self, guild: GuildId, joiner: Joiner) {
+        let mut guilds = self.guilds.lock().unwrap();
+        let joiners = guilds.entry(guild).or_default();
+        if joiners.len() >= JOINER_LOG_SIZE {
+            joiners.pop_front();
+        }
+        joiners.push_back(joiner);
+    }
+
+    /// Returns up to `count` of the latest joins of the guild, newest first,
+    /// and the number of joins recorded.
+    pub fn latest(&self, guild: GuildId, count: usize) -> (Vec<Joiner>, usize) {
+        let guilds = self.guilds.lock().unwrap();
+        match guilds.get(&guild) {
+            Some(joiners) => (
+                joiners.iter().rev().take(count).copied().collect(),
+                joiners.len(),
+            ),
+            None => (Vec::new(), 0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GUILD: GuildId = GuildId(1);
+
+    fn joiner(user: u64) -> Joiner {
+        Joiner {
+            user: UserId(user),
+            joined_at: 1_700_000_000 + user,
+            created_at: 1_600_000_000,
+        }
+    }
+
+    fn users(joiners: &[Joiner]) -> Vec<u64> {
+        joiners.iter().map(|joiner| joiner.user.0).collect()
+    }
+
+    #[test]
+    fn latest_joiners_come_first() {
+        let log = JoinerLog::default();
+        for user in 1..=5 {
+            log.record(GUILD, joiner(user));
+        }
+        let (latest, total) = log.latest(GUILD, 3);
+        assert_eq!((users(&latest), total), (vec![5, 4, 3], 5));
+        assert_eq!(log.latest(GuildId(2), 3).1, 0);
+    }
+
+    #[test]
+    fn full_logs_evict_the_oldest_joiners() {
+        let log = JoinerLog::default();
+        for user in 1..=JOINER_LOG_SIZE as u64 + 2 {
+            log.record(GUILD, joiner(user));
+        }
+        let (latest, total) = log.latest(GUILD, usize::MAX);
+        assert_eq!(total, JOINER_LOG_SIZE);
+        assert_eq!(
+            latest.first().unwrap().user,
+            UserId(JOINER_LOG_SIZE as u64 + 2)
+        );
+        assert_eq!(latest.last().unwrap().user, UserId(3));
+    }
+}
@@ -1,231 +1,3577 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::{hash_map, HashMap, VecDeque};
 use std::fmt;
-use std::fs;
 use std::io;
-use std::path::PathBuf;
-use std::sync::Mutex;
-
-use std::time::UNIX_EPOCH;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use crossbeam::sync::ShardedLock;
 use serde::{Deserialize, Serialize};
+use serenity::builder::CreateEmbed;
 use serenity::model::id;
 
-const BACKLOG_SIZE: usize = 720;
+use crate::clock::Clock;
+use crate::store::Store;
+
+pub const DEFAULT_BACKLOG_SIZE: usize = 720;
+/// The largest supported `backlog_size`, ten years of hours,
+/// which also bounds the logs read from stored data.
+pub const MAX_BACKLOG_SIZE: usize = 10 * 366 * 24;
+/// Number of minutes of per-minute join counts kept.
+const MINUTE_BACKLOG_SIZE: usize = 120;
+/// Number of most recent minutes summed into `Stat::recent_minutes`.
+const RECENT_MINUTES: usize = 5;
+/// Version of the `GuildJoins` format written by this build.
+///
+/// - 0: files written before versioning, possibly without leaves, EWMA or runs
+/// - 1: the current format
+const CURRENT_VERSION: u32 = 1;
+/// Number of raid events kept per guild.
+const MAX_RAID_EVENTS: usize = 50;
+/// Number of minutes within which a join spike and a leave spike are correlated.
+const NUKE_WINDOW_MINUTES: usize = 30;
+/// Number of consecutive failed saves after which saving is considered broken.
+const DEGRADED_AFTER_FAILURES: u32 = 3;
+/// Number of interquartile ranges above the upper quartile of the fleet beyond which a guild is an outlier.
+const FLEET_OUTLIER_IQRS: f64 = 1.5;
+/// Guilds with fewer joins in the current hour are never fleet outliers,
+/// so that a few joins in a quiet fleet are not flagged.
+const FLEET_OUTLIER_MIN_JOINS: u32 = 10;
 
+/// Join data of all guilds, loaded from the store on first access.
+///
+/// Methods may block on file IO, so async callers should run them with `tokio::task::spawn_blocking`.
+/// Guilds are locked individually, so operations on different guilds do not block each other
+/// except when a guild is first loaded.
+/// Guilds are saved from snapshots after releasing their lock,
+/// so saving a guild does not block other operations on it.
+///
+/// If `capacity` is set, the least recently used guild is saved and unloaded
+/// when loading a guild would exceed it.
+///
+/// Guilds are saved eagerly when an hour completes, but at most once per `save_interval`;
+/// other changes are left to the periodic `autosave`.
 pub struct GuildJoinsMap {
-    lock: ShardedLock<HashMap<id::GuildId, Mutex<GuildJoins>>>,
-    data_dir: PathBuf,
+    lock: ShardedLock<HashMap<id::GuildId, Arc<Entry>>>,
+    store: Box<dyn Store>,
+    clock: Box<dyn Clock>,
+    backlog_size: usize,
+    ewma_alpha: f64,
+    percentile_method: PercentileMethod,
+    capacity: Option<usize>,
+    save_interval: Duration,
+    /// The unix hour this process started observing joins.
+    running_since: u64,
+    /// Incremented on every access to order entries by recency.
+    tick: AtomicU64,
+    /// Number of saves that failed since the last successful save.
+    save_failures: AtomicU32,
+    /// Whether saving has failed `DEGRADED_AFTER_FAILURES` times in a row,
+    /// so that joins are only counted in memory.
+    degraded: AtomicBool,
+    /// Whether `degraded` changed since `take_degraded_change` last returned.
+    degraded_changed: AtomicBool,
+    /// Held while backups are created, pruned or restored,
+    /// which is done without holding `lock`.
+    backups: Mutex<()>,
+}
+
+struct Entry {
+    joins: Mutex<GuildJoins>,
+    /// The `tick` of the last access to this guild.
+    last_used: AtomicU64,
+    /// The `generation` of the latest snapshot written to the store,
+    /// locked while writing so that snapshots are written in order.
+    written: Mutex<u64>,
+}
+
+/// Copies the guild data to save after releasing its lock,
+/// marking it as saved.
+fn snapshot(gj: &mut GuildJoins) -> GuildJoins {
+    gj.generation += 1;
+    gj.dirty = false;
+    gj.rolled_over = false;
+    gj.last_saved = Some(Instant::now());
+    gj.clone()
 }
 
 impl GuildJoinsMap {
-    pub fn new(data_dir: PathBuf) -> Self {
+    pub fn new(
+        store: Box<dyn Store>,
+        clock: Box<dyn Clock>,
+        backlog_size: usize,
+        ewma_alpha: f64,
+        percentile_method: PercentileMethod,
+        capacity: Option<usize>,
+        save_interval: Duration,
+    ) -> Self {
         Self {
             lock: ShardedLock::default(),
-            data_dir,
+            store,
+            running_since: clock.now_hour(),
+            clock,
+            backlog_size,
+            ewma_alpha,
+            percentile_method,
+            capacity,
+            save_interval,
+            tick: AtomicU64::new(0),
+            save_failures: AtomicU32::new(0),
+            degraded: AtomicBool::new(false),
+            degraded_changed: AtomicBool::new(false),
+            backups: Mutex::new(()),
+        }
+    }
+
+    /// Returns whether saving is currently broken, if that changed since the last call.
+    pub fn take_degraded_change(&self) -> Option<bool> {
+        match self.degraded_changed.swap(false, Ordering::SeqCst) {
+            true => Some(self.degraded.load(Ordering::SeqCst)),
+            false => None,
+        }
+    }
+
+    /// Tracks consecutive save failures to warn once when saving stops working
+    /// and once when it recovers.
+    fn record_save(&self, result: &io::Result<()>) {
+        match result {
+            Ok(()) => {
+                self.save_failures.store(0, Ordering::SeqCst);
+                if self.degraded.swap(false, Ordering::SeqCst) {
+                    self.degraded_changed.store(true, Ordering::SeqCst);
+                    log::warn!("Saving guild data works again, leaving degraded mode");
+                }
+            }
+            Err(err) => {
+                let failures = self.save_failures.fetch_add(1, Ordering::SeqCst) + 1;
+                if failures >= DEGRADED_AFTER_FAILURES
+                    && !self.degraded.swap(true, Ordering::SeqCst)
+                {
+                    self.degraded_changed.store(true, Ordering::SeqCst);
+                    log::error!(
+                        "DEGRADED: {} saves failed in a row, latest error: {}; joins are only counted in memory until saving works again",
+                        failures,
+                        err
+                    );
+                }
+            }
         }
     }
 
+    /// Takes a snapshot of every loaded guild, locking one guild at a time under the read lock.
+    fn snapshot_all(&self) -> Vec<(id::GuildId, Arc<Entry>, GuildJoins)> {
+        let read = self.lock.read().unwrap();
+        read.iter()
+            .map(|(&guild, entry)| {
+                let snapshot = snapshot(&mut entry.joins.lock().unwrap());
+                (guild, Arc::clone(entry), snapshot)
+            })
+            .collect()
+    }
+
     pub fn save(&self) -> io::Result<()> {
-        let write = self.lock.write().unwrap();
-        for gj in write.values() {
-            gj.lock().unwrap().save()?;
+        for (guild, entry, snapshot) in self.snapshot_all() {
+            self.write_snapshot(guild, &entry, snapshot)?;
+        }
+        Ok(())
+    }
+
+    /// Saves all guilds changed since their last save,
+    /// logging and skipping guilds that fail to save.
+    ///
+    /// Returns the number of guilds successfully saved.
+    pub fn autosave(&self) -> usize {
+        let entries: Vec<_> = {
+            let read = self.lock.read().unwrap();
+            read.iter()
+                .map(|(&guild, entry)| (guild, Arc::clone(entry)))
+                .collect()
+        };
+        let mut saved = 0;
+        for (guild, entry) in entries {
+            let snapshot = {
+                let mut gj = entry.joins.lock().unwrap();
+                if !gj.dirty || gj.evicted {
+                    continue;
+                }
+                snapshot(&mut gj)
+            };
+            match self.write_snapshot(guild, &entry, snapshot) {
+                Ok(()) => saved += 1,
+                Err(err) => log::error!("Error saving data of guild {}: {}", guild, err),
+            }
+        }
+        saved
+    }
+
+    /// Returns the joins in the current hour of every loaded guild.
+    ///
+    /// Guilds are locked one at a time under the read lock,
+    /// in the same order as other operations lock them.
+    pub fn current_joins(&self) -> Vec<(id::GuildId, u32)> {
+        let read = self.lock.read().unwrap();
+        read.iter()
+            .map(|(&guild, entry)| {
+                let mut gj = entry.joins.lock().unwrap();
+                gj.update_to_latest_hour(&*self.clock);
+                (guild, gj.current)
+            })
+            .collect()
+    }
+
+    /// Writes a snapshot taken by `snapshot` to the store,
+    /// unless a newer snapshot of the guild has already been written.
+    ///
+    /// The guild is not locked during the write, so it can be used meanwhile.
+    /// If the write fails, the guild is marked as changed again.
+    fn write_snapshot(
+        &self,
+        guild: id::GuildId,
+        entry: &Entry,
+        snapshot: GuildJoins,
+    ) -> io::Result<()> {
+        let mut written = entry.written.lock().unwrap();
+        if snapshot.generation <= *written {
+            return Ok(());
+        }
+        let result = self.store.save(guild, &snapshot);
+        self.record_save(&result);
+        match result {
+            Ok(()) => *written = snapshot.generation,
+            Err(_) => entry.joins.lock().unwrap().dirty = true,
+        }
+        result
+    }
+
+    /// Saves all guilds and backs up the store, deleting all but the `keep` newest backups.
+    ///
+    /// Returns the name of the new backup.
+    pub fn backup(&self, keep: usize) -> io::Result<String> {
+        // the snapshots are a consistent cut, written without blocking the guilds
+        let snapshots = self.snapshot_all();
+        let _backups = self.backups.lock().unwrap();
+        for (guild, entry, snapshot) in snapshots {
+            self.write_snapshot(guild, &entry, snapshot)?;
         }
+        let name = self.store.backup()?;
+        self.store.prune_backups(keep)?;
+        Ok(name)
+    }
+
+    /// Restores the store from the named backup.
+    ///
+    /// Data in memory is discarded without saving, so that guilds are reloaded from the backup.
+    /// Snapshots being written finish before the backup is restored,
+    /// and snapshots taken earlier but not written yet are discarded.
+    pub fn restore(&self, name: &str) -> io::Result<()> {
+        let _backups = self.backups.lock().unwrap();
+        let mut write = self.lock.write().unwrap();
+        {
+            let mut entries: Vec<_> = write
+                .values()
+                .map(|entry| (entry, entry.written.lock().unwrap()))
+                .collect();
+            self.store.restore(name)?;
+            for (entry, written) in &mut entries {
+                entry.joins.lock().unwrap().evicted = true;
+                **written = u64::MAX;
+            }
+        }
+        write.clear();
         Ok(())
     }
 
-    fn run<F, R>(&self, guild: id::GuildId, f: F) -> R
+    /// Saves all changed guilds and exits the process.
+    ///
+    /// The write lock is held until the process exits
+    /// and all guilds are marked as evicted,
+    /// so no event handler can record joins that would be lost after the save.
+    /// Concurrent calls, e.g. from multiple shards, block on the lock
+    /// so the data is only saved once.
+    pub fn save_and_exit(&self) -> ! {
+        let write = self.lock.write().unwrap();
+        let mut code = 0;
+        for (&guild, entry) in write.iter() {
+            let snapshot = {
+                let mut gj = entry.joins.lock().unwrap();
+                gj.evicted = true;
+                if !gj.dirty {
+                    continue;
+                }
+                snapshot(&mut gj)
+            };
+            if let Err(err) = self.write_snapshot(guild, entry, snapshot) {
+                log::error!("Error saving data of guild {}: {}", guild, err);
+                code = 1;
+            }
+        }
+        std::process::exit(code)
+    }
+
+    /// Runs `f` on the data of the guild, loading it first if necessary.
+    ///
+    /// The guild is only locked while `f` runs;
+    /// saving after an hour completes happens after releasing the lock.
+    /// Errors saving the guild are logged rather than returned.
+    fn run<F, R>(&self, guild: id::GuildId, f: F) -> io::Result<R>
     where
         F: FnOnce(&mut GuildJoins) -> R,
     {
+        let mut f = Some(f);
+        loop {
+            let entry = self.entry(guild)?;
+            let mut gj = entry.joins.lock().unwrap();
+            if gj.evicted {
+                // unloaded since `entry` returned it, so the changes would be lost
+                continue;
+            }
+            let f = f.take().expect("f is only taken before returning");
+            let ret = f(&mut gj);
+
+            let debounced = gj
+                .last_saved
+                .is_some_and(|last| last.elapsed() < self.save_interval);
+            let snapshot = match gj.rolled_over && !debounced {
+                true => Some(snapshot(&mut gj)),
+                false => None,
+            };
+            drop(gj);
+            if let Some(snapshot) = snapshot {
+                // the change is kept in memory and saved again later,
+                // so a failed save must not fail the operation
+                if let Err(err) = self.write_snapshot(guild, &entry, snapshot) {
+                    log::error!("Error saving data of guild {}: {}", guild, err);
+                }
+            }
+            return Ok(ret);
+        }
+    }
+
+    /// Returns the entry of the guild, loading it if it is not loaded yet.
+    ///
+    /// Loading is the only file IO done while holding the write lock of the map.
+    fn entry(&self, guild: id::GuildId) -> io::Result<Arc<Entry>> {
+        let tick = self.tick.fetch_add(1, Ordering::Relaxed);
         {
             let read = self.lock.read().unwrap();
-            if let Some(gj) = read.get(&guild) {
-                let mut lock = gj.lock().unwrap();
-                return f(&mut lock);
+            if let Some(entry) = read.get(&guild) {
+                entry.last_used.store(tick, Ordering::Relaxed);
+                return Ok(Arc::clone(entry));
             }
         }
 
-        {
-            let path = self.data_dir.join(&format!("{}.json", guild));
-            let mut write = self.lock.write().unwrap();
-            let gj = write
-                .entry(guild)
-                .or_insert_with(|| Mutex::new(GuildJoins::read_or_new(path)));
-            let lock = gj.get_mut().unwrap();
-            f(lock)
+        let mut write = self.lock.write().unwrap();
+        if !write.contains_key(&guild) {
+            if let Some(capacity) = self.capacity {
+                while write.len() >= capacity.max(1) && self.evict(&mut write) {}
+            }
+        }
+        let entry = match write.entry(guild) {
+            hash_map::Entry::Occupied(entry) => entry.into_mut(),
+            hash_map::Entry::Vacant(entry) => entry.insert(Arc::new(Entry {
+                joins: Mutex::new(self.load(guild)?),
+                last_used: AtomicU64::new(tick),
+                written: Mutex::new(0),
+            })),
+        };
+        Ok(Arc::clone(entry))
+    }
+
+    /// Saves and unloads the least recently used guild.
+    ///
+    /// Returns false if there is no guild to evict or it cannot be saved,
+    /// in which case it stays loaded so that no joins are lost.
+    fn evict(&self, map: &mut HashMap<id::GuildId, Arc<Entry>>) -> bool {
+        let lru = map
+            .iter()
+            .min_by_key(|(_, entry)| entry.last_used.load(Ordering::Relaxed))
+            .map(|(&guild, entry)| (guild, Arc::clone(entry)));
+        let (guild, entry) = match lru {
+            Some(lru) => lru,
+            None => return false,
+        };
+        let snapshot = {
+            let mut gj = entry.joins.lock().unwrap();
+            gj.evicted = true;
+            snapshot(&mut gj)
+        };
+        if let Err(err) = self.write_snapshot(guild, &entry, snapshot) {
+            log::error!("Error saving data of guild {} for eviction: {}", guild, err);
+            entry.joins.lock().unwrap().evicted = false;
+            return false;
+        }
+        map.remove(&guild);
+        true
+    }
+
+    /// Loads the stored data of the guild, or starts afresh if it has none.
+    ///
+    /// Corrupt data is moved aside for investigation before starting afresh.
+    /// Other errors are returned so that the stored data is not overwritten.
+    fn load(&self, guild: id::GuildId) -> io::Result<GuildJoins> {
+        match self.store.load(guild) {
+            Ok(gj) => {
+                return Ok(gj.restore(
+                    self.backlog_size,
+                    self.ewma_alpha,
+                    &*self.clock,
+                    self.running_since,
+                ));
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof
+                ) =>
+            {
+                match self.store.quarantine(guild) {
+                    Ok(moved_to) => log::error!(
+                        "Data of guild {} is corrupt, moved it to {} and starting afresh: {}",
+                        guild,
+                        moved_to,
+                        err
+                    ),
+                    Err(quarantine_err) => {
+                        log::error!(
+                            "Data of guild {} is corrupt and cannot be moved aside: {}",
+                            guild,
+                            quarantine_err
+                        );
+                        return Err(err);
+                    }
+                }
+            }
+            Err(err) => return Err(err),
+        }
+        Ok(GuildJoins::new(
+            self.backlog_size,
+            self.ewma_alpha,
+            &*self.clock,
+        ))
+    }
+
+    /// Unloads the guild without saving and deletes its stored data.
+    pub fn delete(&self, guild: id::GuildId) -> io::Result<()> {
+        let mut write = self.lock.write().unwrap();
+        if let Some(entry) = write.remove(&guild) {
+            let mut written = entry.written.lock().unwrap();
+            entry.joins.lock().unwrap().evicted = true;
+            // snapshots taken before the guild was unloaded must not recreate its data
+            *written = u64::MAX;
+        }
+        // the write lock keeps the guild from being loaded again while deleting
+        self.store.delete(guild)
+    }
+
+    /// Deletes the stored data of every unloaded guild without joins in the whole backlog,
+    /// except those for which `keep` returns true.
+    ///
+    /// Returns the deleted guilds.
+    pub fn prune_idle(&self, keep: impl Fn(id::GuildId) -> bool) -> io::Result<Vec<id::GuildId>> {
+        let mut pruned = Vec::new();
+        for guild in self.store.guilds()? {
+            if keep(guild) || self.lock.read().unwrap().contains_key(&guild) {
+                continue;
+            }
+            // unreadable data is left for `load` to quarantine
+            let idle = match self.store.load(guild) {
+                Ok(gj) => gj
+                    .restore(
+                        self.backlog_size,
+                        self.ewma_alpha,
+                        &*self.clock,
+                        self.running_since,
+                    )
+                    .is_idle(),
+                Err(err) => {
+                    log::warn!("Cannot check whether guild {} is idle: {}", guild, err);
+                    false
+                }
+            };
+            if !idle {
+                continue;
+            }
+            let write = self.lock.write().unwrap();
+            // loaded by a join while checking
+            if write.contains_key(&guild) {
+                continue;
+            }
+            self.store.delete(guild)?;
+            drop(write);
+            pruned.push(guild);
         }
+        Ok(pruned)
+    }
+
+    /// Loads the data of the guild into memory if it is not loaded yet.
+    pub fn prime(&self, guild: id::GuildId) -> io::Result<()> {
+        self.run(guild, |_| ())
+    }
+
+    pub fn reset(&self, guild: id::GuildId) -> io::Result<()> {
+        let clock = &*self.clock;
+        self.run(guild, |gj| {
+            let threshold = gj.threshold.take();
+            *gj = GuildJoins::new(gj.backlog_size, gj.ewma_alpha, clock);
+            gj.threshold = threshold;
+            gj.rolled_over = true;
+            gj.dirty = true;
+        })
+    }
+
+    /// Returns the thresholds set for the guild at runtime, if any.
+    pub fn threshold(&self, guild: id::GuildId) -> io::Result<Option<ThresholdConfig>> {
+        self.run(guild, |gj| gj.threshold.clone())
+    }
+
+    /// Overrides the configured thresholds of the guild.
+    pub fn set_threshold(&self, guild: id::GuildId, threshold: ThresholdConfig) -> io::Result<()> {
+        self.run(guild, |gj| {
+            gj.threshold = Some(threshold);
+            // saved eagerly since losing the change would be confusing
+            gj.rolled_over = true;
+            gj.dirty = true;
+        })
+    }
+
+    /// Estimates the joins of the unknown hours of the guild,
+    /// returning the number of samples seeded.
+    pub fn seed(&self, guild: id::GuildId, joins: u32) -> io::Result<usize> {
+        let clock = &*self.clock;
+        self.run(guild, |gj| {
+            gj.seed(clock, joins);
+            gj.baseline_log().1
+        })
+    }
+
+    /// Announces the joins per hour expected in the guild until the expectation ends,
+    /// or clears the expectation if `None`.
+    pub fn set_expectation(
+        &self,
+        guild: id::GuildId,
+        expectation: Option<Expectation>,
+    ) -> io::Result<()> {
+        self.run(guild, |gj| {
+            gj.expectation = expectation;
+            gj.rolled_over = true;
+            gj.dirty = true;
+        })
     }
 
     pub fn add(&self, guild: id::GuildId, delta: u32) -> io::Result<Stat> {
+        let clock = &*self.clock;
+        let percentile_method = self.percentile_method;
+        self.run(guild, move |gj| {
+            gj.add(clock, delta);
+            gj.stat(clock, percentile_method)
+        })
+    }
+
+    /// Records the join of a bot account, counting `delta` joins towards the statistics.
+    pub fn add_bot(&self, guild: id::GuildId, delta: u32) -> io::Result<Stat> {
+        let clock = &*self.clock;
+        let percentile_method = self.percentile_method;
+        self.run(guild, move |gj| {
+            gj.add(clock, delta);
+            gj.current_bots += 1;
+            gj.dirty = true;
+            gj.stat(clock, percentile_method)
+        })
+    }
+
+    pub fn add_leave(&self, guild: id::GuildId, delta: u32) -> io::Result<Stat> {
+        let clock = &*self.clock;
+        let percentile_method = self.percentile_method;
+        self.run(guild, move |gj| {
+            gj.add_leave(clock, delta);
+            gj.stat(clock, percentile_method)
+        })
+    }
+
+    /// Returns the joins in the current hour
+    /// and the fraction of recorded hours with fewer joins, if any hours are recorded.
+    pub fn rank(&self, guild: id::GuildId) -> io::Result<(u32, Option<f64>)> {
+        let clock = &*self.clock;
+        self.run(guild, move |gj| gj.rank(clock))
+    }
+
+    /// Records an abnormal join.
+    ///
+    /// If `started` is true, a new raid event is recorded,
+    /// otherwise the peak of the latest raid event is updated.
+    pub fn record_raid(&self, guild: id::GuildId, started: bool, stat: Stat) -> io::Result<()> {
+        let clock = &*self.clock;
+        self.run(guild, move |gj| gj.record_raid(clock, started, stat))
+    }
+
+    /// Returns the latest `count` raid events, oldest first.
+    pub fn raids(&self, guild: id::GuildId, count: usize) -> io::Result<Vec<RaidEvent>> {
+        self.run(guild, move |gj| {
+            let skip = gj.raids.len().saturating_sub(count);
+            gj.raids.iter().skip(skip).cloned().collect()
+        })
+    }
+
+    /// Records that the guild's recent joins were followed by mass leaves.
+    pub fn record_nuke(&self, guild: id::GuildId, stat: Stat) -> io::Result<()> {
+        let clock = &*self.clock;
+        self.run(guild, move |gj| gj.record_nuke(clock, stat))
+    }
+
+    /// Returns the completed hour of the guild with the most joins ever recorded.
+    pub fn peak(&self, guild: id::GuildId) -> io::Result<Option<Peak>> {
+        let clock = &*self.clock;
+        self.run(guild, move |gj| {
+            gj.update_to_latest_hour(clock);
+            gj.peak
+        })
+    }
+
+    /// Returns the join counts of all completed hours as CSV.
+    pub fn export(&self, guild: id::GuildId) -> io::Result<String> {
+        let clock = &*self.clock;
+        self.run(guild, move |gj| {
+            gj.update_to_latest_hour(clock);
+            log_to_csv(gj.current_hour, &gj.log)
+        })
+    }
+
+    /// Summarizes the completed hours from `from` hours ago until `to` hours ago.
+    ///
+    /// Returns `None` if the window extends beyond the backlog.
+    pub fn window(
+        &self,
+        guild: id::GuildId,
+        from: usize,
+        to: usize,
+    ) -> io::Result<Option<WindowStat>> {
+        let clock = &*self.clock;
+        self.run(guild, move |gj| {
+            gj.update_to_latest_hour(clock);
+            window_stat(gj.current_hour, &gj.log, from, to)
+        })
+    }
+
+    pub fn history(&self, guild: id::GuildId, hours: usize) -> io::Result<Vec<Option<u32>>> {
+        let clock = &*self.clock;
+        self.run(guild, move |gj| gj.history(clock, hours))
+    }
+
+    /// Returns the unix hours from and until which joins were not observed
+    /// before the guild was loaded, if not returned before.
+    pub fn take_offline_gap(&self, guild: id::GuildId) -> io::Result<Option<(u64, u64)>> {
+        self.run(guild, |gj| gj.offline_gap.take())
+    }
+
+    /// Returns the current unix hour of the guild
+    /// and the join counts of the last `hours` completed hours, oldest first.
+    pub fn recent(&self, guild: id::GuildId, hours: usize) -> io::Result<(u64, Vec<Option<u32>>)> {
+        let clock = &*self.clock;
         self.run(guild, move |gj| {
-            gj.add(delta)?;
-            gj.stat()
+            let log = gj.history(clock, hours);
+            (gj.current_hour, log)
         })
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct GuildJoins {
+    /// The format version of the file, `CURRENT_VERSION` once upgraded.
+    #[serde(default)]
+    version: u32,
     current_hour: u64,
+    #[serde(with = "compact_log")]
     log: VecDeque<Option<u32>>,
     current: u32,
+    /// Hourly member removals, parallel to `log`.
+    /// Absent in files written before leaves were tracked.
+    #[serde(default, with = "compact_log")]
+    leaves: VecDeque<Option<u32>>,
+    #[serde(default)]
+    current_leaves: u32,
+    /// Bot accounts that joined in the current hour, whether or not counted in `current`.
+    #[serde(default)]
+    current_bots: u32,
+    /// The unix minute of the last element of `minutes`.
+    #[serde(default)]
+    current_minute: u64,
+    /// Joins per minute over the last `MINUTE_BACKLOG_SIZE` minutes, ending with the current minute.
+    #[serde(default)]
+    minutes: VecDeque<u32>,
+    /// Leaves per minute, parallel to `minutes`.
+    #[serde(default)]
+    leave_minutes: VecDeque<u32>,
+    /// Exponentially weighted moving average of hourly joins,
+    /// or `None` if no hour has completed yet.
+    #[serde(default)]
+    ewma: Option<f64>,
+    /// The latest `MAX_RAID_EVENTS` raids detected, oldest first.
+    #[serde(default)]
+    raids: VecDeque<RaidEvent>,
+    /// Thresholds set at runtime, overriding the config.
+    #[serde(default)]
+    threshold: Option<ThresholdConfig>,
+    /// The completed hour with the most joins ever,
+    /// or `None` if no joins were recorded since peaks were tracked.
+    #[serde(default)]
+    peak: Option<Peak>,
+    /// Joins announced by admins as expected, until it expires.
+    #[serde(default)]
+    expectation: Option<Expectation>,
+    /// Joins per hour estimated by admins for the unknown hours before seeding.
+    #[serde(default)]
+    seed: Option<Seed>,
+    /// Number of completed hours kept in `log`.
+    #[serde(skip)]
+    backlog_size: usize,
+    /// Weight of the latest hour in `ewma`.
+    #[serde(skip)]
+    ewma_alpha: f64,
+    /// Whether an hour has completed since the last save.
+    #[serde(skip)]
+    rolled_over: bool,
+    /// Whether anything has changed since the last save.
+    #[serde(skip)]
+    dirty: bool,
+    #[serde(skip)]
+    last_saved: Option<Instant>,
+    /// Incremented on every snapshot to order writes.
     #[serde(skip)]
-    path: PathBuf,
+    generation: u64,
+    /// Whether the guild has been unloaded, so that changes to this copy would be lost.
+    #[serde(skip)]
+    evicted: bool,
+    /// The unix hours from and until which joins were not observed
+    /// before the data was loaded, if not reported yet.
+    #[serde(skip)]
+    offline_gap: Option<(u64, u64)>,
 }
 
 impl GuildJoins {
-    pub fn read_or_new(path: PathBuf) -> Self {
-        Self::read(path.clone()).unwrap_or_else(|_| Self::new(path))
-    }
-
-    pub fn new(path: PathBuf) -> Self {
+    pub fn new(backlog_size: usize, ewma_alpha: f64, clock: &dyn Clock) -> Self {
         Self {
-            current_hour: current_hour(),
-            log: std::iter::repeat(None).take(BACKLOG_SIZE).collect(),
+            version: CURRENT_VERSION,
+            current_hour: clock.now_hour(),
+            log: vec![None; backlog_size].into(),
             current: 0,
-            path,
+            leaves: vec![None; backlog_size].into(),
+            current_leaves: 0,
+            current_bots: 0,
+            current_minute: clock.now_minute(),
+            minutes: vec![0; MINUTE_BACKLOG_SIZE].into(),
+            leave_minutes: vec![0; MINUTE_BACKLOG_SIZE].into(),
+            ewma: None,
+            raids: VecDeque::new(),
+            threshold: None,
+            peak: None,
+            expectation: None,
+            seed: None,
+            backlog_size,
+            ewma_alpha,
+            rolled_over: false,
+            dirty: false,
+            last_saved: None,
+            generation: 0,
+            evicted: false,
+            offline_gap: None,
         }
     }
 
-    pub fn read(path: PathBuf) -> Result<Self, std::io::Error> {
-        let f = fs::File::open(&path)?;
-        let mut de: Self =
-            serde_json::from_reader(f).map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
-        de.path = path;
-        de.update_to_latest_hour(true)?;
-        Ok(de)
+    /// Prepares a `GuildJoins` freshly deserialized from a store for use.
+    ///
+    /// `running_since` is the unix hour the process started observing joins.
+    pub fn restore(
+        mut self,
+        backlog_size: usize,
+        ewma_alpha: f64,
+        clock: &dyn Clock,
+        running_since: u64,
+    ) -> Self {
+        self.backlog_size = backlog_size;
+        self.ewma_alpha = ewma_alpha;
+        self.upgrade();
+        resize_backlog(&mut self.log, backlog_size);
+        resize_backlog(&mut self.leaves, backlog_size);
+        self.minutes.resize(MINUTE_BACKLOG_SIZE, 0);
+        self.leave_minutes.resize(MINUTE_BACKLOG_SIZE, 0);
+        // joins were not observed before this process started
+        let unobserved_until = running_since.min(clock.now_hour());
+        if self.current_hour < unobserved_until {
+            if self.current_hour + 1 < unobserved_until {
+                self.offline_gap = Some((self.current_hour + 1, unobserved_until));
+            }
+            self.update_to_hour(unobserved_until, None);
+        }
+        self.update_to_latest_hour(clock);
+        self
     }
 
-    pub fn save(&self) -> io::Result<()> {
-        let f = fs::File::create(&self.path)?;
-        serde_json::to_writer(f, self).map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
-        Ok(())
+    /// Migrates data deserialized from older versions to `CURRENT_VERSION`.
+    fn upgrade(&mut self) {
+        if self.version > CURRENT_VERSION {
+            log::warn!(
+                "Guild data has version {}, newer than the supported version {}",
+                self.version,
+                CURRENT_VERSION
+            );
+            return;
+        }
+        if self.version == 0 {
+            // leaves were not tracked, so those hours are unknown rather than 0
+            resize_backlog(&mut self.leaves, self.log.len());
+            if self.ewma.is_none() {
+                self.ewma = self
+                    .log
+                    .iter()
+                    .copied()
+                    .flatten()
+                    .fold(None, |ewma, joins| {
+                        Some(update_ewma(ewma, self.ewma_alpha, f64::from(joins)))
+                    });
+            }
+        }
+        if self.version != CURRENT_VERSION {
+            self.version = CURRENT_VERSION;
+            self.dirty = true;
+        }
     }
 
-    pub fn update_to_latest_hour(&mut self, fill_with_none: bool) -> io::Result<()> {
-        let now = current_hour();
-        assert!(self.current_hour <= now, "System clock travelled backwards");
+    /// Completes the hours until the current hour.
+    ///
+    /// Hours during which this process was running are known to have no joins
+    /// if none were recorded, whether or not the guild was loaded,
+    /// since any join would have loaded it.
+    /// Hours before the process started are unknown and filled in by `restore`.
+    pub fn update_to_latest_hour(&mut self, clock: &dyn Clock) {
+        self.update_to_hour(clock.now_hour(), Some(0));
+    }
+
+    /// Merges in the hourly counts saved by another instance observing the same guild,
+    /// so that saving does not discard the hours only the other instance observed.
+    ///
+    /// Both instances receive the same joins, so each hour keeps the larger count, not the sum.
+    /// Everything else is kept from this copy.
+    pub fn merge_counts(&mut self, other: &GuildJoins) {
+        if self.current_hour < other.current_hour {
+            // this instance did not observe the hours it has not reached yet
+            self.update_to_hour(other.current_hour, None);
+        }
+        merge_log(
+            &mut self.log,
+            &mut self.current,
+            self.current_hour,
+            (&other.log, other.current, other.current_hour),
+        );
+        merge_log(
+            &mut self.leaves,
+            &mut self.current_leaves,
+            self.current_hour,
+            (&other.leaves, other.current_leaves, other.current_hour),
+        );
+    }
 
-        let need_save = self.current_hour != now;
+    /// Completes the hours until `now`, filling the hours without data with `fill`.
+    fn update_to_hour(&mut self, now: u64, fill: Option<u32>) {
+        if now < self.current_hour {
+            // keep counting into the current hour until the clock catches up
+            log::warn!(
+                "System clock travelled backwards from hour {} to {}, skipping rollover",
+                self.current_hour,
+                now
+            );
+            return;
+        }
 
         if self.current_hour < now {
+            self.rolled_over = true;
+            self.dirty = true;
+            if self.current > self.peak.map_or(0, |peak| peak.joins) {
+                self.peak = Some(Peak {
+                    hour: self.current_hour,
+                    joins: self.current,
+                });
+            }
             self.current_hour += 1;
-            self.log.pop_front();
-            self.log.push_back(Some(self.current));
-            self.current = 0;
+            self.ewma = Some(update_ewma(
+                self.ewma,
+                self.ewma_alpha,
+                f64::from(self.current),
+            ));
+            self.push_hour(Some(self.current), Some(self.current_leaves));
+        }
+        if let Some(seed) = self.seed {
+            // the oldest hour in the backlog is `now - log.len()`
+            if seed.hour + self.log.len() as u64 <= now {
+                self.seed = None;
+            }
         }
 
-        // could make this O(1), but no need for that complexity
-        while self.current_hour < now {
-            self.current_hour += 1;
-            self.log.pop_front();
-            self.log.push_back(match fill_with_none {
-                true => None,
-                false => Some(0),
-            });
-            self.current = 0;
+        // hours older than the whole backlog would be shifted out anyway
+        let gap = (now - self.current_hour).min(self.log.len() as u64) as usize;
+        shift_backlog(&mut self.log, gap, fill);
+        shift_backlog(&mut self.leaves, gap, fill);
+        debug_assert_eq!(self.log.len(), self.backlog_size);
+        debug_assert_eq!(self.leaves.len(), self.backlog_size);
+        if fill.is_some() {
+            // repeatedly averaging in hours without joins
+            self.ewma = self
+                .ewma
+                .map(|ewma| ewma * (1. - self.ewma_alpha).powi(gap as i32));
         }
+        self.current_hour = now;
+    }
 
-        if need_save {
-            self.save()?;
+    fn update_to_latest_minute(&mut self, clock: &dyn Clock) {
+        let now = clock.now_minute();
+        if self.current_minute >= now {
+            return;
         }
 
-        Ok(())
+        let elapsed = (now - self.current_minute).min(MINUTE_BACKLOG_SIZE as u64);
+        for _ in 0..elapsed {
+            self.minutes.pop_front();
+            self.minutes.push_back(0);
+            self.leave_minutes.pop_front();
+            self.leave_minutes.push_back(0);
+        }
+        self.current_minute = now;
+    }
+
+    fn push_hour(&mut self, joins: Option<u32>, leaves: Option<u32>) {
+        self.log.pop_front();
+        self.log.push_back(joins);
+        self.leaves.pop_front();
+        self.leaves.push_back(leaves);
+        self.current = 0;
+        self.current_leaves = 0;
+        self.current_bots = 0;
     }
 
-    pub fn add(&mut self, delta: u32) -> io::Result<()> {
-        self.update_to_latest_hour(false)?;
+    pub fn add(&mut self, clock: &dyn Clock, delta: u32) {
+        self.update_to_latest_hour(clock);
+        self.update_to_latest_minute(clock);
+        self.dirty |= delta > 0;
         self.current += delta;
-        Ok(())
+        if let Some(minute) = self.minutes.back_mut() {
+            *minute += delta;
+        }
+    }
+
+    pub fn add_leave(&mut self, clock: &dyn Clock, delta: u32) {
+        self.update_to_latest_hour(clock);
+        self.update_to_latest_minute(clock);
+        self.dirty |= delta > 0;
+        self.current_leaves += delta;
+        if let Some(minute) = self.leave_minutes.back_mut() {
+            *minute += delta;
+        }
     }
 
-    pub fn stat(&mut self) -> io::Result<Stat> {
-        self.update_to_latest_hour(false)?;
+    /// Labels the latest raid as a mass join followed by mass leave,
+    /// or records a new one if the latest raid is older than `NUKE_WINDOW_MINUTES`.
+    fn record_nuke(&mut self, clock: &dyn Clock, stat: Stat) {
+        self.dirty = true;
+        let now = clock.now_secs();
+        match self.raids.back_mut() {
+            Some(raid) if now.saturating_sub(raid.timestamp) <= NUKE_WINDOW_MINUTES as u64 * 60 => {
+                raid.kind = RaidKind::MassJoinLeave;
+            }
+            _ => {
+                if self.raids.len() >= MAX_RAID_EVENTS {
+                    self.raids.pop_front();
+                }
+                self.raids.push_back(RaidEvent {
+                    timestamp: now,
+                    peak: stat.current,
+                    kind: RaidKind::MassJoinLeave,
+                    stat,
+                });
+            }
+        }
+    }
+
+    fn record_raid(&mut self, clock: &dyn Clock, started: bool, stat: Stat) {
+        self.dirty = true;
+        match self.raids.back_mut() {
+            Some(raid) if !started => raid.peak = raid.peak.max(stat.current),
+            _ => {
+                if self.raids.len() >= MAX_RAID_EVENTS {
+                    self.raids.pop_front();
+                }
+                self.raids.push_back(RaidEvent {
+                    timestamp: clock.now_secs(),
+                    peak: stat.current,
+                    kind: RaidKind::Joins,
+                    stat,
+                });
+            }
+        }
+    }
+
+    /// Returns the join counts of the last `hours` completed hours, oldest first.
+    pub fn history(&mut self, clock: &dyn Clock, hours: usize) -> Vec<Option<u32>> {
+        self.update_to_latest_hour(clock);
+        let skip = self.log.len().saturating_sub(hours);
+        self.log.iter().skip(skip).copied().collect()
+    }
+
+    /// Returns whether no joins were recorded in the whole backlog or the current hour,
+    /// and no thresholds were set at runtime.
+    fn is_idle(&self) -> bool {
+        self.current == 0
+            && self.log.iter().all(|joins| joins.unwrap_or(0) == 0)
+            && self.threshold.is_none()
+    }
+
+    /// Estimates the joins of the unknown hours before the backlog was seeded.
+    pub fn seed(&mut self, clock: &dyn Clock, joins: u32) {
+        self.update_to_latest_hour(clock);
+        self.seed = Some(Seed {
+            joins,
+            hour: self.current_hour,
+        });
+        self.ewma.get_or_insert(f64::from(joins));
+        self.rolled_over = true;
+        self.dirty = true;
+    }
+
+    /// Returns `log` with the unknown hours before the seed filled with the seeded joins,
+    /// and the number of hours filled.
+    fn baseline_log(&self) -> (Vec<Option<u32>>, usize) {
+        let mut log: Vec<_> = self.log.iter().copied().collect();
+        let seed = match self.seed {
+            Some(seed) => seed,
+            None => return (log, 0),
+        };
+        let oldest = self.current_hour.saturating_sub(log.len() as u64);
+        let mut seeded = 0;
+        for (hour, joins) in (oldest..).zip(&mut log) {
+            if hour < seed.hour && joins.is_none() {
+                *joins = Some(seed.joins);
+                seeded += 1;
+            }
+        }
+        (log, seeded)
+    }
 
+    /// Returns the join counts of recorded and seeded hours in ascending order.
+    fn sorted_log(&self) -> Vec<f64> {
         let mut data: Vec<_> = self
-            .log
-            .iter()
-            .copied()
+            .baseline_log()
+            .0
+            .into_iter()
             .flatten()
             .map(|int| int as f64)
             .collect();
         // we can't have NANs from (int as f64)
         data.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        data
+    }
+
+    pub fn rank(&mut self, clock: &dyn Clock) -> (u32, Option<f64>) {
+        self.update_to_latest_hour(clock);
+        let data = self.sorted_log();
+        let rank = match data.is_empty() {
+            true => None,
+            false => Some(get_rank(&data, f64::from(self.current))),
+        };
+        (self.current, rank)
+    }
+
+    /// Returns the active expectation, discarding it if it has expired.
+    fn expectation(&mut self, clock: &dyn Clock) -> Option<Expectation> {
+        let expectation = self.expectation?;
+        if expectation.until <= clock.now_secs() {
+            self.expectation = None;
+            self.dirty = true;
+            return None;
+        }
+        Some(expectation)
+    }
+
+    pub fn stat(&mut self, clock: &dyn Clock, method: PercentileMethod) -> Stat {
+        self.update_to_latest_hour(clock);
+        self.update_to_latest_minute(clock);
+
+        let (log, seeded) = self.baseline_log();
+        let data: Vec<_> = log.iter().copied().flatten().collect();
+        Stat {
+            ewma: self.ewma.unwrap_or(0.),
+            decayed_uq: decayed_percentile(&log, self.ewma_alpha, 0.75),
+            leaves: self.current_leaves,
+            bots: self.current_bots,
+            net: i64::from(self.current) - i64::from(self.current_leaves),
+            recent_minutes: self.minutes.iter().rev().take(RECENT_MINUTES).sum(),
+            elapsed_minutes: elapsed_minutes(clock.now_secs()),
+            nuke_window: nuke_window(&self.minutes, &self.leave_minutes),
+            peak: self.peak,
+            expected: self
+                .expectation(clock)
+                .map_or(0, |expectation| expectation.joins),
+            seeded,
+            ..compute_stat(&data, self.current, method)
+        }
+    }
+}
+
+/// A detected raid.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RaidEvent {
+    /// Unix time of the first abnormal join.
+    pub timestamp: u64,
+    /// Highest joins in an hour during the raid.
+    pub peak: u32,
+    /// The stats at the first abnormal join.
+    pub stat: Stat,
+    #[serde(default)]
+    pub kind: RaidKind,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RaidKind {
+    /// Abnormal joins.
+    #[default]
+    Joins,
+    /// A join spike shortly followed by a leave spike.
+    MassJoinLeave,
+}
 
-        Ok(Stat {
-            mean: data.iter().copied().sum::<f64>() / (data.len() as f64),
-            max: get_percentile(&data, 1.),
-            uq: get_percentile(&data, 0.75),
-            median: get_percentile(&data, 0.5),
-            lq: get_percentile(&data, 0.25),
-            min: get_percentile(&data, 0.),
-            n: data.len(),
-            current: self.current,
+impl fmt::Display for RaidKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Joins => "abnormal joins",
+            Self::MassJoinLeave => "mass join+leave pattern",
         })
     }
 }
 
-#[derive(Debug)]
+/// Join statistics of a guild.
+///
+/// The serialized field names are a stable interface for external consumers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Stat {
-    mean: f64,
-    max: f64,
-    uq: f64,
-    median: f64,
-    lq: f64,
-    min: f64,
-    n: usize,
-    current: u32,
+    #[serde(rename = "mean_joins_per_hour")]
+    pub mean: f64,
+    #[serde(rename = "std_deviation")]
+    pub stddev: f64,
+    /// Median absolute deviation.
+    #[serde(rename = "median_absolute_deviation")]
+    pub mad: f64,
+    pub max: f64,
+    #[serde(rename = "upper_quartile")]
+    pub uq: f64,
+    pub median: f64,
+    #[serde(rename = "lower_quartile")]
+    pub lq: f64,
+    pub min: f64,
+    #[serde(rename = "samples")]
+    pub n: usize,
+    /// Exponentially weighted moving average of hourly joins.
+    #[serde(rename = "ewma_joins_per_hour")]
+    pub ewma: f64,
+    /// Upper quartile of hourly joins with the weight of each hour decaying like the EWMA.
+    #[serde(rename = "decayed_upper_quartile", default)]
+    pub decayed_uq: f64,
+    #[serde(rename = "current_hour_joins")]
+    pub current: u32,
+    #[serde(rename = "current_hour_leaves")]
+    pub leaves: u32,
+    /// Bot accounts among or in addition to `current`.
+    #[serde(rename = "current_hour_bot_joins", default)]
+    pub bots: u32,
+    #[serde(rename = "current_hour_net")]
+    pub net: i64,
+    /// Joins in the last `RECENT_MINUTES` minutes.
+    pub recent_minutes: u32,
+    /// Minutes elapsed in the current hour, from 1 to 60.
+    #[serde(default)]
+    pub elapsed_minutes: u64,
+    #[serde(default)]
+    pub nuke_window: NukeWindow,
+    /// The completed hour with the most joins ever recorded.
+    #[serde(default)]
+    pub peak: Option<Peak>,
+    /// Joins per hour expected by admins in addition to the normal limit.
+    #[serde(default)]
+    pub expected: u32,
+    /// Samples among `n` estimated by admins rather than recorded.
+    #[serde(rename = "seeded_samples", default)]
+    pub seeded: usize,
+}
+
+/// A planned influx of joins announced by admins.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Expectation {
+    /// Joins per hour expected in addition to the normal limit.
+    pub joins: u32,
+    /// Unix time the expectation ends.
+    pub until: u64,
+}
+
+/// A baseline estimated by admins for a guild without enough history.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Seed {
+    /// Estimated joins per hour.
+    pub joins: u32,
+    /// The unix hour seeded, before which unknown hours count as `joins`.
+    pub hour: u64,
+}
+
+/// The joins of a completed hour.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Peak {
+    /// The unix hour.
+    pub hour: u64,
+    pub joins: u32,
 }
 
 impl Stat {
-    pub fn is_abnormal(&self) -> bool {
-        if self.current <= 8 {
+    /// Formats the stats as an embed, colored by the severity of the current joins.
+    pub fn to_embed(&self, severity: Severity) -> CreateEmbed {
+        let color = match severity {
+            Severity::Normal => 0x2ecc71,
+            Severity::Elevated => 0xe67e22,
+            Severity::Critical => 0xe74c3c,
+        };
+        let mut embed = CreateEmbed::default();
+        embed
+            .title("Join statistics")
+            .color(color)
+            .field("Mean", format!("{:.3} joins/h", self.mean), true)
+            .field("Recent average", format!("{:.3} joins/h", self.ewma), true)
+            .field("Samples", self.n, true)
+            .field("Minimum", format!("{:.3}", self.min), true)
+            .field("Lower quartile", format!("{:.3}", self.lq), true)
+            .field("Median", format!("{:.3}", self.median), true)
+            .field("Upper quartile", format!("{:.3}", self.uq), true)
+            .field("Maximum", format!("{:.3}", self.max), true)
+            .field(
+                "Past hour",
+                format!(
+                    "{} joins, {} leaves (net {:+})",
+                    self.current, self.leaves, self.net
+                ),
+                false,
+            )
+            .field(
+                format!("Past {} minutes", RECENT_MINUTES),
+                format!("{} joins", self.recent_minutes),
+                false,
+            );
+        embed
+    }
+
+    /// Returns `Elevated` if the joins exceed the limit of the detection formula,
+    /// or `Critical` if they exceed `critical_factor` times the limit.
+    pub fn severity(&self, threshold: &ThresholdConfig) -> Severity {
+        if self.current <= threshold.min_floor || self.n < threshold.min_samples {
+            return Severity::Normal;
+        }
+        let limit = self.hourly_limit(threshold);
+        let hourly = self.current as f64 / limit;
+        let minute = self.recent_minutes as f64 / self.minute_limit(threshold);
+        // too few minutes have elapsed to extrapolate from a handful of joins
+        let early = match self.elapsed_minutes >= threshold.early_min_minutes {
+            true => {
+                project_hour(self.current, self.elapsed_minutes) / (limit * threshold.early_factor)
+            }
+            false => 0.,
+        };
+        let excess = hourly.max(minute).max(early);
+        if excess > threshold.critical_factor {
+            Severity::Critical
+        } else if excess > 1. {
+            Severity::Elevated
+        } else {
+            Severity::Normal
+        }
+    }
+
+    /// Returns the largest number of joins in an hour that is not abnormal.
+    pub fn hourly_limit(&self, threshold: &ThresholdConfig) -> f64 {
+        let baseline = Baseline {
+            uq: self.uq,
+            median: self.median,
+            mad: self.mad,
+            ewma: self.ewma,
+            stddev: self.stddev,
+            decayed_uq: self.decayed_uq,
+        };
+        baseline.limit(threshold) + f64::from(self.expected)
+    }
+
+    /// Returns whether a join spike in the last `NUKE_WINDOW_MINUTES` minutes
+    /// was followed by at least `nuke_leave_ratio` as many leaves,
+    /// as when raid accounts are removed after spamming.
+    pub fn is_nuke(&self, threshold: &ThresholdConfig) -> bool {
+        let window = &self.nuke_window;
+        if window.joins <= threshold.min_floor || self.n < threshold.min_samples {
             return false;
         }
-        (self.current as f64) > self.uq * 2. + 5.
+        let baseline = self.uq / 60. * NUKE_WINDOW_MINUTES as f64;
+        let limit = baseline * threshold.minute_multiplier + threshold.offset;
+        f64::from(window.joins) > limit
+            && f64::from(window.leaves) >= f64::from(window.joins) * threshold.nuke_leave_ratio
+            && window.leaves_after_joins
+    }
+
+    /// Compares the joins of the recent minutes with the hourly upper quartile
+    /// scaled down to the same duration,
+    /// so that a short burst is detected before it dominates the hourly count.
+    fn minute_limit(&self, threshold: &ThresholdConfig) -> f64 {
+        let baseline = self.uq / 60. * RECENT_MINUTES as f64;
+        let expected = f64::from(self.expected) / 60. * RECENT_MINUTES as f64;
+        baseline * threshold.minute_multiplier + threshold.offset + expected
     }
 }
 
-impl fmt::Display for Stat {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        writeln!(
-            f,
-            "Average of {:.3} joins/h in {} samples",
-            self.mean, self.n
-        )?;
-        writeln!(
-            f,
-            "Quartiles: {:.3} / {:.3} / {:.3} / {:.3} / {:.3}",
-            self.min, self.lq, self.median, self.uq, self.max
-        )?;
-        writeln!(f, "There were {} joins in the past hour.", self.current)?;
-        Ok(())
+/// Joins and leaves in the last `NUKE_WINDOW_MINUTES` minutes.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct NukeWindow {
+    pub joins: u32,
+    pub leaves: u32,
+    /// Whether the leaves happened later than the joins on average.
+    pub leaves_after_joins: bool,
+}
+
+/// How far the joins exceed the normal range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Normal,
+    Elevated,
+    Critical,
+}
+
+/// The statistics of past hours used by the hourly detection formula.
+struct Baseline {
+    uq: f64,
+    median: f64,
+    mad: f64,
+    ewma: f64,
+    stddev: f64,
+    decayed_uq: f64,
+}
+
+impl Baseline {
+    fn is_abnormal(&self, current: f64, threshold: &ThresholdConfig) -> bool {
+        current > self.limit(threshold)
+    }
+
+    /// Returns the largest number of joins in an hour that is not abnormal.
+    fn limit(&self, threshold: &ThresholdConfig) -> f64 {
+        match threshold.method {
+            DetectionMethod::Quartile => self.uq * threshold.multiplier + threshold.offset,
+            DetectionMethod::Mad => self.median + self.mad * threshold.mad_multiplier,
+            DetectionMethod::Ewma => {
+                self.ewma + self.stddev * threshold.ewma_multiplier + threshold.offset
+            }
+            DetectionMethod::Decayed => self.decayed_uq * threshold.multiplier + threshold.offset,
+        }
     }
 }
 
-fn current_hour() -> u64 {
-    UNIX_EPOCH
-        .elapsed()
-        .expect("System clock is earlire than unix epoch")
-        .as_secs()
-        / 3600
+/// Returns the minutes elapsed in the hour of the unix time `now`,
+/// counting the current minute, from 1 to 60.
+pub fn elapsed_minutes(now: u64) -> u64 {
+    now % 3600 / 60 + 1
 }
 
-pub fn get_percentile(slice: &[f64], ratio: f64) -> f64 {
-    if slice.is_empty() {
-        return 0.;
+/// Extrapolates the joins in an hour from `current` joins in its first `elapsed_minutes` minutes,
+/// assuming they continue at the same rate.
+pub fn project_hour(current: u32, elapsed_minutes: u64) -> f64 {
+    f64::from(current) * 60. / elapsed_minutes.clamp(1, 60) as f64
+}
+
+/// Returns the weight of the latest hour in an EWMA
+/// such that the weight of an hour halves every `half_life_hours` hours.
+pub fn ewma_alpha(half_life_hours: f64) -> f64 {
+    1. - 0.5f64.powf(1. / half_life_hours)
+}
+
+fn update_ewma(ewma: Option<f64>, alpha: f64, value: f64) -> f64 {
+    match ewma {
+        Some(ewma) => linterp(ewma, value, alpha),
+        None => value,
     }
-    let position = linterp(0., (slice.len() - 1) as f64, ratio);
-    let low = position.trunc() as usize;
-    let high = low + 1;
-    if high >= slice.len() {
-        slice[low]
+}
+
+/// Computes the distribution of the hourly join counts in `data`, in any order,
+/// with `current` joins in the current hour.
+///
+/// The mean is NaN and all percentiles are 0 if `data` is empty.
+/// Fields that depend on more than hourly counts, such as `ewma` and `leaves`, are 0.
+pub fn compute_stat(data: &[u32], current: u32, method: PercentileMethod) -> Stat {
+    let mut data: Vec<_> = data.iter().map(|&joins| f64::from(joins)).collect();
+    // we can't have NANs from u32
+    data.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+    let mean = data.iter().copied().sum::<f64>() / (data.len() as f64);
+    let stddev = if data.is_empty() {
+        0.
     } else {
-        linterp(slice[low], slice[high], position.fract())
+        (data.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (data.len() as f64)).sqrt()
+    };
+
+    let median = get_percentile(&data, 0.5, method);
+    let mut deviations: Vec<_> = data.iter().map(|x| (x - median).abs()).collect();
+    deviations.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let mad = get_percentile(&deviations, 0.5, method);
+
+    Stat {
+        mean,
+        stddev,
+        mad,
+        max: get_percentile(&data, 1., method),
+        uq: get_percentile(&data, 0.75, method),
+        median,
+        lq: get_percentile(&data, 0.25, method),
+        min: get_percentile(&data, 0., method),
+        n: data.len(),
+        ewma: 0.,
+        decayed_uq: 0.,
+        current,
+        leaves: 0,
+        bots: 0,
+        net: i64::from(current),
+        recent_minutes: 0,
+        elapsed_minutes: 60,
+        nuke_window: NukeWindow::default(),
+        peak: None,
+        expected: 0,
+        seeded: 0,
     }
 }
 
-pub fn linterp(l: f64, r: f64, k: f64) -> f64 {
-    l * (1. - k) + r * k
+/// Sums the joins and leaves of the last `NUKE_WINDOW_MINUTES` minutes
+/// of the parallel per-minute counts.
+fn nuke_window(minutes: &VecDeque<u32>, leave_minutes: &VecDeque<u32>) -> NukeWindow {
+    let mut window = NukeWindow::default();
+    // weighted by the number of minutes before the end of the window
+    let (mut join_age, mut leave_age) = (0, 0);
+    let counts = minutes.iter().rev().zip(leave_minutes.iter().rev());
+    for (age, (&joins, &leaves)) in counts.take(NUKE_WINDOW_MINUTES).enumerate() {
+        window.joins += joins;
+        window.leaves += leaves;
+        join_age += u64::from(joins) * age as u64;
+        leave_age += u64::from(leaves) * age as u64;
+    }
+    // compares the mean ages without dividing by the counts
+    window.leaves_after_joins =
+        leave_age * u64::from(window.joins) < join_age * u64::from(window.leaves);
+    window
+}
+
+/// Replays the hourly join counts of `log` through the hourly detection formula,
+/// comparing each hour with the hours recorded before it.
+///
+/// Returns the number of hours that would have been abnormal
+/// and the number of recorded hours replayed.
+/// Short bursts are not replayed since per-minute counts are not kept for old hours.
+pub fn simulate(
+    log: &[Option<u32>],
+    threshold: &ThresholdConfig,
+    ewma_alpha: f64,
+    method: PercentileMethod,
+) -> (usize, usize) {
+    let mut data: Vec<f64> = Vec::new();
+    let mut ewma = None;
+    let mut flagged = 0;
+    for (hour, joins) in log.iter().enumerate() {
+        let joins = match *joins {
+            Some(joins) => f64::from(joins),
+            None => continue,
+        };
+        if joins > f64::from(threshold.min_floor) && data.len() >= threshold.min_samples {
+            let median = get_percentile(&data, 0.5, method);
+            let mut deviations: Vec<_> = data.iter().map(|x| (x - median).abs()).collect();
+            deviations.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            let stddev = match data.len() {
+                0 => 0.,
+                len => {
+                    let mean = data.iter().sum::<f64>() / len as f64;
+                    (data.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / len as f64).sqrt()
+                }
+            };
+            let baseline = Baseline {
+                uq: get_percentile(&data, 0.75, method),
+                median,
+                mad: get_percentile(&deviations, 0.5, method),
+                ewma: ewma.unwrap_or(0.),
+                stddev,
+                decayed_uq: match threshold.method {
+                    DetectionMethod::Decayed => decayed_percentile(&log[..hour], ewma_alpha, 0.75),
+                    _ => 0.,
+                },
+            };
+            if baseline.is_abnormal(joins, threshold) {
+                flagged += 1;
+            }
+        }
+        ewma = Some(update_ewma(ewma, ewma_alpha, joins));
+        let index = data.partition_point(|&x| x < joins);
+        data.insert(index, joins);
+    }
+    (flagged, data.len())
+}
+
+/// Parameters of the abnormal join detection formula.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ThresholdConfig {
+    pub method: DetectionMethod,
+    pub multiplier: f64,
+    pub offset: f64,
+    /// `k` in the `Mad` detection formula.
+    pub mad_multiplier: f64,
+    /// `k` in the `Ewma` detection formula.
+    pub ewma_multiplier: f64,
+    /// Joins in the current hour at or below this count are never abnormal.
+    pub min_floor: u32,
+    /// Nothing is abnormal until at least this many hours are recorded,
+    /// since the statistics of a few hours are meaningless.
+    pub min_samples: usize,
+    /// Multiplier applied to the per-minute baseline when checking for short bursts.
+    pub minute_multiplier: f64,
+    /// Joins exceeding the limit by this factor are critical.
+    pub critical_factor: f64,
+    /// A join spike followed by at least this fraction as many leaves
+    /// is reported as a mass join and leave.
+    pub nuke_leave_ratio: f64,
+    /// The current hour is not extrapolated until this many minutes have elapsed.
+    pub early_min_minutes: u64,
+    /// Joins projected for the current hour exceeding the limit by this factor are abnormal
+    /// before the hour completes.
+    pub early_factor: f64,
+}
+
+impl Default for ThresholdConfig {
+    fn default() -> Self {
+        Self {
+            method: DetectionMethod::Quartile,
+            multiplier: 2.,
+            offset: 5.,
+            mad_multiplier: 3.,
+            ewma_multiplier: 3.,
+            min_floor: 8,
+            min_samples: 48,
+            minute_multiplier: 10.,
+            critical_factor: 3.,
+            nuke_leave_ratio: 0.5,
+            early_min_minutes: 10,
+            early_factor: 2.,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DetectionMethod {
+    /// `current > uq * multiplier + offset`
+    Quartile,
+    /// `current > median + mad_multiplier * mad`,
+    /// which is less sensitive to a few busy hours in the history.
+    Mad,
+    /// `current > ewma + ewma_multiplier * stddev + offset`,
+    /// which adapts faster when the normal activity of a server changes.
+    Ewma,
+    /// `current > decayed_uq * multiplier + offset`,
+    /// where `decayed_uq` weights recent hours more like the EWMA,
+    /// so that busy hours long ago raise the limit less.
+    Decayed,
+}
+
+impl fmt::Display for Stat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "Average of {:.3} joins/h in {} samples",
+            self.mean, self.n
+        )?;
+        if self.seeded > 0 {
+            writeln!(
+                f,
+                "{} of the samples are estimates seeded by admins.",
+                self.seeded
+            )?;
+        }
+        writeln!(f, "Recent average: {:.3} joins/h", self.ewma)?;
+        writeln!(
+            f,
+            "Recent-weighted upper quartile: {:.3} joins/h",
+            self.decayed_uq
+        )?;
+        writeln!(f, "Std deviation: {:.3}", self.stddev)?;
+        writeln!(f, "Median absolute deviation: {:.3}", self.mad)?;
+        writeln!(
+            f,
+            "Quartiles: {:.3} / {:.3} / {:.3} / {:.3} / {:.3}",
+            self.min, self.lq, self.median, self.uq, self.max
+        )?;
+        writeln!(
+            f,
+            "There were {} joins and {} leaves (net {:+}) in the past hour.",
+            self.current, self.leaves, self.net
+        )?;
+        if self.bots > 0 {
+            writeln!(f, "{} of the joins in the past hour were bots.", self.bots)?;
+        }
+        writeln!(
+            f,
+            "There were {} joins in the past {} minutes.",
+            self.recent_minutes, RECENT_MINUTES
+        )?;
+        if self.expected > 0 {
+            writeln!(
+                f,
+                "Up to {} more joins per hour are expected.",
+                self.expected
+            )?;
+        }
+        if let Some(peak) = self.peak {
+            writeln!(
+                f,
+                "The busiest hour ever had {} joins at <t:{}:f>.",
+                peak.joins,
+                peak.hour * 3600
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Drops the `count` oldest hours and appends `count` hours of `fill`.
+fn shift_backlog(log: &mut VecDeque<Option<u32>>, count: usize, fill: Option<u32>) {
+    let len = log.len();
+    log.drain(..count.min(len));
+    log.resize(len, fill);
+}
+
+/// Keeps the larger count of each hour in `log` and `current`, the count of `hour`,
+/// and in `other`, a log, the count of its current hour and the hour, which is at most `hour`.
+fn merge_log(
+    log: &mut VecDeque<Option<u32>>,
+    current: &mut u32,
+    hour: u64,
+    other: (&VecDeque<Option<u32>>, u32, u64),
+) {
+    let (other_log, other_current, other_hour) = other;
+    let completed = other_log
+        .iter()
+        .rev()
+        .zip((0..other_hour).rev())
+        .map(|(&count, hour)| (hour, count));
+    for (other_hour, count) in completed.chain(Some((other_hour, Some(other_current)))) {
+        if other_hour == hour {
+            *current = (*current).max(count.unwrap_or(0));
+            continue;
+        }
+        let age = (hour - other_hour) as usize;
+        if let Some(slot) = log
+            .len()
+            .checked_sub(age)
+            .and_then(|index| log.get_mut(index))
+        {
+            *slot = match (*slot, count) {
+                (Some(slot), Some(count)) => Some(slot.max(count)),
+                (slot, count) => slot.or(count),
+            };
+        }
+    }
+}
+
+/// Drops the oldest hours or pads with unknown (`None`) hours
+/// so that `log` holds exactly `size` entries.
+fn resize_backlog(log: &mut VecDeque<Option<u32>>, size: usize) {
+    while log.len() > size {
+        log.pop_front();
+    }
+    while log.len() < size {
+        log.push_front(None);
+    }
+}
+
+/// Join statistics of a span of past hours.
+pub struct WindowStat {
+    /// The unix hour of the first hour in the window.
+    pub start_hour: u64,
+    /// The unix hour after the last hour in the window.
+    pub end_hour: u64,
+    pub total: u32,
+    /// The unix hour with the most joins and its joins, if any hour has data.
+    pub peak: Option<(u64, u32)>,
+    /// Mean joins per hour over the hours with data.
+    pub mean: f64,
+    /// Number of hours with data.
+    pub n: usize,
+}
+
+/// Summarizes the hours of `log`, which ends just before `current_hour`,
+/// that started between `from` and `to` hours ago,
+/// where the last hour of `log` started 1 hour ago.
+///
+/// Returns `None` if `from` is beyond the length of `log` or the window is empty.
+pub fn window_stat(
+    current_hour: u64,
+    log: &VecDeque<Option<u32>>,
+    from: usize,
+    to: usize,
+) -> Option<WindowStat> {
+    if from > log.len() || from <= to {
+        return None;
+    }
+    let start = log.len() - from;
+    let end = log.len() - to;
+    let first_hour = current_hour - log.len() as u64;
+
+    let mut total = 0;
+    let mut peak: Option<(u64, u32)> = None;
+    let mut n = 0;
+    for (index, joins) in log.iter().enumerate().take(end).skip(start) {
+        if let Some(joins) = *joins {
+            let hour = first_hour + index as u64;
+            total += joins;
+            n += 1;
+            if peak.is_none_or(|(_, peak)| joins > peak) {
+                peak = Some((hour, joins));
+            }
+        }
+    }
+    Some(WindowStat {
+        start_hour: first_hour + start as u64,
+        end_hour: first_hour + end as u64,
+        total,
+        peak,
+        mean: match n {
+            0 => 0.,
+            n => f64::from(total) / n as f64,
+        },
+        n,
+    })
+}
+
+/// The distribution of the joins in the current hour across guilds.
+pub struct FleetStat {
+    pub guilds: usize,
+    pub median: f64,
+    /// Upper quartile.
+    pub uq: f64,
+    /// Lower quartile.
+    pub lq: f64,
+    pub max: f64,
+    /// Joins above which a guild is an outlier.
+    pub fence: f64,
+    /// Guilds with joins above `fence` and their joins, busiest first.
+    pub outliers: Vec<(id::GuildId, u32)>,
+}
+
+/// Compares the joins in the current hour of each guild in `joins` to the others.
+pub fn fleet_stat(joins: &[(id::GuildId, u32)], method: PercentileMethod) -> FleetStat {
+    let mut data: Vec<_> = joins
+        .iter()
+        .map(|&(_, current)| f64::from(current))
+        .collect();
+    // we can't have NANs from u32
+    data.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+    let uq = get_percentile(&data, 0.75, method);
+    let lq = get_percentile(&data, 0.25, method);
+    let fence = (uq + FLEET_OUTLIER_IQRS * (uq - lq)).max(f64::from(FLEET_OUTLIER_MIN_JOINS));
+    let mut outliers: Vec<_> = joins
+        .iter()
+        .copied()
+        .filter(|&(_, current)| f64::from(current) > fence)
+        .collect();
+    outliers.sort_by_key(|&(guild, current)| (std::cmp::Reverse(current), guild));
+
+    FleetStat {
+        guilds: joins.len(),
+        median: get_percentile(&data, 0.5, method),
+        uq,
+        lq,
+        max: get_percentile(&data, 1., method),
+        fence,
+        outliers,
+    }
+}
+
+/// Formats the hourly join counts of `log`, which ends just before `current_hour`, as CSV.
+///
+/// Each row has the unix time at which the hour started and the joins in it.
+/// Hours without data have an empty joins cell, unlike hours with 0 joins.
+pub fn log_to_csv(current_hour: u64, log: &VecDeque<Option<u32>>) -> String {
+    let mut csv = String::from("hour_start,joins\n");
+    let first_hour = current_hour.saturating_sub(log.len() as u64);
+    for (hour, joins) in (first_hour..).zip(log) {
+        let joins = joins.map(|joins| joins.to_string()).unwrap_or_default();
+        csv += &format!("{},{}\n", hour * 3600, joins);
+    }
+    csv
+}
+
+/// Sums consecutive hours of `log` into at most `width` buckets,
+/// returning the buckets and the number of hours in each of them.
+///
+/// The oldest bucket may cover fewer hours so that the others line up with the latest hour.
+/// A bucket is unknown only if all its hours are.
+pub fn downsample(log: &[Option<u32>], width: usize) -> (Vec<Option<u32>>, usize) {
+    let hours = log.len().div_ceil(width.max(1)).max(1);
+    let mut buckets: Vec<_> = log
+        .rchunks(hours)
+        .map(|chunk| {
+            chunk
+                .iter()
+                .copied()
+                .flatten()
+                .reduce(|a, b| a.saturating_add(b))
+        })
+        .collect();
+    buckets.reverse();
+    (buckets, hours)
+}
+
+/// Renders hourly join counts as a sparkline scaled to the largest count.
+///
+/// Unknown hours, where the bot was offline, are rendered as `?`.
+pub fn sparkline(log: &[Option<u32>]) -> String {
+    const LEVELS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+    let max = log.iter().copied().flatten().max().unwrap_or(0);
+    log.iter()
+        .map(|&count| match count {
+            None => '?',
+            Some(_) if max == 0 => LEVELS[0],
+            Some(count) => {
+                let level = u64::from(count) * (LEVELS.len() as u64 - 1) / u64::from(max);
+                LEVELS[level as usize]
+            }
+        })
+        .collect()
+}
+
+/// Lists the hourly join counts of `log`, which ends just before `current_hour`, newest first.
+///
+/// Unknown hours, where the bot was offline, are marked as such.
+pub fn timeline(current_hour: u64, log: &[Option<u32>]) -> String {
+    let mut out = String::new();
+    for (age, count) in log.iter().rev().enumerate() {
+        let hour = current_hour - 1 - age as u64;
+        match count {
+            Some(count) => out += &format!("<t:{}:f>: {}\n", hour * 3600, count),
+            None => out += &format!("<t:{}:f>: no data (offline)\n", hour * 3600),
+        }
+    }
+    out
+}
+
+/// How `get_percentile` picks a value between two elements.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PercentileMethod {
+    /// Interpolates linearly between the two closest ranks.
+    #[default]
+    Linear,
+    /// The smallest element such that at least `ratio` of the elements are not greater than it.
+    NearestRank,
+    /// The lower of the two closest ranks.
+    Lower,
+}
+
+/// Returns the percentile of the sorted `slice` at `ratio` in `[0, 1]`.
+///
+/// Returns 0 if `slice` is empty.
+pub fn get_percentile(slice: &[f64], ratio: f64, method: PercentileMethod) -> f64 {
+    if slice.is_empty() {
+        return 0.;
+    }
+    let last = slice.len() - 1;
+    match method {
+        PercentileMethod::Linear => {
+            let position = linterp(0., last as f64, ratio);
+            let low = position.trunc() as usize;
+            let high = low + 1;
+            if high >= slice.len() {
+                slice[low]
+            } else {
+                linterp(slice[low], slice[high], position.fract())
+            }
+        }
+        PercentileMethod::NearestRank => {
+            let rank = (ratio * slice.len() as f64).ceil() as usize;
+            slice[rank.saturating_sub(1).min(last)]
+        }
+        PercentileMethod::Lower => {
+            slice[(linterp(0., last as f64, ratio).floor() as usize).min(last)]
+        }
+    }
+}
+
+/// Returns the weighted percentile at `ratio` in `[0, 1]` of the hours in `log`, oldest first,
+/// where the weight of an hour is `(1 - alpha)` times the weight of the next hour.
+///
+/// This is the smallest count such that the hours not exceeding it
+/// make up at least `ratio` of the total weight, or 0 if `log` has no data.
+pub fn decayed_percentile(log: &[Option<u32>], alpha: f64, ratio: f64) -> f64 {
+    let mut weighted: Vec<(f64, f64)> = Vec::with_capacity(log.len());
+    let mut weight = 1.;
+    for joins in log.iter().rev() {
+        if let Some(joins) = *joins {
+            weighted.push((f64::from(joins), weight));
+        }
+        weight *= 1. - alpha;
+    }
+    // we can't have NANs from u32
+    weighted.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+
+    let total: f64 = weighted.iter().map(|&(_, weight)| weight).sum();
+    let mut cumulative = 0.;
+    for &(joins, weight) in &weighted {
+        cumulative += weight;
+        if cumulative >= ratio * total {
+            return joins;
+        }
+    }
+    weighted.last().map_or(0., |&(joins, _)| joins)
+}
+
+/// The inverse of `get_percentile` with `PercentileMethod::Linear`:
+/// returns the smallest ratio at which the percentile of `slice` reaches `value`.
+pub fn get_rank(slice: &[f64], value: f64) -> f64 {
+    let below = slice.partition_point(|&x| x < value);
+    if below == 0 {
+        return 0.;
+    }
+    if below == slice.len() {
+        return 1.;
+    }
+    let (low, high) = (slice[below - 1], slice[below]);
+    let position = (below - 1) as f64 + (value - low) / (high - low);
+    position / (slice.len() - 1) as f64
+}
+
+/// Interpolates linearly from `l` at `k = 0` to `r` at `k = 1`.
+///
+/// The result is exactly `l` if `l == r`, and never outside `[l, r]` for `k` in `[0, 1]`,
+/// so that percentiles between equal counts cannot be rounded below the lower one.
+pub fn linterp(l: f64, r: f64, k: f64) -> f64 {
+    l + (r - l) * k
+}
+
+/// Stores hourly counts as runs of equal counts,
+/// since the backlog of an idle guild is mostly unknown or zero hours.
+///
+/// Files written before this format have a plain array of counts, which is still accepted.
+mod compact_log {
+    use std::collections::VecDeque;
+
+    use serde::de::Error as _;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::MAX_BACKLOG_SIZE;
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(untagged)]
+    enum Log {
+        /// `(count, repeat)` pairs in order.
+        Runs {
+            runs: Vec<(Option<u32>, usize)>,
+        },
+        Plain(VecDeque<Option<u32>>),
+    }
+
+    pub fn serialize<S: Serializer>(
+        log: &VecDeque<Option<u32>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut runs: Vec<(Option<u32>, usize)> = Vec::new();
+        for &count in log {
+            match runs.last_mut() {
+                Some((last, repeat)) if *last == count => *repeat += 1,
+                _ => runs.push((count, 1)),
+            }
+        }
+        Log::Runs { runs }.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<VecDeque<Option<u32>>, D::Error> {
+        Ok(match Log::deserialize(deserializer)? {
+            Log::Runs { runs } => {
+                let mut log = VecDeque::new();
+                for (count, repeat) in runs {
+                    // corrupt runs must not allocate unbounded memory
+                    let len = log
+                        .len()
+                        .checked_add(repeat)
+                        .filter(|&len| len <= MAX_BACKLOG_SIZE)
+                        .ok_or_else(|| {
+                            D::Error::custom(format!(
+                                "log is longer than the maximum of {} hours",
+                                MAX_BACKLOG_SIZE
+                            ))
+                        })?;
+                    log.resize(len, count);
+                }
+                log
+            }
+            Log::Plain(log) => log,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::fs;
+
+    use crate::clock::{MockClock, SystemClock};
+    use crate::store::{JsonStore, MemoryStore};
+
+    #[derive(Serialize, Deserialize)]
+    struct CompactLog {
+        #[serde(with = "compact_log")]
+        log: VecDeque<Option<u32>>,
+    }
+
+    /// A log with a few joins in mostly unknown or empty hours.
+    fn sparse_log() -> VecDeque<Option<u32>> {
+        let mut log: VecDeque<_> = vec![None; DEFAULT_BACKLOG_SIZE].into();
+        for joins in log.iter_mut().skip(600) {
+            *joins = Some(0);
+        }
+        log[650] = Some(3);
+        log[700] = Some(12);
+        log
+    }
+
+    #[test]
+    fn compact_log_round_trip() {
+        let log = sparse_log();
+        let json = serde_json::to_string(&CompactLog { log: log.clone() }).unwrap();
+        let read: CompactLog = serde_json::from_str(&json).unwrap();
+        assert_eq!(read.log, log);
+    }
+
+    #[test]
+    fn compact_log_reads_plain_arrays() {
+        let read: CompactLog = serde_json::from_str(r#"{"log":[null,1,2,null]}"#).unwrap();
+        assert_eq!(read.log, [None, Some(1), Some(2), None]);
+    }
+
+    #[test]
+    fn compact_log_is_smaller_than_plain() {
+        let log = sparse_log();
+        let compact = serde_json::to_string(&CompactLog { log: log.clone() }).unwrap();
+        let plain = serde_json::to_string(&log).unwrap();
+        assert!(
+            compact.len() * 20 < plain.len(),
+            "compact {} bytes, plain {} bytes",
+            compact.len(),
+            plain.len()
+        );
+    }
+
+    #[test]
+    fn compact_log_rejects_huge_runs() {
+        let json = format!(r#"{{"log":{{"runs":[[null,{}]]}}}}"#, MAX_BACKLOG_SIZE + 1);
+        assert!(serde_json::from_str::<CompactLog>(&json).is_err());
+        let json = format!(
+            r#"{{"log":{{"runs":[[null,{}],[1,{}]]}}}}"#,
+            usize::MAX,
+            usize::MAX
+        );
+        assert!(serde_json::from_str::<CompactLog>(&json).is_err());
+        let json = format!(r#"{{"log":{{"runs":[[null,{}]]}}}}"#, MAX_BACKLOG_SIZE);
+        let read: CompactLog = serde_json::from_str(&json).unwrap();
+        assert_eq!(read.log.len(), MAX_BACKLOG_SIZE);
+    }
+
+    #[test]
+    fn sparkline_scales_to_the_largest_count() {
+        assert_eq!(sparkline(&[Some(0), Some(7), None, Some(14)]), "▁▄?█");
+        assert_eq!(sparkline(&[Some(0), None]), "▁?");
+    }
+
+    #[test]
+    fn downsample_sums_buckets_ending_at_the_latest_hour() {
+        let log = [Some(1), None, Some(2), None, None, Some(3), Some(4)];
+        assert_eq!(downsample(&log, 10), (log.to_vec(), 1));
+        assert_eq!(downsample(&log, 3), (vec![Some(1), Some(2), Some(7)], 3));
+        assert_eq!(downsample(&log[1..5], 2), (vec![Some(2), None], 2));
+    }
+
+    #[test]
+    fn downsampled_sparkline_fits_the_width() {
+        let log = vec![Some(1); MAX_BACKLOG_SIZE];
+        let (buckets, hours) = downsample(&log, 500);
+        assert!(buckets.len() <= 500);
+        assert_eq!(
+            buckets.iter().flatten().sum::<u32>() as usize,
+            MAX_BACKLOG_SIZE
+        );
+        assert!(sparkline(&buckets).chars().count() <= 500);
+        assert_eq!(hours, MAX_BACKLOG_SIZE.div_ceil(500));
+    }
+
+    fn guild_joins_at(clock: &MockClock) -> GuildJoins {
+        GuildJoins::new(4, 0.5, clock)
+    }
+
+    #[test]
+    fn merge_counts_keeps_the_larger_count_of_each_hour() {
+        let clock = MockClock::new(3600 * 100);
+        let mut a = guild_joins_at(&clock);
+        let mut b = guild_joins_at(&clock);
+        a.add(&clock, 3);
+        b.add(&clock, 5);
+        clock.advance(3600);
+        a.add(&clock, 2);
+        b.add(&clock, 1);
+        a.log[1] = Some(7);
+        b.log[0] = Some(4);
+
+        a.merge_counts(&b);
+        assert_eq!(
+            a.log.iter().copied().collect::<Vec<_>>(),
+            [Some(4), Some(7), None, Some(5)]
+        );
+        assert_eq!(a.current, 2);
+
+        // merging again changes nothing
+        let merged = a.log.clone();
+        a.merge_counts(&b);
+        assert_eq!(a.log, merged);
+        assert_eq!(a.current, 2);
+    }
+
+    #[test]
+    fn merge_counts_catches_up_with_a_later_copy() {
+        let clock = MockClock::new(3600 * 100);
+        let mut a = guild_joins_at(&clock);
+        a.add(&clock, 3);
+        let mut b = a.clone();
+        clock.advance(2 * 3600);
+        b.add(&clock, 6);
+
+        a.merge_counts(&b);
+        assert_eq!(a.current_hour, b.current_hour);
+        assert_eq!(a.current, 6);
+        assert_eq!(
+            a.log.iter().copied().collect::<Vec<_>>(),
+            [None, None, Some(3), Some(0)]
+        );
+        assert_eq!(a.log.len(), a.backlog_size);
+    }
+
+    #[test]
+    fn merge_counts_ignores_hours_before_the_backlog() {
+        let clock = MockClock::new(3600 * 100);
+        let mut a = guild_joins_at(&clock);
+        let mut b = guild_joins_at(&clock);
+        b.add(&clock, 9);
+        clock.advance(10 * 3600);
+        a.add(&clock, 1);
+
+        a.merge_counts(&b);
+        assert_eq!(a.current, 1);
+        assert!(a.log.iter().all(|&count| count == Some(0)));
+    }
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        /// Advances the clock by this many seconds.
+        Wait(u64),
+        Add(u32),
+        AddLeave(u32),
+        Stat,
+        Seed(u32),
+        /// Saves and loads the data with this backlog size.
+        Reload(usize),
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            (0..3600 * 30u64).prop_map(Op::Wait),
+            (0..3600 * 1000u64).prop_map(Op::Wait),
+            (0..100u32).prop_map(Op::Add),
+            (0..100u32).prop_map(Op::AddLeave),
+            Just(Op::Stat),
+            (0..100u32).prop_map(Op::Seed),
+            (1..50usize).prop_map(Op::Reload),
+        ]
+    }
+
+    fn method() -> impl Strategy<Value = PercentileMethod> {
+        prop_oneof![
+            Just(PercentileMethod::Linear),
+            Just(PercentileMethod::NearestRank),
+            Just(PercentileMethod::Lower),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn backlog_keeps_its_length(backlog in 1..50usize, ops in prop::collection::vec(op(), 0..60)) {
+            let clock = MockClock::new(3600 * 400_000 + 1234);
+            let mut backlog = backlog;
+            let mut gj = GuildJoins::new(backlog, 0.1, &clock);
+            for op in ops {
+                match op {
+                    Op::Wait(secs) => clock.advance(secs),
+                    Op::Add(joins) => gj.add(&clock, joins),
+                    Op::AddLeave(leaves) => gj.add_leave(&clock, leaves),
+                    Op::Stat => {
+                        gj.stat(&clock, PercentileMethod::Linear);
+                    }
+                    Op::Seed(joins) => gj.seed(&clock, joins),
+                    Op::Reload(size) => {
+                        let json = serde_json::to_string(&gj).unwrap();
+                        let loaded: GuildJoins = serde_json::from_str(&json).unwrap();
+                        backlog = size;
+                        gj = loaded.restore(backlog, 0.1, &clock, clock.now_hour());
+                    }
+                }
+                gj.update_to_latest_hour(&clock);
+                prop_assert_eq!(gj.log.len(), backlog);
+                prop_assert_eq!(gj.leaves.len(), backlog);
+                prop_assert_eq!(gj.current_hour, clock.now_hour());
+                prop_assert_eq!(gj.history(&clock, backlog + 5).len(), backlog);
+            }
+        }
+
+        #[test]
+        fn quartiles_are_ordered(data in prop::collection::vec(0..10_000u32, 0..200), method in method()) {
+            let stat = compute_stat(&data, 0, method);
+            prop_assert!(stat.min <= stat.lq);
+            prop_assert!(stat.lq <= stat.median);
+            prop_assert!(stat.median <= stat.uq);
+            prop_assert!(stat.uq <= stat.max);
+        }
+
+        #[test]
+        fn percentiles_are_monotonic(
+            data in prop::collection::vec(0..10_000u32, 1..200),
+            a in 0.0..=1.0f64,
+            b in 0.0..=1.0f64,
+            method in method(),
+        ) {
+            let mut data: Vec<_> = data.into_iter().map(f64::from).collect();
+            data.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            let (low, high) = if a <= b { (a, b) } else { (b, a) };
+            let (low, high) = (get_percentile(&data, low, method), get_percentile(&data, high, method));
+            prop_assert!(low <= high, "{} > {}", low, high);
+            prop_assert!(data[0] <= low && high <= data[data.len() - 1]);
+        }
+    }
+
+    #[test]
+    fn stat_of_no_data() {
+        let stat = compute_stat(&[], 7, PercentileMethod::Linear);
+        assert_eq!(stat.n, 0);
+        assert!(stat.mean.is_nan());
+        assert_eq!(stat.stddev, 0.);
+        assert_eq!(
+            (stat.min, stat.lq, stat.median, stat.uq, stat.max),
+            (0., 0., 0., 0., 0.)
+        );
+        assert_eq!(stat.current, 7);
+    }
+
+    #[test]
+    fn stat_of_one_hour() {
+        let stat = compute_stat(&[5], 0, PercentileMethod::Linear);
+        assert_eq!(stat.n, 1);
+        assert_eq!((stat.mean, stat.stddev, stat.mad), (5., 0., 0.));
+        assert_eq!(
+            (stat.min, stat.lq, stat.median, stat.uq, stat.max),
+            (5., 5., 5., 5., 5.)
+        );
+    }
+
+    #[test]
+    fn stat_of_odd_length() {
+        let stat = compute_stat(&[5, 1, 4, 2, 3], 0, PercentileMethod::Linear);
+        assert_eq!(
+            (stat.min, stat.lq, stat.median, stat.uq, stat.max),
+            (1., 2., 3., 4., 5.)
+        );
+        assert_eq!(stat.mean, 3.);
+    }
+
+    #[test]
+    fn stat_of_even_length() {
+        let stat = compute_stat(&[4, 3, 2, 1], 0, PercentileMethod::Linear);
+        assert_eq!(
+            (stat.min, stat.lq, stat.median, stat.uq, stat.max),
+            (1., 1.75, 2.5, 3.25, 4.)
+        );
+    }
+
+    #[test]
+    fn stat_deviations() {
+        let stat = compute_stat(&[2, 4, 4, 4, 5, 5, 7, 9], 0, PercentileMethod::Linear);
+        assert_eq!(stat.mean, 5.);
+        assert_eq!(stat.stddev, 2.);
+        assert_eq!(stat.median, 4.5);
+        assert_eq!(stat.mad, 0.5);
+    }
+
+    #[test]
+    fn percentile_methods() {
+        let data = [10., 20., 30., 40., 50.];
+        let nearest: Vec<_> = [0., 0.25, 0.5, 0.75, 1.]
+            .iter()
+            .map(|&ratio| get_percentile(&data, ratio, PercentileMethod::NearestRank))
+            .collect();
+        assert_eq!(nearest, [10., 20., 30., 40., 50.]);
+
+        let data = [1., 2., 3., 4.];
+        let lower: Vec<_> = [0.25, 0.5, 0.75, 1.]
+            .iter()
+            .map(|&ratio| get_percentile(&data, ratio, PercentileMethod::Lower))
+            .collect();
+        assert_eq!(lower, [1., 2., 3., 4.]);
+        assert_eq!(get_percentile(&data, 0.5, PercentileMethod::Linear), 2.5);
+        assert_eq!(get_percentile(&[], 0.5, PercentileMethod::Linear), 0.);
+    }
+
+    #[test]
+    fn linterp_between_bounds() {
+        assert_eq!(linterp(2., 6., 0.), 2.);
+        assert_eq!(linterp(2., 6., 0.25), 3.);
+        assert_eq!(linterp(2., 6., 1.), 6.);
+    }
+
+    /// Stats of a completed hour with `current` joins after hours with `history` joins.
+    fn stat_after(history: &[u32], current: u32) -> Stat {
+        compute_stat(history, current, PercentileMethod::Linear)
+    }
+
+    #[test]
+    fn custom_multiplier_triggers_at_its_limit() {
+        let history = [10; 48];
+        let custom = ThresholdConfig {
+            multiplier: 3.,
+            ..ThresholdConfig::default()
+        };
+        // 10 * 3 + 5
+        assert_eq!(stat_after(&history, 35).severity(&custom), Severity::Normal);
+        assert_eq!(
+            stat_after(&history, 36).severity(&custom),
+            Severity::Elevated
+        );
+        // 10 * 2 + 5 with the default multiplier
+        let default = ThresholdConfig::default();
+        assert_eq!(
+            stat_after(&history, 25).severity(&default),
+            Severity::Normal
+        );
+        assert_eq!(
+            stat_after(&history, 26).severity(&default),
+            Severity::Elevated
+        );
+    }
+
+    #[test]
+    fn floor_and_samples_suppress_alerts() {
+        let threshold = ThresholdConfig {
+            min_floor: 40,
+            ..ThresholdConfig::default()
+        };
+        assert_eq!(
+            stat_after(&[0; 48], 40).severity(&threshold),
+            Severity::Normal
+        );
+        assert_eq!(
+            stat_after(&[0; 48], 41).severity(&threshold),
+            Severity::Critical
+        );
+        // fewer than `min_samples` hours
+        assert_eq!(
+            stat_after(&[0; 47], 1000).severity(&threshold),
+            Severity::Normal
+        );
+    }
+
+    /// A map of guilds stored in memory, with a clock starting at hour 100 000.
+    fn test_map() -> (GuildJoinsMap, MemoryStore, std::sync::Arc<MockClock>) {
+        test_map_with(None, Duration::ZERO)
+    }
+
+    fn test_map_with(
+        capacity: Option<usize>,
+        save_interval: Duration,
+    ) -> (GuildJoinsMap, MemoryStore, std::sync::Arc<MockClock>) {
+        let store = MemoryStore::default();
+        let clock = std::sync::Arc::new(MockClock::new(3600 * 100_000 + 60));
+        let map = GuildJoinsMap::new(
+            Box::new(store.clone()),
+            Box::new(std::sync::Arc::clone(&clock)),
+            24,
+            0.1,
+            PercentileMethod::Linear,
+            capacity,
+            save_interval,
+        );
+        (map, store, clock)
+    }
+
+    #[test]
+    fn reset_clears_only_its_guild() {
+        let (map, store, clock) = test_map();
+        let (guild, other) = (id::GuildId(1), id::GuildId(2));
+        for _ in 0..5 {
+            map.add(guild, 3).unwrap();
+            map.add(other, 2).unwrap();
+            clock.advance(3600);
+        }
+        assert_eq!(map.add(guild, 0).unwrap().n, 5);
+
+        map.reset(guild).unwrap();
+        let stat = map.add(guild, 0).unwrap();
+        assert_eq!((stat.n, stat.current), (0, 0));
+        // saved immediately
+        assert_eq!(store.saved(guild).unwrap()["current"], 0);
+        assert_eq!(map.add(other, 0).unwrap().n, 5);
+    }
+
+    #[test]
+    fn stat_shows_the_standard_deviation() {
+        let clock = MockClock::new(3600 * 100_000);
+        let mut gj = GuildJoins::new(24, 0.1, &clock);
+        for &joins in &[2, 4, 4, 4, 5, 5, 7, 9] {
+            gj.add(&clock, joins);
+            clock.advance(3600);
+        }
+        let stat = gj.stat(&clock, PercentileMethod::Linear);
+        assert_eq!(stat.stddev, 2.);
+        assert!(stat.to_string().contains("Std deviation: 2.000\n"));
+
+        let mut empty = GuildJoins::new(24, 0.1, &clock);
+        let stat = empty.stat(&clock, PercentileMethod::Linear);
+        assert!(stat.to_string().contains("Std deviation: 0.000\n"));
+    }
+
+    #[test]
+    fn old_files_without_leaves_are_read() {
+        let clock = MockClock::new(3600 * 100_000 + 60);
+        let json = r#"{"current_hour":100000,"log":[1,null,3,4],"current":5}"#;
+        let gj: GuildJoins = serde_json::from_str(json).unwrap();
+        let mut gj = gj.restore(4, 0.5, &clock, 100_000);
+        assert_eq!(gj.version, CURRENT_VERSION);
+        assert!(gj.dirty);
+        assert_eq!(gj.leaves, [None; 4]);
+        assert_eq!(gj.current_leaves, 0);
+        let stat = gj.stat(&clock, PercentileMethod::Linear);
+        assert_eq!((stat.current, stat.leaves, stat.net), (5, 0, 5));
+        assert_eq!(stat.n, 3);
+    }
+
+    #[test]
+    fn leaves_count_against_net_growth() {
+        let clock = MockClock::new(3600 * 100_000);
+        let mut gj = GuildJoins::new(4, 0.5, &clock);
+        gj.add(&clock, 2);
+        gj.add_leave(&clock, 5);
+        let stat = gj.stat(&clock, PercentileMethod::Linear);
+        assert_eq!((stat.current, stat.leaves, stat.net), (2, 5, -3));
+        clock.advance(3600);
+        gj.update_to_latest_hour(&clock);
+        assert_eq!(gj.leaves.back(), Some(&Some(5)));
+        assert_eq!(gj.current_leaves, 0);
+    }
+
+    fn loaded_with_backlog(log: &str, backlog: usize) -> GuildJoins {
+        let clock = MockClock::new(3600 * 100_000 + 60);
+        let json = format!(
+            r#"{{"version":1,"current_hour":100000,"log":{},"current":0}}"#,
+            log
+        );
+        let gj: GuildJoins = serde_json::from_str(&json).unwrap();
+        gj.restore(backlog, 0.5, &clock, 100_000)
+    }
+
+    #[test]
+    fn longer_backlog_pads_the_oldest_hours() {
+        let gj = loaded_with_backlog("[1,2,3]", 5);
+        assert_eq!(gj.log, [None, None, Some(1), Some(2), Some(3)]);
+        assert_eq!(gj.leaves.len(), 5);
+    }
+
+    #[test]
+    fn shorter_backlog_drops_the_oldest_hours() {
+        let gj = loaded_with_backlog("[1,2,3,4,5]", 2);
+        assert_eq!(gj.log, [Some(4), Some(5)]);
+        assert_eq!(gj.leaves.len(), 2);
+    }
+
+    #[test]
+    fn new_guilds_use_the_configured_backlog() {
+        let clock = MockClock::new(3600 * 100_000);
+        let mut gj = GuildJoins::new(7, 0.5, &clock);
+        assert_eq!(gj.log.len(), 7);
+        clock.advance(3600 * 3);
+        gj.update_to_latest_hour(&clock);
+        assert_eq!(gj.log.len(), 7);
+        assert_eq!(gj.history(&clock, 100).len(), 7);
+    }
+
+    #[test]
+    fn autosave_writes_changed_guilds() {
+        let (map, store, _clock) = test_map();
+        map.add(id::GuildId(1), 3).unwrap();
+        map.add(id::GuildId(2), 1).unwrap();
+        assert_eq!(map.autosave(), 2);
+        let saved = store.saved(id::GuildId(1)).unwrap();
+        assert_eq!(saved["current"], 3);
+        // the saved JSON is read back as is
+        assert!(serde_json::from_value::<GuildJoins>(saved).is_ok());
+        assert_eq!(map.autosave(), 0);
+
+        map.add(id::GuildId(1), 1).unwrap();
+        assert_eq!(map.autosave(), 1);
+        assert_eq!(store.saved(id::GuildId(1)).unwrap()["current"], 4);
+    }
+
+    #[test]
+    fn autosave_retries_failed_guilds() {
+        let (map, store, _clock) = test_map();
+        map.add(id::GuildId(1), 3).unwrap();
+        store.failing.store(true, Ordering::Relaxed);
+        assert_eq!(map.autosave(), 0);
+        store.failing.store(false, Ordering::Relaxed);
+        assert_eq!(map.autosave(), 1);
+        assert_eq!(store.saved(id::GuildId(1)).unwrap()["current"], 3);
+    }
+
+    #[test]
+    fn guilds_are_locked_separately() {
+        let (map, _store, _clock) = test_map();
+        let (first, second) = (id::GuildId(1), id::GuildId(2));
+        map.add(first, 0).unwrap();
+        map.add(second, 0).unwrap();
+
+        let (entered_send, entered) = std::sync::mpsc::channel();
+        let (added_send, added) = std::sync::mpsc::channel();
+        std::thread::scope(|scope| {
+            let holder = scope.spawn(|| {
+                map.run(first, move |_| {
+                    entered_send.send(()).unwrap();
+                    // only returns in time if the other guild can be used meanwhile
+                    added.recv_timeout(Duration::from_secs(10))
+                })
+            });
+            entered.recv().unwrap();
+            map.add(second, 1).unwrap();
+            added_send.send(()).unwrap();
+            assert!(holder.join().unwrap().unwrap().is_ok());
+        });
+    }
+
+    #[test]
+    fn short_gaps_roll_the_current_hour_then_empty_hours() {
+        let clock = MockClock::new(3600 * 100_000);
+        let mut gj = GuildJoins::new(5, 0.5, &clock);
+        gj.add(&clock, 5);
+        clock.advance(3 * 3600);
+        gj.add(&clock, 1);
+        assert_eq!(gj.log, [None, None, Some(5), Some(0), Some(0)]);
+        assert_eq!(gj.current, 1);
+    }
+
+    #[test]
+    fn gaps_before_loading_are_unknown() {
+        let clock = MockClock::new(3600 * 100_000);
+        let mut gj = GuildJoins::new(5, 0.5, &clock);
+        gj.add(&clock, 5);
+        let json = serde_json::to_string(&gj).unwrap();
+        clock.advance(3 * 3600);
+        let loaded: GuildJoins = serde_json::from_str(&json).unwrap();
+        let gj = loaded.restore(5, 0.5, &clock, clock.now_hour());
+        assert_eq!(gj.log, [None, None, Some(5), None, None]);
+        assert_eq!(gj.offline_gap, Some((100_001, 100_003)));
+    }
+
+    #[test]
+    fn huge_gaps_complete_quickly() {
+        let clock = MockClock::new(3600 * 100_000);
+        let mut gj = GuildJoins::new(DEFAULT_BACKLOG_SIZE, 0.5, &clock);
+        gj.add(&clock, 5);
+        clock.advance(10_000 * 3600);
+        let started = Instant::now();
+        gj.add(&clock, 1);
+        assert!(started.elapsed() < Duration::from_millis(100));
+        assert_eq!(gj.current_hour, 110_000);
+        assert_eq!(gj.log.len(), DEFAULT_BACKLOG_SIZE);
+        assert!(gj.log.iter().all(|&joins| joins == Some(0)));
+        assert_eq!(gj.current, 1);
+        // the rolled hour did not vanish from the all-time peak
+        assert_eq!(gj.peak.map(|peak| peak.joins), Some(5));
+    }
+
+    /// 34 ordinary hours, and 14 busy hours that inflate the upper quartile.
+    fn outlier_heavy_history() -> Vec<u32> {
+        let mut history: Vec<_> = (0..34).map(|hour| 8 + hour % 5).collect();
+        history.extend([200; 14]);
+        history
+    }
+
+    #[test]
+    fn mad_is_robust_to_busy_hours() {
+        let quartile = ThresholdConfig::default();
+        let mad = ThresholdConfig {
+            method: DetectionMethod::Mad,
+            ..ThresholdConfig::default()
+        };
+        let stat = stat_after(&outlier_heavy_history(), 40);
+        assert_eq!(stat.median, 11.);
+        assert_eq!(stat.mad, 2.);
+        assert!(stat.uq >= 200.);
+        assert!(stat
+            .to_string()
+            .contains("Median absolute deviation: 2.000\n"));
+        assert_eq!(stat.severity(&quartile), Severity::Normal);
+        assert_eq!(stat.severity(&mad), Severity::Elevated);
+
+        // without busy hours both detect the spike
+        let history: Vec<_> = (0..48).map(|hour| 8 + hour % 5).collect();
+        let stat = stat_after(&history, 40);
+        assert_ne!(stat.severity(&quartile), Severity::Normal);
+        assert_ne!(stat.severity(&mad), Severity::Normal);
+    }
+
+    #[test]
+    fn stat_json_round_trip() {
+        let stat = stat_after(&[2, 4, 4, 4, 5, 5, 7, 9], 12);
+        let json = serde_json::to_value(&stat).unwrap();
+        for (key, value) in &[
+            ("mean_joins_per_hour", 5.),
+            ("std_deviation", 2.),
+            ("median", 4.5),
+            ("upper_quartile", 5.5),
+            ("lower_quartile", 4.),
+            ("samples", 8.),
+            ("current_hour_joins", 12.),
+        ] {
+            assert_eq!(json[key].as_f64(), Some(*value), "{}", key);
+        }
+        let read: Stat = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&read).unwrap(), json);
+    }
+
+    #[test]
+    fn backwards_clock_keeps_counting_into_the_current_hour() {
+        let clock = MockClock::new(3600 * 100_000);
+        let mut gj = GuildJoins::new(4, 0.5, &clock);
+        gj.add(&clock, 2);
+        clock.set(3600 * 99_998);
+        gj.add(&clock, 3);
+        gj.stat(&clock, PercentileMethod::Linear);
+        assert_eq!((gj.current_hour, gj.current), (100_000, 5));
+        assert_eq!(gj.log, [None; 4]);
+
+        // once the clock catches up, the hour completes normally
+        clock.set(3600 * 100_001);
+        gj.add(&clock, 1);
+        assert_eq!(gj.log, [None, None, None, Some(5)]);
+        assert_eq!((gj.current_hour, gj.current), (100_001, 1));
+    }
+
+    #[test]
+    fn stat_covers_only_the_observed_hours() {
+        let clock = MockClock::new(3600 * 100_000);
+        let mut gj = GuildJoins::new(6, 0.5, &clock);
+        for joins in [2, 4, 6] {
+            gj.add(&clock, joins);
+            clock.advance(3600);
+        }
+        let stat = gj.stat(&clock, PercentileMethod::Linear);
+        assert_eq!(gj.log, [None, None, None, Some(2), Some(4), Some(6)]);
+        assert_eq!((stat.n, stat.current), (3, 0));
+        assert_eq!((stat.min, stat.median, stat.max), (2., 4., 6.));
+
+        // an hour without joins is known to be empty
+        clock.advance(3600);
+        let stat = gj.stat(&clock, PercentileMethod::Linear);
+        assert_eq!(gj.log, [None, None, Some(2), Some(4), Some(6), Some(0)]);
+        assert_eq!((stat.n, stat.min), (4, 0.));
+    }
+
+    #[test]
+    fn rank_inverts_the_linear_percentile() {
+        let data = [2., 4., 4., 8., 10.];
+        assert_eq!(get_rank(&data, 1.), 0.);
+        assert_eq!(get_rank(&data, 2.), 0.);
+        assert_eq!(get_rank(&data, 3.), 0.125);
+        assert_eq!(get_rank(&data, 4.), 0.25);
+        assert_eq!(get_rank(&data, 6.), 0.625);
+        assert_eq!(get_rank(&data, 10.), 1.);
+        assert_eq!(get_rank(&data, 11.), 1.);
+        for ratio in [0.125, 0.25, 0.625] {
+            let value = get_percentile(&data, ratio, PercentileMethod::Linear);
+            assert_eq!(get_rank(&data, value), ratio);
+        }
+    }
+
+    #[test]
+    fn rank_without_history_is_unknown() {
+        let clock = MockClock::new(3600 * 100_000);
+        let mut gj = GuildJoins::new(6, 0.5, &clock);
+        gj.add(&clock, 3);
+        assert_eq!(gj.rank(&clock), (3, None));
+        clock.advance(3600);
+        gj.add(&clock, 5);
+        assert_eq!(gj.rank(&clock), (5, Some(1.)));
+    }
+
+    #[test]
+    fn evicted_guilds_are_saved_and_reloaded() {
+        let (map, store, _clock) = test_map_with(Some(2), Duration::ZERO);
+        let guilds = [id::GuildId(1), id::GuildId(2), id::GuildId(3)];
+        map.add(guilds[0], 3).unwrap();
+        map.add(guilds[1], 1).unwrap();
+        // nothing is saved before an hour completes
+        assert!(store.saved(guilds[0]).is_none());
+
+        map.add(guilds[2], 1).unwrap();
+        assert_eq!(store.saved(guilds[0]).unwrap()["current"], 3);
+        assert!(store.saved(guilds[1]).is_none());
+
+        // reloading evicts the next least recently used guild
+        let stat = map.add(guilds[0], 2).unwrap();
+        assert_eq!(stat.current, 5);
+        assert_eq!(store.saved(guilds[1]).unwrap()["current"], 1);
+    }
+
+    #[test]
+    fn guilds_that_cannot_be_saved_are_not_evicted() {
+        let (map, store, _clock) = test_map_with(Some(1), Duration::ZERO);
+        map.add(id::GuildId(1), 3).unwrap();
+        store.failing.store(true, Ordering::Relaxed);
+        map.add(id::GuildId(2), 1).unwrap();
+        store.failing.store(false, Ordering::Relaxed);
+        assert_eq!(map.add(id::GuildId(1), 0).unwrap().current, 3);
+    }
+
+    #[test]
+    fn simulate_counts_the_flagged_hours() {
+        let threshold = ThresholdConfig {
+            min_samples: 4,
+            ..ThresholdConfig::default()
+        };
+        let log = [
+            Some(4),
+            Some(4),
+            Some(4),
+            Some(4),
+            // above 4 * 2 + 5
+            Some(14),
+            // at the limit
+            Some(13),
+            None,
+            // above 10.75 * 2 + 5
+            Some(30),
+            Some(9),
+        ];
+        let alpha = ewma_alpha(168.);
+        assert_eq!(
+            simulate(&log, &threshold, alpha, PercentileMethod::Linear),
+            (2, 8)
+        );
+        // too few hours recorded before the busy ones
+        let threshold = ThresholdConfig {
+            min_samples: 8,
+            ..threshold
+        };
+        assert_eq!(
+            simulate(&log, &threshold, alpha, PercentileMethod::Linear),
+            (0, 8)
+        );
+        // quiet hours below the floor are never flagged
+        let threshold = ThresholdConfig {
+            min_samples: 4,
+            min_floor: 30,
+            ..threshold
+        };
+        assert_eq!(
+            simulate(&log, &threshold, alpha, PercentileMethod::Linear),
+            (0, 8)
+        );
+    }
+
+    #[test]
+    fn rapid_rollovers_are_saved_once_per_interval() {
+        let (map, store, clock) = test_map_with(None, Duration::from_secs(3600));
+        let guild = id::GuildId(1);
+        for _ in 0..1000 {
+            map.add(guild, 1).unwrap();
+            clock.advance(3600);
+        }
+        assert_eq!(store.saves.load(Ordering::Relaxed), 1);
+
+        // the coalesced changes are still saved by the next autosave
+        assert_eq!(map.autosave(), 1);
+        assert_eq!(store.saves.load(Ordering::Relaxed), 2);
+        assert_eq!(map.autosave(), 0);
+    }
+
+    #[test]
+    fn ewma_halves_the_weight_of_an_hour_per_half_life() {
+        let alpha = ewma_alpha(168.);
+        let weight = (0..168).fold(1., |weight, _| weight * (1. - alpha));
+        assert!((weight - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn ewma_converges_to_a_constant_input() {
+        let clock = MockClock::new(3600 * 100_000);
+        let mut gj = GuildJoins::new(DEFAULT_BACKLOG_SIZE, ewma_alpha(24.), &clock);
+        gj.add(&clock, 100);
+        for _ in 0..500 {
+            clock.advance(3600);
+            gj.add(&clock, 10);
+        }
+        let ewma = gj.stat(&clock, PercentileMethod::Linear).ewma;
+        assert!((ewma - 10.).abs() < 0.01, "{}", ewma);
+    }
+
+    #[test]
+    fn raids_are_recorded_when_they_start() {
+        let (map, store, clock) = test_map();
+        let guild = id::GuildId(1);
+        let stat = map.add(guild, 20).unwrap();
+        map.record_raid(guild, true, stat).unwrap();
+        clock.advance(60);
+        let stat = map.add(guild, 30).unwrap();
+        map.record_raid(guild, false, stat).unwrap();
+
+        let raids = map.raids(guild, 10).unwrap();
+        assert_eq!(raids.len(), 1);
+        assert_eq!(raids[0].timestamp, 3600 * 100_000 + 60);
+        assert_eq!((raids[0].peak, raids[0].stat.current), (50, 20));
+        assert_eq!(raids[0].kind, RaidKind::Joins);
+
+        for _ in 0..MAX_RAID_EVENTS {
+            let stat = map.add(guild, 1).unwrap();
+            map.record_raid(guild, true, stat).unwrap();
+        }
+        let raids = map.raids(guild, MAX_RAID_EVENTS + 1).unwrap();
+        assert_eq!(raids.len(), MAX_RAID_EVENTS);
+        assert_eq!(raids[0].peak, 51);
+        assert_eq!(map.raids(guild, 2).unwrap()[1].peak, 100);
+
+        // persisted with the guild
+        map.autosave();
+        let saved = &store.saved(guild).unwrap()["raids"];
+        assert_eq!(saved.as_array().unwrap().len(), MAX_RAID_EVENTS);
+    }
+
+    #[test]
+    fn detection_starts_at_min_samples() {
+        let threshold = ThresholdConfig::default();
+        let quiet = |hours| stat_after(&vec![1; hours], 100).severity(&threshold);
+        assert_eq!(quiet(0), Severity::Normal);
+        assert_eq!(quiet(47), Severity::Normal);
+        assert_eq!(quiet(48), Severity::Critical);
+        assert_eq!(quiet(49), Severity::Critical);
+    }
+
+    #[test]
+    fn unknown_hours_are_not_samples() {
+        let threshold = ThresholdConfig {
+            min_samples: 3,
+            ..ThresholdConfig::default()
+        };
+        let clock = MockClock::new(3600 * 100_000);
+        let mut gj = GuildJoins::new(DEFAULT_BACKLOG_SIZE, 0.5, &clock);
+        for _ in 0..2 {
+            gj.add(&clock, 1);
+            clock.advance(3600);
+        }
+        gj.add(&clock, 100);
+        let stat = gj.stat(&clock, PercentileMethod::Linear);
+        assert_eq!(stat.n, 2);
+        assert_eq!(stat.severity(&threshold), Severity::Normal);
+
+        clock.advance(3600);
+        gj.add(&clock, 100);
+        let stat = gj.stat(&clock, PercentileMethod::Linear);
+        assert_eq!(stat.n, 3);
+        assert_ne!(stat.severity(&threshold), Severity::Normal);
+    }
+
+    #[test]
+    fn csv_leaves_unknown_hours_empty() {
+        let log = VecDeque::from(vec![None, Some(0), Some(7)]);
+        assert_eq!(
+            log_to_csv(100_000, &log),
+            "hour_start,joins\n359989200,\n359992800,0\n359996400,7\n"
+        );
+        assert_eq!(log_to_csv(100_000, &VecDeque::new()), "hour_start,joins\n");
+    }
+
+    #[test]
+    fn percentile_methods_agree_at_the_edges() {
+        let data = [1., 2., 3., 10.];
+        let at = |ratio| {
+            [
+                PercentileMethod::Linear,
+                PercentileMethod::NearestRank,
+                PercentileMethod::Lower,
+            ]
+            .map(|method| get_percentile(&data, ratio, method))
+        };
+        assert_eq!(at(0.), [1., 1., 1.]);
+        assert_eq!(at(1.), [10., 10., 10.]);
+        assert_eq!(at(0.5), [2.5, 2., 2.]);
+        let [linear, nearest, lower] = at(0.9);
+        assert!((linear - 7.9).abs() < 1e-9, "{}", linear);
+        assert_eq!((nearest, lower), (10., 3.));
+
+        // only the linear method produces counts that are not in the data
+        for method in [PercentileMethod::NearestRank, PercentileMethod::Lower] {
+            let stat = compute_stat(&[1, 2, 3, 10], 0, method);
+            assert_eq!((stat.min, stat.median, stat.max), (1., 2., 10.));
+        }
+    }
+
+    #[test]
+    fn severity_follows_the_ratio_to_the_limit() {
+        let history = [10; 48];
+        let threshold = ThresholdConfig::default();
+        // the limit is 10 * 2 + 5
+        for (current, severity) in [
+            (25, Severity::Normal),
+            (26, Severity::Elevated),
+            (50, Severity::Elevated),
+            (75, Severity::Elevated),
+            (76, Severity::Critical),
+            (250, Severity::Critical),
+        ] {
+            let stat = stat_after(&history, current);
+            assert_eq!(stat.severity(&threshold), severity, "{} joins", current);
+        }
+
+        let threshold = ThresholdConfig {
+            critical_factor: 2.,
+            ..threshold
+        };
+        assert_eq!(
+            stat_after(&history, 50).severity(&threshold),
+            Severity::Elevated
+        );
+        assert_eq!(
+            stat_after(&history, 51).severity(&threshold),
+            Severity::Critical
+        );
+    }
+
+    #[test]
+    fn plain_logs_are_saved_as_runs() {
+        let clock = MockClock::new(3600 * 100_000 + 60);
+        let json = r#"{"current_hour":100000,"log":[null,null,0,0,0,3],"current":0}"#;
+        let gj: GuildJoins = serde_json::from_str(json).unwrap();
+        let gj = gj.restore(6, 0.5, &clock, 100_000);
+        let saved = serde_json::to_value(&gj).unwrap();
+        assert_eq!(
+            saved["log"],
+            serde_json::json!({"runs": [[null, 2], [0, 3], [3, 1]]})
+        );
+        let read: GuildJoins = serde_json::from_str(&saved.to_string()).unwrap();
+        assert_eq!(read.log, gj.log);
+    }
+
+    #[test]
+    fn unversioned_files_are_upgraded() {
+        let clock = MockClock::new(3600 * 100_000 + 60);
+        let json = r#"{"current_hour":100000,"log":[1,null,3,4],"current":0}"#;
+        let gj: GuildJoins = serde_json::from_str(json).unwrap();
+        assert_eq!(gj.version, 0);
+        let gj = gj.restore(4, 0.5, &clock, 100_000);
+        assert_eq!(gj.version, CURRENT_VERSION);
+        // the moving average is seeded from the recorded hours
+        assert_eq!(gj.ewma, Some(3.));
+        let saved = serde_json::to_value(&gj).unwrap();
+        assert_eq!(saved["version"], CURRENT_VERSION);
+    }
+
+    #[test]
+    fn current_files_are_not_rewritten() {
+        let gj = loaded_with_backlog("[1,2,3]", 3);
+        assert_eq!(gj.version, CURRENT_VERSION);
+        assert!(!gj.dirty);
+    }
+
+    #[test]
+    fn newer_files_keep_their_version() {
+        let clock = MockClock::new(3600 * 100_000 + 60);
+        let json = r#"{"version":99,"current_hour":100000,"log":[1,2],"current":0}"#;
+        let gj: GuildJoins = serde_json::from_str(json).unwrap();
+        let gj = gj.restore(2, 0.5, &clock, 100_000);
+        assert_eq!(gj.version, 99);
+        assert_eq!(gj.log, [Some(1), Some(2)]);
+    }
+
+    #[test]
+    fn window_slices_hours_ago() {
+        let log = VecDeque::from(vec![Some(1), None, Some(5), Some(2), Some(0), Some(4)]);
+        let summary = |from, to| {
+            window_stat(100, &log, from, to)
+                .map(|w| (w.start_hour, w.end_hour, w.total, w.peak, w.mean, w.n))
+        };
+        // the last hour of the log started 1 hour ago
+        assert_eq!(summary(1, 0), Some((99, 100, 4, Some((99, 4)), 4., 1)));
+        assert_eq!(summary(3, 1), Some((97, 99, 2, Some((97, 2)), 1., 2)));
+        assert_eq!(summary(6, 0), Some((94, 100, 12, Some((96, 5)), 2.4, 5)));
+        // hours without joins are known, unlike unknown hours
+        assert_eq!(summary(2, 1), Some((98, 99, 0, Some((98, 0)), 0., 1)));
+        assert_eq!(summary(5, 4), Some((95, 96, 0, None, 0., 0)));
+    }
+
+    #[test]
+    fn window_beyond_the_log_is_unavailable() {
+        let log = VecDeque::from(vec![Some(1); 48]);
+        assert!(window_stat(100, &log, 48, 24).is_some());
+        assert!(window_stat(100, &log, 49, 24).is_none());
+        assert!(window_stat(100, &log, 24, 24).is_none());
+        assert!(window_stat(100, &log, 12, 24).is_none());
+    }
+
+    fn json_map(name: &str) -> (GuildJoinsMap, std::path::PathBuf) {
+        let dir = std::env::temp_dir().join(format!(
+            "boredphoton-{}-{}-{}",
+            name,
+            std::process::id(),
+            SystemClock.now_secs()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let map = GuildJoinsMap::new(
+            Box::new(JsonStore::new(dir.clone())),
+            Box::new(MockClock::new(3600 * 100_000 + 60)),
+            24,
+            0.1,
+            PercentileMethod::Linear,
+            None,
+            Duration::ZERO,
+        );
+        (map, dir)
+    }
+
+    #[test]
+    fn missing_files_start_afresh() {
+        let (map, dir) = json_map("missing");
+        let stat = map.add(id::GuildId(1), 2).unwrap();
+        assert_eq!((stat.n, stat.current), (0, 2));
+        assert!(!dir.join("1.corrupt.json").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn malformed_files_are_moved_aside() {
+        let (map, dir) = json_map("malformed");
+        fs::write(dir.join("1.json"), r#"{"current_hour":"#).unwrap();
+        fs::write(dir.join("2.json"), r#"{"current_hour":"soon"}"#).unwrap();
+
+        for guild in [id::GuildId(1), id::GuildId(2)] {
+            let stat = map.add(guild, 2).unwrap();
+            assert_eq!((stat.n, stat.current), (0, 2));
+        }
+        assert_eq!(
+            fs::read_to_string(dir.join("1.corrupt.json")).unwrap(),
+            r#"{"current_hour":"#
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("2.corrupt.json")).unwrap(),
+            r#"{"current_hour":"soon"}"#
+        );
+        // the fresh data is saved in place of the corrupt data
+        map.save().unwrap();
+        let saved = fs::read_to_string(dir.join("1.json")).unwrap();
+        assert!(serde_json::from_str::<GuildJoins>(&saved).is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn decayed_baseline_follows_recent_quiet_hours() {
+        let mut log = vec![Some(50); 600];
+        log.extend(vec![Some(2); 72]);
+        let alpha = ewma_alpha(24.);
+        // the last 72 hours carry 7/8 of the weight
+        assert_eq!(decayed_percentile(&log, alpha, 0.75), 2.);
+        let history: Vec<u32> = log.iter().flatten().copied().collect();
+        let mut stat = stat_after(&history, 20);
+        assert_eq!(stat.uq, 50.);
+
+        stat.decayed_uq = decayed_percentile(&log, alpha, 0.75);
+        let decayed = ThresholdConfig {
+            method: DetectionMethod::Decayed,
+            ..ThresholdConfig::default()
+        };
+        assert_eq!(stat.severity(&ThresholdConfig::default()), Severity::Normal);
+        assert_eq!(stat.severity(&decayed), Severity::Elevated);
+    }
+
+    #[test]
+    fn decayed_percentile_without_decay_is_unweighted() {
+        let log = [Some(4), None, Some(1), Some(3), Some(2)];
+        assert_eq!(decayed_percentile(&log, 0., 0.5), 2.);
+        assert_eq!(decayed_percentile(&log, 0., 0.75), 3.);
+        assert_eq!(decayed_percentile(&log, 0., 1.), 4.);
+        assert_eq!(decayed_percentile(&[None, None], 0.5, 0.75), 0.);
+    }
+
+    /// A guild with 48 hours of 2 joins each, at the start of the next hour.
+    fn quiet_guild(clock: &MockClock) -> GuildJoins {
+        let mut gj = GuildJoins::new(DEFAULT_BACKLOG_SIZE, 0.5, clock);
+        for _ in 0..48 {
+            gj.add(clock, 2);
+            clock.advance(3600);
+        }
+        gj
+    }
+
+    #[test]
+    fn join_spike_then_leave_spike_is_a_nuke() {
+        let threshold = ThresholdConfig::default();
+        let clock = MockClock::new(3600 * 100_000);
+        let mut gj = quiet_guild(&clock);
+        for _ in 0..3 {
+            gj.add(&clock, 14);
+            clock.advance(60);
+        }
+        let stat = gj.stat(&clock, PercentileMethod::Linear);
+        assert!(!stat.is_nuke(&threshold));
+
+        clock.advance(10 * 60);
+        gj.add_leave(&clock, 30);
+        let stat = gj.stat(&clock, PercentileMethod::Linear);
+        assert_eq!((stat.nuke_window.joins, stat.nuke_window.leaves), (42, 30));
+        assert!(stat.is_nuke(&threshold));
+
+        // the spike leaves the window
+        clock.advance(30 * 60);
+        let stat = gj.stat(&clock, PercentileMethod::Linear);
+        assert!(!stat.is_nuke(&threshold));
+    }
+
+    #[test]
+    fn leaves_before_joins_are_not_a_nuke() {
+        let threshold = ThresholdConfig::default();
+        let clock = MockClock::new(3600 * 100_000);
+        let mut gj = quiet_guild(&clock);
+        gj.add_leave(&clock, 30);
+        clock.advance(10 * 60);
+        gj.add(&clock, 42);
+        let stat = gj.stat(&clock, PercentileMethod::Linear);
+        assert!(!stat.nuke_window.leaves_after_joins);
+        assert!(!stat.is_nuke(&threshold));
+
+        // too few leaves after a spike
+        let clock = MockClock::new(3600 * 100_000);
+        let mut gj = quiet_guild(&clock);
+        gj.add(&clock, 42);
+        clock.advance(10 * 60);
+        gj.add_leave(&clock, 20);
+        assert!(!gj
+            .stat(&clock, PercentileMethod::Linear)
+            .is_nuke(&threshold));
+    }
+
+    #[test]
+    fn nukes_relabel_the_latest_raid() {
+        let (map, _store, clock) = test_map();
+        let guild = id::GuildId(1);
+        let stat = map.add(guild, 40).unwrap();
+        map.record_raid(guild, true, stat.clone()).unwrap();
+        clock.advance(10 * 60);
+        map.record_nuke(guild, stat.clone()).unwrap();
+        let raids = map.raids(guild, 10).unwrap();
+        assert_eq!(raids.len(), 1);
+        assert_eq!(raids[0].kind, RaidKind::MassJoinLeave);
+        assert_eq!(raids[0].kind.to_string(), "mass join+leave pattern");
+
+        // a nuke long after the raid is a separate event
+        clock.advance(NUKE_WINDOW_MINUTES as u64 * 60 + 60);
+        map.record_nuke(guild, stat).unwrap();
+        let raids = map.raids(guild, 10).unwrap();
+        assert_eq!(raids.len(), 2);
+        assert_eq!(raids[1].kind, RaidKind::MassJoinLeave);
+    }
+
+    #[test]
+    fn idle_hours_are_filled_alike_whether_or_not_loaded() {
+        let clock = MockClock::new(3600 * 100_000);
+        let started = clock.now_hour();
+        let mut running = GuildJoins::new(6, 0.5, &clock);
+        running.add(&clock, 4);
+        let saved = serde_json::to_string(&running).unwrap();
+
+        clock.advance(3 * 3600);
+        running.update_to_latest_hour(&clock);
+        // unloaded while this process kept running, then loaded again
+        let unloaded: GuildJoins = serde_json::from_str(&saved).unwrap();
+        let mut reloaded = unloaded.restore(6, 0.5, &clock, started);
+        assert_eq!(reloaded.log, running.log);
+        assert_eq!(reloaded.log, [None, None, None, Some(4), Some(0), Some(0)]);
+        let method = PercentileMethod::Linear;
+        assert_eq!(
+            running.stat(&clock, method).to_string(),
+            reloaded.stat(&clock, method).to_string()
+        );
+
+        // hours before this process started were not observed by anyone
+        let restarted: GuildJoins = serde_json::from_str(&saved).unwrap();
+        let restarted = restarted.restore(6, 0.5, &clock, clock.now_hour());
+        assert_eq!(restarted.log, [None, None, None, Some(4), None, None]);
+    }
+
+    #[test]
+    fn concurrent_adds_are_not_lost() {
+        let (map, store, clock) = test_map();
+        let guilds = [id::GuildId(1), id::GuildId(2)];
+        std::thread::scope(|scope| {
+            for thread in 0..8 {
+                let (map, clock) = (&map, &clock);
+                scope.spawn(move || {
+                    for i in 0..500 {
+                        map.add(guilds[thread % 2], 1).unwrap();
+                        if thread == 0 && i % 100 == 99 {
+                            // rollovers save while the other threads add
+                            clock.advance(3600);
+                        }
+                    }
+                });
+            }
+            scope.spawn(|| {
+                for _ in 0..50 {
+                    map.autosave();
+                }
+            });
+            scope.spawn(|| {
+                for _ in 0..20 {
+                    map.backup(3).unwrap();
+                }
+            });
+        });
+        assert_eq!(store.backups.lock().unwrap().len(), 3);
+
+        for guild in guilds {
+            let total = map.add(guild, 0).unwrap().current
+                + map
+                    .history(guild, 24)
+                    .unwrap()
+                    .iter()
+                    .flatten()
+                    .sum::<u32>();
+            assert_eq!(total, 2000);
+        }
+        // the saved data is the latest snapshot, not an older one written later
+        map.autosave();
+        let saved = store.saved(guilds[0]).unwrap();
+        let loaded: GuildJoins = serde_json::from_value(saved).unwrap();
+        let saved_total = loaded.current + loaded.log.iter().flatten().sum::<u32>();
+        assert_eq!(saved_total, 2000);
+    }
+
+    #[test]
+    fn timeline_lists_the_newest_hour_first() {
+        let log = [Some(3), None, Some(0)];
+        assert_eq!(
+            timeline(100_000, &log),
+            "<t:359996400:f>: 0\n\
+             <t:359992800:f>: no data (offline)\n\
+             <t:359989200:f>: 3\n"
+        );
+        assert_eq!(timeline(100_000, &[]), "");
+    }
+
+    #[test]
+    fn recent_returns_the_last_completed_hours() {
+        let (map, _store, clock) = test_map();
+        let guild = id::GuildId(1);
+        for joins in 1..=3 {
+            map.add(guild, joins).unwrap();
+            clock.advance(3600);
+        }
+        map.add(guild, 9).unwrap();
+        let (current_hour, log) = map.recent(guild, 2).unwrap();
+        assert_eq!((current_hour, log), (100_003, vec![Some(2), Some(3)]));
+        let (_, log) = map.recent(guild, 100).unwrap();
+        assert_eq!(log.len(), 24);
+    }
+
+    #[test]
+    fn offline_gaps_are_reported_once() {
+        let (map, store, clock) = test_map();
+        let guild = id::GuildId(1);
+        map.add(guild, 1).unwrap();
+        map.autosave();
+
+        // the process restarts 5 hours later
+        clock.advance(5 * 3600);
+        let restarted = GuildJoinsMap::new(
+            Box::new(store.clone()),
+            Box::new(std::sync::Arc::clone(&clock)),
+            24,
+            0.1,
+            PercentileMethod::Linear,
+            None,
+            Duration::ZERO,
+        );
+        assert_eq!(
+            restarted.take_offline_gap(guild).unwrap(),
+            Some((100_001, 100_005))
+        );
+        assert_eq!(restarted.take_offline_gap(guild).unwrap(), None);
+        // guilds loaded while running have no gap
+        assert_eq!(map.take_offline_gap(guild).unwrap(), None);
+    }
+
+    #[test]
+    fn peak_updates_only_on_new_maxima() {
+        let clock = MockClock::new(3600 * 100_000);
+        let mut gj = GuildJoins::new(24, 0.5, &clock);
+        let mut peaks = Vec::new();
+        for joins in [3, 7, 5, 7, 9, 0] {
+            gj.add(&clock, joins);
+            clock.advance(3600);
+            gj.update_to_latest_hour(&clock);
+            peaks.push(gj.peak.map(|peak| (peak.hour, peak.joins)));
+        }
+        assert_eq!(
+            peaks,
+            [
+                Some((100_000, 3)),
+                Some((100_001, 7)),
+                Some((100_001, 7)),
+                // ties keep the earlier hour
+                Some((100_001, 7)),
+                Some((100_004, 9)),
+                Some((100_004, 9)),
+            ]
+        );
+        // the current hour is not a peak until it completes
+        gj.add(&clock, 50);
+        let stat = gj.stat(&clock, PercentileMethod::Linear);
+        assert_eq!(stat.peak.map(|peak| peak.joins), Some(9));
+        assert!(stat
+            .to_string()
+            .contains("The busiest hour ever had 9 joins at <t:360014400:f>."));
+    }
+
+    #[test]
+    fn files_without_a_peak_start_tracking_it() {
+        let gj = loaded_with_backlog("[40,50]", 2);
+        assert!(gj.peak.is_none());
+    }
+
+    #[test]
+    fn projection_extrapolates_the_elapsed_minutes() {
+        assert_eq!(elapsed_minutes(3600 * 100_000), 1);
+        assert_eq!(elapsed_minutes(3600 * 100_000 + 59), 1);
+        assert_eq!(elapsed_minutes(3600 * 100_000 + 14 * 60), 15);
+        assert_eq!(elapsed_minutes(3600 * 100_000 + 3599), 60);
+
+        assert_eq!(project_hour(10, 15), 40.);
+        assert_eq!(project_hour(10, 30), 20.);
+        assert_eq!(project_hour(10, 60), 10.);
+        // out of range minutes are clamped
+        assert_eq!(project_hour(10, 0), 600.);
+        assert_eq!(project_hour(10, 90), 10.);
+    }
+
+    #[test]
+    fn early_projection_waits_for_enough_minutes() {
+        let history = [10; 48];
+        let threshold = ThresholdConfig {
+            // keep the minute burst detector out of the way
+            minute_multiplier: 1000.,
+            ..ThresholdConfig::default()
+        };
+        // the limit is 10 * 2 + 5, and projections above 25 * 2 are abnormal
+        let at = |current, elapsed_minutes| {
+            let stat = Stat {
+                elapsed_minutes,
+                ..stat_after(&history, current)
+            };
+            stat.severity(&threshold)
+        };
+        assert_eq!(at(20, 9), Severity::Normal);
+        assert_eq!(at(20, 10), Severity::Elevated);
+        assert_eq!(at(20, 24), Severity::Normal);
+        assert_eq!(at(12, 15), Severity::Normal);
+        assert_eq!(at(13, 15), Severity::Elevated);
+    }
+
+    #[test]
+    fn active_expectations_raise_the_limit_until_they_expire() {
+        let threshold = ThresholdConfig::default();
+        let clock = MockClock::new(3600 * 100_000);
+        let mut gj = quiet_guild(&clock);
+        gj.expectation = Some(Expectation {
+            joins: 300,
+            until: clock.now_secs() + 1800,
+        });
+        gj.add(&clock, 20);
+        let stat = gj.stat(&clock, PercentileMethod::Linear);
+        assert_eq!(stat.expected, 300);
+        assert_eq!(stat.severity(&threshold), Severity::Normal);
+
+        clock.advance(1800);
+        let stat = gj.stat(&clock, PercentileMethod::Linear);
+        assert_eq!(stat.expected, 0);
+        assert_ne!(stat.severity(&threshold), Severity::Normal);
+        assert!(gj.expectation.is_none());
+    }
+
+    #[test]
+    fn without_expectations_the_limit_is_unchanged() {
+        let threshold = ThresholdConfig::default();
+        let clock = MockClock::new(3600 * 100_000);
+        let mut gj = quiet_guild(&clock);
+        gj.add(&clock, 20);
+        let stat = gj.stat(&clock, PercentileMethod::Linear);
+        assert_eq!(stat.expected, 0);
+        assert_ne!(stat.severity(&threshold), Severity::Normal);
+    }
+
+    #[test]
+    fn repeated_save_failures_warn_once() {
+        let (map, store, _clock) = test_map();
+        store.failing.store(true, Ordering::Relaxed);
+        for _ in 0..DEGRADED_AFTER_FAILURES - 1 {
+            map.add(id::GuildId(1), 1).unwrap();
+            assert_eq!(map.autosave(), 0);
+        }
+        assert_eq!(map.take_degraded_change(), None);
+        for _ in 0..3 {
+            map.add(id::GuildId(1), 1).unwrap();
+            assert_eq!(map.autosave(), 0);
+        }
+        assert_eq!(map.take_degraded_change(), Some(true));
+        assert_eq!(map.take_degraded_change(), None);
+
+        // joins are still counted in memory
+        store.failing.store(false, Ordering::Relaxed);
+        assert_eq!(map.autosave(), 1);
+        let count = DEGRADED_AFTER_FAILURES + 2;
+        assert_eq!(store.saved(id::GuildId(1)).unwrap()["current"], count);
+        assert_eq!(map.take_degraded_change(), Some(false));
+        assert_eq!(map.take_degraded_change(), None);
+    }
+
+    #[test]
+    fn excluded_bots_only_count_as_bots() {
+        let (map, _store, _clock) = test_map();
+        let stat = map.add_bot(id::GuildId(1), 0).unwrap();
+        assert_eq!((stat.current, stat.bots), (0, 1));
+        // bots that are not excluded count as joins too
+        let stat = map.add_bot(id::GuildId(1), 1).unwrap();
+        assert_eq!((stat.current, stat.bots), (1, 2));
+        let stat = map.add(id::GuildId(1), 1).unwrap();
+        assert_eq!((stat.current, stat.bots), (2, 2));
+    }
+
+    #[test]
+    fn seeded_guilds_have_a_baseline_immediately() {
+        let (map, _store, clock) = test_map();
+        // the whole backlog of the test map
+        let threshold = ThresholdConfig {
+            min_samples: 24,
+            ..ThresholdConfig::default()
+        };
+        let stat = map.add(id::GuildId(1), 5).unwrap();
+        assert_eq!(stat.n, 0);
+
+        assert_eq!(map.seed(id::GuildId(1), 10).unwrap(), 24);
+        let stat = map.add(id::GuildId(1), 0).unwrap();
+        assert_eq!((stat.n, stat.seeded), (24, 24));
+        assert_eq!(stat.uq, 10.);
+        assert_eq!(stat.severity(&threshold), Severity::Normal);
+        let stat = map.add(id::GuildId(1), 30).unwrap();
+        assert_ne!(stat.severity(&threshold), Severity::Normal);
+
+        // recorded hours replace the seeded ones
+        clock.advance(3600);
+        let stat = map.add(id::GuildId(1), 0).unwrap();
+        assert_eq!((stat.n, stat.seeded), (24, 23));
+    }
+
+    #[test]
+    fn only_unloaded_guilds_idle_for_the_whole_backlog_are_pruned() {
+        let (map, store, clock) = test_map();
+        map.add(id::GuildId(5), 3).unwrap();
+        map.save().unwrap();
+        // the joins of guild 5 leave the backlog
+        clock.advance(25 * 3600);
+        map.add(id::GuildId(2), 3).unwrap();
+        for guild in [1, 3, 4] {
+            map.add(id::GuildId(guild), 0).unwrap();
+        }
+        map.save().unwrap();
+
+        let restarted = GuildJoinsMap::new(
+            Box::new(store.clone()),
+            Box::new(std::sync::Arc::clone(&clock)),
+            24,
+            0.1,
+            PercentileMethod::Linear,
+            None,
+            Duration::ZERO,
+        );
+        restarted.prime(id::GuildId(4)).unwrap();
+        let mut pruned = restarted
+            .prune_idle(|guild| guild == id::GuildId(3))
+            .unwrap();
+        pruned.sort();
+        assert_eq!(pruned, [id::GuildId(1), id::GuildId(5)]);
+        for (guild, kept) in [(1, false), (2, true), (3, true), (4, true), (5, false)] {
+            assert_eq!(store.saved(id::GuildId(guild)).is_some(), kept);
+        }
+    }
+
+    #[test]
+    fn deleted_guilds_are_not_saved_again() {
+        let (map, store, _clock) = test_map();
+        map.add(id::GuildId(1), 3).unwrap();
+        map.save().unwrap();
+        map.delete(id::GuildId(1)).unwrap();
+        assert!(store.saved(id::GuildId(1)).is_none());
+        map.save().unwrap();
+        assert_eq!(map.autosave(), 0);
+        assert!(store.saved(id::GuildId(1)).is_none());
+
+        // the guild starts afresh if the bot is added back
+        let stat = map.add(id::GuildId(1), 0).unwrap();
+        assert_eq!(stat.current, 0);
+    }
+
+    #[test]
+    fn fleet_outliers_stand_out_from_the_other_guilds() {
+        let joins: Vec<_> = [2, 3, 80, 3, 4, 4, 5, 6]
+            .iter()
+            .enumerate()
+            .map(|(guild, &current)| (id::GuildId(guild as u64 + 1), current))
+            .collect();
+        let fleet = fleet_stat(&joins, PercentileMethod::Linear);
+        assert_eq!(fleet.guilds, 8);
+        assert_eq!((fleet.lq, fleet.median, fleet.uq), (3., 4., 5.25));
+        assert_eq!(fleet.max, 80.);
+        // small fleets are not flagged below the minimum
+        assert_eq!(fleet.fence, f64::from(FLEET_OUTLIER_MIN_JOINS));
+        assert_eq!(fleet.outliers, [(id::GuildId(3), 80)]);
+
+        let quiet: Vec<_> = joins
+            .iter()
+            .filter(|&&(_, current)| current < 80)
+            .copied()
+            .collect();
+        assert!(fleet_stat(&quiet, PercentileMethod::Linear)
+            .outliers
+            .is_empty());
+    }
 }
@@ -0,0 +1,54 @@
+//! Detection of abnormal join rates in Discord servers.
+//!
+//! The bot binary is built on these modules, which can also be used on their own,
+//! e.g. to run the detection on joins observed by another bot:
+//!
+//! ```
+//! use boredphoton::clock::Clock;
+//! use boredphoton::joins::{GuildJoins, PercentileMethod, Severity, ThresholdConfig};
+//!
+//! struct FixedClock(u64);
+//!
+//! impl Clock for FixedClock {
+//!     fn now_secs(&self) -> u64 {
+//!         self.0
+//!     }
+//! }
+//!
+//! let clock = FixedClock(1_700_000_000);
+//! let mut joins = GuildJoins::new(720, 0.01, &clock);
+//! joins.add(&clock, 3);
+//! let stat = joins.stat(&clock, PercentileMethod::default());
+//! assert_eq!(stat.current, 3);
+//! // without enough past hours, nothing is abnormal yet
+//! assert_eq!(stat.severity(&ThresholdConfig::default()), Severity::Normal);
+//! ```
+//!
+//! `joins::GuildJoinsMap` keeps the data of many guilds, persisted by a `store::Store`.
+//! `handler::HandlerBuilder` runs the bot itself with options set in code.
+
+mod alert;
+mod bots;
+pub mod clock;
+mod cluster;
+mod confirm;
+#[cfg(feature = "control")]
+mod control;
+pub mod error;
+mod gaps;
+pub mod handler;
+#[cfg(feature = "health")]
+mod health;
+mod invites;
+mod joiners;
+pub mod joins;
+mod logging;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod monitoring;
+mod rate;
+mod rejoins;
+mod schedule;
+mod signal;
+pub mod store;
+mod template;
@@ -0,0 +1,81 @@
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+/// Target of log records whose message is a JSON object of structured fields.
+const EVENT_TARGET: &str = "boredphoton::event";
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable colored lines from `pretty_env_logger`.
+    #[default]
+    Pretty,
+    /// One JSON object per line.
+    Json,
+}
+
+pub fn init(format: LogFormat) {
+    match format {
+        LogFormat::Pretty => pretty_env_logger::init(),
+        LogFormat::Json => {
+            JSON.store(true, Ordering::SeqCst);
+            env_logger::Builder::from_default_env()
+                .format(|buf, record| {
+                    let mut object = match record.target() {
+                        EVENT_TARGET => match serde_json::from_str(&record.args().to_string()) {
+                            Ok(Value::Object(object)) => object,
+                            _ => Map::new(),
+                        },
+                        _ => {
+                            let mut object = Map::new();
+                            object.insert("msg".into(), record.args().to_string().into());
+                            object.insert("target".into(), record.target().into());
+                            object
+                        }
+                    };
+                    object.insert("timestamp".into(), buf.timestamp().to_string().into());
+                    object.insert("level".into(), record.level().as_str().into());
+                    writeln!(buf, "{}", Value::Object(object))
+                })
+                .init();
+        }
+    }
+}
+
+/// Logs a message with structured fields.
+///
+/// The fields are only emitted with the JSON log format;
+/// the pretty format only shows the message.
+pub fn event(level: log::Level, message: fmt::Arguments, fields: Value) {
+    if JSON.load(Ordering::Relaxed) {
+        let mut object = match fields {
+            Value::Object(object) => object,
+            other => {
+                let mut object = Map::new();
+                object.insert("fields".into(), other);
+                object
+            }
+        };
+        object.insert("msg".into(), message.to_string().into());
+        log::log!(target: EVENT_TARGET, level, "{}", Value::Object(object));
+    } else {
+        log::log!(level, "{}", message);
+    }
+}
+
+/// Appends an entry to the audit file at `path` as a line of JSON.
+pub fn append_audit(path: &Path, entry: &Value) -> io::Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    writeln!(file, "{}", entry)
+}
@@ -0,0 +1,162 @@
+//! Prometheus metrics of join rates, served over HTTP.
+//!
+//! Exposed metrics, labelled by `guild`:
+//! - `boredphoton_joins_current_hour` (gauge): joins in the current hour
+//! - `boredphoton_joins_mean` (gauge): mean joins per hour over the backlog
+//! - `boredphoton_joins_upper_quartile` (gauge): upper quartile of joins per hour over the backlog
+//! - `boredphoton_raids_detected_total` (counter): number of joins detected as abnormal
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use serenity::model::id::GuildId;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::joins::Stat;
+
+#[derive(Default)]
+pub struct Metrics {
+    guilds: RwLock<HashMap<GuildId, Arc<GuildMetrics>>>,
+}
+
+/// Gauges are stored as the bits of an `f64`.
+#[derive(Default)]
+struct GuildMetrics {
+    current: AtomicU64,
+    mean: AtomicU64,
+    uq: AtomicU64,
+    raids: AtomicU64,
+}
+
+impl Metrics {
+    fn guild(&self, guild: GuildId) -> Arc<GuildMetrics> {
+        if let Some(metrics) = self.guilds.read().unwrap().get(&guild) {
+            return Arc::clone(metrics);
+        }
+        Arc::clone(self.guilds.write().unwrap().entry(guild).or_default())
+    }
+
+    pub fn observe(&self, guild: GuildId, stat: &Stat) {
+        let metrics = self.guild(guild);
+        metrics
+            .current
+            .store(f64::from(stat.current).to_bits(), Ordering::Relaxed);
+        metrics.mean.store(stat.mean.to_bits(), Ordering::Relaxed);
+        metrics.uq.store(stat.uq.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn raid_detected(&self, guild: GuildId) {
+        self.guild(guild).raids.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(&self) -> String {
+        let guilds = self.guilds.read().unwrap();
+        let mut out = String::new();
+
+        let gauge = |m: &AtomicU64| f64::from_bits(m.load(Ordering::Relaxed));
+        write_family(
+            &mut out,
+            &guilds,
+            "boredphoton_joins_current_hour",
+            "gauge",
+            "Joins in the current hour",
+            |m| gauge(&m.current),
+        );
+        write_family(
+            &mut out,
+            &guilds,
+            "boredphoton_joins_mean",
+            "gauge",
+            "Mean joins per hour",
+            |m| gauge(&m.mean),
+        );
+        write_family(
+            &mut out,
+            &guilds,
+            "boredphoton_joins_upper_quartile",
+            "gauge",
+            "Upper quartile of joins per hour",
+            |m| gauge(&m.uq),
+        );
+        write_family(
+            &mut out,
+            &guilds,
+            "boredphoton_raids_detected_total",
+            "counter",
+            "Joins detected as abnormal",
+            |m| m.raids.load(Ordering::Relaxed) as f64,
+        );
+
+        out
+    }
+}
+
+fn write_family(
+    out: &mut String,
+    guilds: &HashMap<GuildId, Arc<GuildMetrics>>,
+    name: &str,
+    kind: &str,
+    help: &str,
+    value: impl Fn(&GuildMetrics) -> f64,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (guild, metrics) in guilds {
+        let _ = writeln!(out, "{}{{guild=\"{}\"}} {}", name, guild, value(metrics));
+    }
+}
+
+/// Serves `/metrics` on `addr` until `shutdown` completes.
+pub async fn serve(
+    addr: SocketAddr,
+    metrics: Arc<Metrics>,
+    shutdown: impl Future<Output = ()>,
+) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    log::info!("Serving metrics on {}", addr);
+
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, _) = accepted?;
+                let metrics = Arc::clone(&metrics);
+                tokio::spawn(async move {
+                    if let Err(err) = respond(stream, &metrics).await {
+                        log::debug!("Error serving metrics: {}", err);
+                    }
+                });
+            }
+            () = &mut shutdown => {
+                log::info!("Metrics server stopped");
+                return Ok(());
+            }
+        }
+    }
+}
+
+async fn respond(mut stream: TcpStream, metrics: &Metrics) -> std::io::Result<()> {
+    // Only the request line matters, which fits in the first read of any sane client.
+    let mut buf = [0; 1024];
+    let n = stream.read(&mut buf).await?;
+    let request = String::from_utf8_lossy(&buf[..n]);
+    let mut parts = request.split_whitespace();
+
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.render()),
+        _ => ("404 Not Found", String::new()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
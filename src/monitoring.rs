@@ -0,0 +1,102 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use serenity::model::id::GuildId;
+
+/// Guilds whose joins are not monitored.
+pub struct IgnoredGuilds {
+    /// Guilds ignored in the config.
+    configured: HashSet<GuildId>,
+    /// Guilds enabled (`true`) or disabled (`false`) at runtime, overriding the config.
+    toggles: Mutex<HashMap<GuildId, bool>>,
+    /// File the runtime toggles are persisted in.
+    path: PathBuf,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Toggles {
+    enabled: Vec<GuildId>,
+    disabled: Vec<GuildId>,
+}
+
+impl IgnoredGuilds {
+    /// Loads the runtime toggles persisted at `path` on top of the `configured` ignored guilds.
+    pub fn load(configured: HashSet<GuildId>, path: PathBuf) -> io::Result<Self> {
+        let toggles = match fs::File::open(&path) {
+            Ok(f) => serde_json::from_reader(io::BufReader::new(f))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Toggles::default(),
+            Err(err) => return Err(err),
+        };
+        let toggles = toggles
+            .enabled
+            .into_iter()
+            .map(|guild| (guild, true))
+            .chain(toggles.disabled.into_iter().map(|guild| (guild, false)))
+            .collect();
+        Ok(Self {
+            configured,
+            toggles: Mutex::new(toggles),
+            path,
+        })
+    }
+
+    pub fn is_ignored(&self, guild: GuildId) -> bool {
+        match self.toggles.lock().unwrap().get(&guild) {
+            Some(&enabled) => !enabled,
+            None => self.configured.contains(&guild),
+        }
+    }
+
+    /// Enables or disables monitoring of the guild and persists the change.
+    pub fn set_enabled(&self, guild: GuildId, enabled: bool) -> io::Result<()> {
+        let mut toggles = self.toggles.lock().unwrap();
+        toggles.insert(guild, enabled);
+
+        let mut persisted = Toggles::default();
+        for (&guild, &enabled) in toggles.iter() {
+            match enabled {
+                true => persisted.enabled.push(guild),
+                false => persisted.disabled.push(guild),
+            }
+        }
+        let tmp_path = self.path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_vec(&persisted)?)?;
+        fs::rename(&tmp_path, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "boredphoton-{}-{}-monitoring.json",
+            name,
+            std::process::id()
+        ))
+    }
+
+    #[test]
+    fn toggles_override_the_config_and_persist() {
+        let path = temp_path("toggles");
+        let configured = HashSet::from([GuildId(1)]);
+        let ignored = IgnoredGuilds::load(configured.clone(), path.clone()).unwrap();
+        assert!(ignored.is_ignored(GuildId(1)));
+        assert!(!ignored.is_ignored(GuildId(2)));
+
+        ignored.set_enabled(GuildId(1), true).unwrap();
+        ignored.set_enabled(GuildId(2), false).unwrap();
+        assert!(!ignored.is_ignored(GuildId(1)));
+        assert!(ignored.is_ignored(GuildId(2)));
+
+        let reloaded = IgnoredGuilds::load(configured, path.clone()).unwrap();
+        assert!(!reloaded.is_ignored(GuildId(1)));
+        assert!(reloaded.is_ignored(GuildId(2)));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use serenity::model::id::{GuildId, UserId};
+
+/// Trips when too many joins happen within a sliding window,
+/// regardless of the hourly baseline.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    pub window_minutes: u64,
+    /// Joins within the window exceeding this count are abnormal.
+    pub max_joins: usize,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            window_minutes: 10,
+            max_joins: 30,
+        }
+    }
+}
+
+/// Times of the recent joins of each guild.
+#[derive(Default)]
+pub struct JoinRates {
+    guilds: Mutex<HashMap<GuildId, VecDeque<Instant>>>,
+}
+
+impl JoinRates {
+    /// Records a join at `now` and returns whether the rate limit is exceeded.
+    pub fn record(&self, config: &RateLimitConfig, guild: GuildId, now: Instant) -> bool {
+        let window = Duration::from_secs(config.window_minutes * 60);
+        let mut guilds = self.guilds.lock().unwrap();
+        let joins = guilds.entry(guild).or_default();
+
+        while joins
+            .front()
+            .is_some_and(|&time| now.duration_since(time) > window)
+        {
+            joins.pop_front();
+        }
+        joins.push_back(now);
+        // older joins are not needed to tell whether the limit is exceeded
+        while joins.len() > config.max_joins + 1 {
+            joins.pop_front();
+        }
+
+        joins.len() > config.max_joins
+    }
+}
+
+/// Ignores commands from a user within a window after their previous command.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct CommandCooldownConfig {
+    pub enabled: bool,
+    pub seconds: u64,
+    /// Whether to tell the user the first time their command is ignored.
+    pub notify: bool,
+}
+
+impl Default for CommandCooldownConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            seconds: 5,
+            notify: true,
+        }
+    }
+}
+
+/// Maximum number of users whose last command is remembered.
+const MAX_COOLDOWN_USERS: usize = 10000;
+
+/// Times of the last accepted command of each user.
+#[derive(Default)]
+pub struct CommandCooldowns {
+    users: Mutex<HashMap<UserId, Cooldown>>,
+}
+
+struct Cooldown {
+    since: Instant,
+    /// Whether the user has been told about the cooldown.
+    notified: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CooldownCheck {
+    Accepted,
+    /// The first ignored command of the cooldown.
+    IgnoredFirst,
+    Ignored,
+}
+
+impl CommandCooldowns {
+    /// Records a command of `user` at `now` unless the user is on cooldown.
+    pub fn check(
+        &self,
+        config: &CommandCooldownConfig,
+        user: UserId,
+        now: Instant,
+    ) -> CooldownCheck {
+        let window = Duration::from_secs(config.seconds);
+        let mut users = self.users.lock().unwrap();
+        if let Some(cooldown) = users.get_mut(&user) {
+            if now.duration_since(cooldown.since) < window {
+                return match std::mem::replace(&mut cooldown.notified, true) {
+                    false => CooldownCheck::IgnoredFirst,
+                    true => CooldownCheck::Ignored,
+                };
+            }
+        }
+
+        if users.len() >= MAX_COOLDOWN_USERS {
+            users.retain(|_, cooldown| now.duration_since(cooldown.since) < window);
+            if users.len() >= MAX_COOLDOWN_USERS {
+                let oldest = users
+                    .iter()
+                    .min_by_key(|(_, cooldown)| cooldown.since)
+                    .map(|(&user, _)| user);
+                if let Some(oldest) = oldest {
+                    users.remove(&oldest);
+                }
+            }
+        }
+        users.insert(
+            user,
+            Cooldown {
+                since: now,
+                notified: false,
+            },
+        );
+        CooldownCheck::Accepted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GUILD: GuildId = GuildId(1);
+
+    fn config(max_joins: usize) -> RateLimitConfig {
+        RateLimitConfig {
+            enabled: true,
+            window_minutes: 10,
+            max_joins,
+        }
+    }
+
+    #[test]
+    fn burst_across_the_hour_is_caught() {
+        let rates = JoinRates::default();
+        let config = config(30);
+        // 30 joins from :58 to :59, then 30 from :00 to :02, two hours of 30 joins each
+        let hour = Instant::now() + Duration::from_secs(3600);
+        let before = (0..30).map(|i| hour - Duration::from_secs(120 - i * 2));
+        let after = (0..30).map(|i| hour + Duration::from_secs(i * 4));
+        let tripped: Vec<_> = before
+            .chain(after)
+            .map(|time| rates.record(&config, GUILD, time))
+            .collect();
+        assert!(tripped[..30].iter().all(|&tripped| !tripped));
+        assert!(tripped[30..].iter().all(|&tripped| tripped));
+    }
+
+    #[test]
+    fn joins_leave_the_window_after_it_ends() {
+        let rates = JoinRates::default();
+        let config = config(2);
+        let start = Instant::now();
+        let window = Duration::from_secs(600);
+        assert!(!rates.record(&config, GUILD, start));
+        assert!(!rates.record(&config, GUILD, start));
+        // exactly one window after the first joins, they still count
+        assert!(rates.record(&config, GUILD, start + window));
+        // the first joins have left the window, the last two have not
+        assert!(!rates.record(&config, GUILD, start + window + Duration::from_secs(1)));
+        assert!(!rates.record(&config, GuildId(2), start + window));
+    }
+
+    #[test]
+    fn commands_within_the_cooldown_are_dropped() {
+        let config = CommandCooldownConfig {
+            enabled: true,
+            seconds: 5,
+            notify: true,
+        };
+        let cooldowns = CommandCooldowns::default();
+        let start = Instant::now();
+        let check =
+            |user, secs| cooldowns.check(&config, UserId(user), start + Duration::from_secs(secs));
+
+        assert_eq!(check(1, 0), CooldownCheck::Accepted);
+        assert_eq!(check(1, 1), CooldownCheck::IgnoredFirst);
+        assert_eq!(check(1, 4), CooldownCheck::Ignored);
+        // other users have their own cooldowns
+        assert_eq!(check(2, 4), CooldownCheck::Accepted);
+        assert_eq!(check(1, 5), CooldownCheck::Accepted);
+        assert_eq!(check(1, 6), CooldownCheck::IgnoredFirst);
+    }
+}
@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use serenity::model::id::{GuildId, UserId};
+
+/// Counts a user joining the same guild repeatedly within a window as one join.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct RejoinDedupeConfig {
+    pub enabled: bool,
+    pub window_minutes: u64,
+}
+
+impl Default for RejoinDedupeConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            window_minutes: 30,
+        }
+    }
+}
+
+/// Users who recently joined each guild, with the time of their latest join.
+#[derive(Default)]
+pub struct RecentJoiners {
+    guilds: Mutex<HashMap<GuildId, HashMap<UserId, Instant>>>,
+}
+
+impl RecentJoiners {
+    /// Records a join of `user` at `now` and returns whether
+    /// the user already joined the guild within the window.
+    pub fn record(
+        &self,
+        config: &RejoinDedupeConfig,
+        guild: GuildId,
+        user: UserId,
+        now: Instant,
+    ) -> bool {
+        let window = Duration::from_secs(config.window_minutes * 60);
+        let mut guilds = self.guilds.lock().unwrap();
+        let users = guilds.entry(guild).or_default();
+
+        users.retain(|_, &mut time| now.duration_since(time) <= window);
+        users.insert(user, now).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GUILD: GuildId = GuildId(1);
+
+    fn config() -> RejoinDedupeConfig {
+        RejoinDedupeConfig {
+            enabled: true,
+            window_minutes: 30,
+        }
+    }
+
+    #[test]
+    fn double_joins_within_the_window_count_once() {
+        let (config, joiners) = (config(), RecentJoiners::default());
+        let start = Instant::now();
+        let window = Duration::from_secs(30 * 60);
+        assert!(!joiners.record(&config, GUILD, UserId(1), start));
+        assert!(joiners.record(&config, GUILD, UserId(1), start));
+        assert!(!joiners.record(&config, GUILD, UserId(2), start));
+        assert!(!joiners.record(&config, GuildId(2), UserId(1), start));
+        // the window ends inclusively
+        assert!(joiners.record(&config, GUILD, UserId(2), start + window));
+    }
+
+    #[test]
+    fn rejoins_after_the_window_count_again() {
+        let (config, joiners) = (config(), RecentJoiners::default());
+        let start = Instant::now();
+        let window = Duration::from_secs(30 * 60);
+        assert!(!joiners.record(&config, GUILD, UserId(1), start));
+        let later = start + window + Duration::from_secs(1);
+        assert!(!joiners.record(&config, GUILD, UserId(1), later));
+        // each join restarts the window
+        assert!(joiners.record(&config, GUILD, UserId(1), later + window));
+    }
+}
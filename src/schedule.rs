@@ -0,0 +1,239 @@
+//! Scheduling of daily reports at local midnight.
+
+use std::convert::TryFrom;
+
+use chrono::{FixedOffset, NaiveDateTime, NaiveTime, TimeZone as _, Timelike, Utc};
+
+/// The local time of a guild, written as an IANA name like `Asia/Tokyo`
+/// or a fixed offset from UTC like `+09:00`, `-05:00` or `+0530`.
+///
+/// Daylight saving time is only followed for IANA names.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub enum TimeZone {
+    Named(chrono_tz::Tz),
+    Fixed(FixedOffset),
+}
+
+/// Parses a fixed offset from UTC, optionally prefixed by `UTC`.
+fn parse_offset(s: &str) -> Option<FixedOffset> {
+    let s = s.strip_prefix("UTC").unwrap_or(s);
+    let (sign, rest) = match s.as_bytes().first()? {
+        b'+' => (1, &s[1..]),
+        b'-' => (-1, &s[1..]),
+        _ => return None,
+    };
+    let digits: String = rest.chars().filter(|&c| c != ':').collect();
+    if !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let (hours, minutes) = match digits.len() {
+        1 | 2 => (digits.parse::<i32>().ok()?, 0),
+        4 => (digits[..2].parse().ok()?, digits[2..].parse().ok()?),
+        _ => return None,
+    };
+    if hours > 14 || minutes >= 60 {
+        return None;
+    }
+    FixedOffset::east_opt(sign * (hours * 60 + minutes) * 60)
+}
+
+impl TimeZone {
+    pub fn parse(s: &str) -> Option<Self> {
+        match parse_offset(s) {
+            Some(offset) => Some(Self::Fixed(offset)),
+            None => s.parse().ok().map(Self::Named),
+        }
+    }
+
+    /// Returns the unix time of the first local `hour` o'clock strictly after the unix time `now`.
+    ///
+    /// If daylight saving time skips that hour, the first local time after the gap is used instead;
+    /// if it repeats that hour, only the earlier one is used.
+    pub fn next_hour(self, now: u64, hour: u32) -> u64 {
+        match self {
+            Self::Named(tz) => next_hour(&tz, now, hour),
+            Self::Fixed(offset) => next_hour(&offset, now, hour),
+        }
+    }
+
+    /// Returns the local hour of the day at the unix time `now`.
+    pub fn hour(self, now: u64) -> u32 {
+        match self {
+            Self::Named(tz) => local_time(&tz, now).hour(),
+            Self::Fixed(offset) => local_time(&offset, now).hour(),
+        }
+    }
+}
+
+impl TryFrom<String> for TimeZone {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, String> {
+        Self::parse(&s).ok_or_else(|| {
+            format!(
+                "invalid time zone {:?}, expected e.g. Asia/Tokyo or +09:00",
+                s
+            )
+        })
+    }
+}
+
+fn local_time<Z: chrono::TimeZone>(tz: &Z, now: u64) -> NaiveDateTime {
+    let now = Utc
+        .timestamp_opt(now as i64, 0)
+        .single()
+        .expect("the current time is representable");
+    now.with_timezone(tz).naive_local()
+}
+
+fn next_hour<Z: chrono::TimeZone>(tz: &Z, now: u64, hour: u32) -> u64 {
+    let time = NaiveTime::from_hms_opt(hour, 0, 0).expect("hour is below 24");
+    let mut date = local_time(tz, now).date();
+    loop {
+        let target = date.and_time(time);
+        // gaps from daylight saving time end on a quarter hour
+        let resolved = (0..=24 * 4).find_map(|quarter| {
+            let local = target + chrono::Duration::minutes(quarter * 15);
+            tz.from_local_datetime(&local).earliest()
+        });
+        if let Some(resolved) = resolved {
+            let resolved = resolved.timestamp();
+            if resolved > now as i64 {
+                return resolved as u64;
+            }
+        }
+        date = date.succ_opt().expect("the next day is representable");
+    }
+}
+
+/// Returns the unix time of the first local midnight strictly after the unix time `now`.
+pub fn next_midnight(now: u64, timezone: TimeZone) -> u64 {
+    timezone.next_hour(now, 0)
+}
+
+/// A daily period in local time from `start` until `end` o'clock, which may span midnight.
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+pub struct QuietHours {
+    start: u8,
+    end: u8,
+    #[serde(alias = "utc_offset")]
+    timezone: TimeZone,
+}
+
+impl QuietHours {
+    pub fn is_valid(&self) -> bool {
+        self.start < 24 && self.end < 24
+    }
+
+    /// Returns the unix time the quiet hours end at if `now` is within them.
+    pub fn end_if_quiet(&self, now: u64) -> Option<u64> {
+        let hour = self.timezone.hour(now);
+        let (start, end) = (u32::from(self.start), u32::from(self.end));
+        let quiet = match start <= end {
+            true => start <= hour && hour < end,
+            false => start <= hour || hour < end,
+        };
+        quiet.then(|| self.timezone.next_hour(now, end))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tz(s: &str) -> TimeZone {
+        TimeZone::parse(s).unwrap()
+    }
+
+    #[test]
+    fn parses_names_and_offsets() {
+        assert_eq!(tz("Asia/Tokyo"), TimeZone::Named(chrono_tz::Asia::Tokyo));
+        assert_eq!(tz("UTC"), TimeZone::Named(chrono_tz::UTC));
+        assert_eq!(
+            tz("+09:00"),
+            TimeZone::Fixed(FixedOffset::east_opt(9 * 3600).unwrap())
+        );
+        assert_eq!(
+            tz("UTC-0530"),
+            TimeZone::Fixed(FixedOffset::west_opt(5 * 3600 + 1800).unwrap())
+        );
+        for invalid in &["Mars/Olympus", "+15:00", "+09:60", "9", ""] {
+            assert!(
+                TimeZone::parse(invalid).is_none(),
+                "{:?} should be rejected",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn midnight_with_fixed_offset() {
+        // 2024-03-30 12:00 UTC is 21:00 at +09:00
+        assert_eq!(next_midnight(1711800000, tz("+09:00")), 1711810800);
+        // strictly after midnight itself
+        assert_eq!(next_midnight(1711810800, tz("+09:00")), 1711810800 + 86400);
+    }
+
+    #[test]
+    fn midnight_across_dst_start() {
+        let london = tz("Europe/London");
+        // 2024-03-31 00:00 GMT, before clocks go forward at 01:00
+        let first = next_midnight(1711800000, london);
+        assert_eq!(first, 1711843200);
+        // 2024-04-01 00:00 BST, only 23 hours later
+        assert_eq!(next_midnight(first, london), 1711926000);
+    }
+
+    #[test]
+    fn midnight_across_dst_end() {
+        let london = tz("Europe/London");
+        // 2024-10-27 00:00 BST, then 2024-10-28 00:00 GMT 25 hours later
+        assert_eq!(next_midnight(1729983600, london), 1730073600);
+    }
+
+    #[test]
+    fn midnight_skipped_by_dst() {
+        // clocks in Santiago go from 2024-09-07 23:59 -04 to 2024-09-08 01:00 -03
+        assert_eq!(
+            next_midnight(1725724800, tz("America/Santiago")),
+            1725768000
+        );
+    }
+
+    #[test]
+    fn quiet_hours_in_window() {
+        let quiet = QuietHours {
+            start: 23,
+            end: 7,
+            timezone: tz("+09:00"),
+        };
+        // 2024-03-30 23:00 and 02:00 at +09:00 end at 07:00
+        assert_eq!(quiet.end_if_quiet(1711807200), Some(1711836000));
+        assert_eq!(quiet.end_if_quiet(1711817000), Some(1711836000));
+    }
+
+    #[test]
+    fn quiet_hours_out_of_window() {
+        let quiet = QuietHours {
+            start: 23,
+            end: 7,
+            timezone: tz("+09:00"),
+        };
+        // 2024-03-30 22:59 and 2024-03-31 07:00 at +09:00
+        assert_eq!(quiet.end_if_quiet(1711807140), None);
+        assert_eq!(quiet.end_if_quiet(1711836000), None);
+    }
+
+    #[test]
+    fn quiet_hours_within_a_day() {
+        let quiet = QuietHours {
+            start: 1,
+            end: 5,
+            timezone: tz("UTC"),
+        };
+        assert_eq!(quiet.end_if_quiet(3600), Some(5 * 3600));
+        assert_eq!(quiet.end_if_quiet(0), None);
+        assert_eq!(quiet.end_if_quiet(5 * 3600), None);
+    }
+}
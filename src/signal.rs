@@ -0,0 +1,23 @@
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Resolves when the process receives SIGINT or SIGTERM.
+pub async fn terminated() {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(err) => {
+            log::error!("Cannot listen for SIGTERM: {}", err);
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => {
+            if let Err(err) = result {
+                log::error!("Cannot listen for SIGINT: {}", err);
+                // still stop on SIGTERM
+                terminate.recv().await;
+            }
+        }
+        _ = terminate.recv() => (),
+    }
+}
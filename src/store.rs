@@ -0,0 +1,411 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use serenity::model::id;
+
+use crate::joins::GuildJoins;
+
+#[cfg(feature = "sqlite")]
+mod sqlite;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStore;
+#[cfg(feature = "redis")]
+mod redis;
+#[cfg(feature = "redis")]
+pub use self::redis::RedisStore;
+
+/// Persistence backend for per-guild join data.
+pub trait Store: Send + Sync {
+    /// Loads the data of a guild.
+    ///
+    /// Returns an error of kind `NotFound` if the guild has no stored data.
+    fn load(&self, guild: id::GuildId) -> io::Result<GuildJoins>;
+
+    fn save(&self, guild: id::GuildId, gj: &GuildJoins) -> io::Result<()>;
+
+    /// Moves the unreadable stored data of a guild aside so that it is not overwritten,
+    /// returning where it was moved to.
+    fn quarantine(&self, guild: id::GuildId) -> io::Result<String>;
+
+    /// Returns the guilds with stored data.
+    fn guilds(&self) -> io::Result<Vec<id::GuildId>> {
+        Err(unsupported())
+    }
+
+    /// Deletes the stored data of a guild, doing nothing if it has none.
+    fn delete(&self, _guild: id::GuildId) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    /// Copies the stored data of all guilds into a new backup,
+    /// returning the name of the backup.
+    fn backup(&self) -> io::Result<String> {
+        Err(unsupported())
+    }
+
+    /// Replaces the stored data of the guilds in the named backup with the backup contents.
+    fn restore(&self, _name: &str) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    /// Deletes all but the `keep` newest backups.
+    fn prune_backups(&self, _keep: usize) -> io::Result<()> {
+        Err(unsupported())
+    }
+}
+
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "this operation is not supported by this store",
+    )
+}
+
+/// Keeps guild data in memory, shared by its clones, for tests.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct MemoryStore {
+    guilds: std::sync::Arc<std::sync::Mutex<std::collections::HashMap<id::GuildId, String>>>,
+    /// Whether saves fail as if the disk were full.
+    pub failing: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    /// Number of successful saves.
+    pub saves: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+    /// Copies of `guilds`, oldest first.
+    pub backups:
+        std::sync::Arc<std::sync::Mutex<Vec<std::collections::HashMap<id::GuildId, String>>>>,
+}
+
+#[cfg(test)]
+impl MemoryStore {
+    /// Returns the JSON last saved for the guild.
+    pub fn saved(&self, guild: id::GuildId) -> Option<serde_json::Value> {
+        let guilds = self.guilds.lock().unwrap();
+        Some(serde_json::from_str(guilds.get(&guild)?).unwrap())
+    }
+}
+
+#[cfg(test)]
+impl Store for MemoryStore {
+    fn load(&self, guild: id::GuildId) -> io::Result<GuildJoins> {
+        match self.guilds.lock().unwrap().get(&guild) {
+            Some(json) => Ok(serde_json::from_str(json)?),
+            None => Err(io::ErrorKind::NotFound.into()),
+        }
+    }
+
+    fn save(&self, guild: id::GuildId, gj: &GuildJoins) -> io::Result<()> {
+        if self.failing.load(std::sync::atomic::Ordering::Relaxed) {
+            return Err(io::Error::other("disk full"));
+        }
+        let json = serde_json::to_string(gj)?;
+        self.guilds.lock().unwrap().insert(guild, json);
+        self.saves
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn quarantine(&self, guild: id::GuildId) -> io::Result<String> {
+        self.guilds.lock().unwrap().remove(&guild);
+        Ok("nowhere".into())
+    }
+
+    fn guilds(&self) -> io::Result<Vec<id::GuildId>> {
+        Ok(self.guilds.lock().unwrap().keys().copied().collect())
+    }
+
+    fn delete(&self, guild: id::GuildId) -> io::Result<()> {
+        self.guilds.lock().unwrap().remove(&guild);
+        Ok(())
+    }
+
+    fn backup(&self) -> io::Result<String> {
+        let mut backups = self.backups.lock().unwrap();
+        backups.push(self.guilds.lock().unwrap().clone());
+        Ok(backups.len().to_string())
+    }
+
+    fn prune_backups(&self, keep: usize) -> io::Result<()> {
+        let mut backups = self.backups.lock().unwrap();
+        let excess = backups.len().saturating_sub(keep);
+        backups.drain(..excess);
+        Ok(())
+    }
+}
+
+/// Stores each guild in a separate `{guild}.json` file under a data directory.
+pub struct JsonStore {
+    data_dir: PathBuf,
+}
+
+impl JsonStore {
+    pub fn new(data_dir: PathBuf) -> Self {
+        Self { data_dir }
+    }
+
+    fn path(&self, guild: id::GuildId) -> PathBuf {
+        self.data_dir.join(format!("{}.json", guild))
+    }
+
+    fn backups_dir(&self) -> PathBuf {
+        self.data_dir.join("backups")
+    }
+
+    /// Returns the names of all backups, oldest first.
+    fn backup_names(&self) -> io::Result<Vec<BackupName>> {
+        let mut names = Vec::new();
+        match fs::read_dir(self.backups_dir()) {
+            Ok(dir) => {
+                for entry in dir {
+                    if let Some(name) = entry?.file_name().to_str().and_then(BackupName::parse) {
+                        names.push(name);
+                    }
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => return Err(err),
+        }
+        names.sort_unstable();
+        Ok(names)
+    }
+}
+
+/// The name of a backup, the unix time it was created at
+/// followed by `-{seq}` if `seq` other backups were created in the same second.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct BackupName {
+    secs: u64,
+    seq: u32,
+}
+
+impl BackupName {
+    /// Parses a name formatted by `Display`, rejecting any other spelling
+    /// so that the name cannot refer to a path outside the backups.
+    fn parse(name: &str) -> Option<Self> {
+        let (secs, seq) = match name.split_once('-') {
+            Some((secs, seq)) => (secs, seq.parse().ok()?),
+            None => (name, 0),
+        };
+        let parsed = Self {
+            secs: secs.parse().ok()?,
+            seq,
+        };
+        (parsed.to_string() == name).then_some(parsed)
+    }
+}
+
+impl fmt::Display for BackupName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.seq {
+            0 => write!(f, "{}", self.secs),
+            seq => write!(f, "{}-{}", self.secs, seq),
+        }
+    }
+}
+
+/// Copies all guild files from `from` into `to`.
+fn copy_guild_files(from: &Path, to: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(from)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension().is_some_and(|ext| ext == "json") {
+            if let Some(name) = path.file_name() {
+                fs::copy(&path, to.join(name))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+impl Store for JsonStore {
+    fn load(&self, guild: id::GuildId) -> io::Result<GuildJoins> {
+        let f = fs::File::open(self.path(guild))?;
+        Ok(serde_json::from_reader(io::BufReader::new(f))?)
+    }
+
+    fn save(&self, guild: id::GuildId, gj: &GuildJoins) -> io::Result<()> {
+        // Write to a temporary file in the same directory and rename it into place,
+        // so that a crash mid-write never leaves a truncated file behind.
+        let path = self.path(guild);
+        let tmp_path = path.with_extension("json.tmp");
+        let mut f = io::BufWriter::new(fs::File::create(&tmp_path)?);
+        serde_json::to_writer(&mut f, gj)?;
+        f.into_inner()?.sync_all()?;
+        fs::rename(&tmp_path, &path)
+    }
+
+    fn quarantine(&self, guild: id::GuildId) -> io::Result<String> {
+        let path = self.path(guild);
+        let corrupt_path = path.with_extension("corrupt.json");
+        fs::rename(&path, &corrupt_path)?;
+        Ok(corrupt_path.display().to_string())
+    }
+
+    fn guilds(&self) -> io::Result<Vec<id::GuildId>> {
+        let mut guilds = Vec::new();
+        for entry in fs::read_dir(&self.data_dir)? {
+            // quarantined and temporary files have other extensions after the ID
+            if let Some(guild) = entry?
+                .file_name()
+                .to_str()
+                .and_then(|name| name.strip_suffix(".json"))
+                .and_then(|id| id.parse().ok())
+            {
+                guilds.push(id::GuildId(guild));
+            }
+        }
+        Ok(guilds)
+    }
+
+    fn delete(&self, guild: id::GuildId) -> io::Result<()> {
+        match fs::remove_file(self.path(guild)) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    fn backup(&self) -> io::Result<String> {
+        let secs = UNIX_EPOCH.elapsed().map_err(io::Error::other)?.as_secs();
+        fs::create_dir_all(self.backups_dir())?;
+        // backups in the same second must not be merged into one directory
+        let mut name = BackupName { secs, seq: 0 };
+        let dir = loop {
+            let dir = self.backups_dir().join(name.to_string());
+            match fs::create_dir(&dir) {
+                Ok(()) => break dir,
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => name.seq += 1,
+                Err(err) => return Err(err),
+            }
+        };
+        copy_guild_files(&self.data_dir, &dir)?;
+        Ok(name.to_string())
+    }
+
+    fn restore(&self, name: &str) -> io::Result<()> {
+        // only accept names generated by `backup` so that the path cannot escape the backups
+        let name = BackupName::parse(name).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "backup names are unix timestamps, optionally followed by a sequence number",
+            )
+        })?;
+        let dir = self.backups_dir().join(name.to_string());
+        if !dir.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no backup named {}", name),
+            ));
+        }
+        copy_guild_files(&dir, &self.data_dir)
+    }
+
+    fn prune_backups(&self, keep: usize) -> io::Result<()> {
+        let names = self.backup_names()?;
+        let excess = names.len().saturating_sub(keep);
+        for name in &names[..excess] {
+            fs::remove_dir_all(self.backups_dir().join(name.to_string()))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{self, Clock as _};
+
+    fn temp_store(name: &str) -> JsonStore {
+        let dir = std::env::temp_dir().join(format!(
+            "boredphoton-{}-{}-{}",
+            name,
+            std::process::id(),
+            clock::SystemClock.now_secs()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        JsonStore::new(dir)
+    }
+
+    #[test]
+    fn backup_names_round_trip() {
+        for name in &["1600000000", "1600000000-1", "1600000000-12"] {
+            assert_eq!(BackupName::parse(name).unwrap().to_string(), *name);
+        }
+        for name in &[
+            "",
+            "-1",
+            "1600000000-",
+            "1600000000-0",
+            "+1600000000",
+            "1600000000-1-2",
+            "../1",
+        ] {
+            assert!(
+                BackupName::parse(name).is_none(),
+                "{:?} should be rejected",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn backup_names_sort_by_creation() {
+        let mut names: Vec<_> = ["11", "10-2", "10", "10-1"]
+            .iter()
+            .map(|name| BackupName::parse(name).unwrap())
+            .collect();
+        names.sort();
+        let names: Vec<_> = names.iter().map(|name| name.to_string()).collect();
+        assert_eq!(names, ["10", "10-1", "10-2", "11"]);
+    }
+
+    #[test]
+    fn backups_in_the_same_second_are_kept_apart() {
+        let store = temp_store("backup");
+        let gj = GuildJoins::new(24, 0.1, &clock::MockClock::new(0));
+        store.save(id::GuildId(1), &gj).unwrap();
+        let first = store.backup().unwrap();
+        store.save(id::GuildId(2), &gj).unwrap();
+        let second = store.backup().unwrap();
+        assert_ne!(first, second);
+
+        store.delete(id::GuildId(1)).unwrap();
+        store.delete(id::GuildId(2)).unwrap();
+        store.restore(&first).unwrap();
+        assert_eq!(store.guilds().unwrap(), [id::GuildId(1)]);
+
+        store.prune_backups(1).unwrap();
+        let names: Vec<_> = store
+            .backup_names()
+            .unwrap()
+            .iter()
+            .map(|name| name.to_string())
+            .collect();
+        assert_eq!(names, [second]);
+        fs::remove_dir_all(&store.data_dir).unwrap();
+    }
+
+    #[test]
+    fn interrupted_saves_leave_the_file_intact() {
+        let store = temp_store("atomic");
+        let clock = clock::MockClock::new(3600 * 100_000);
+        let mut gj = GuildJoins::new(24, 0.1, &clock);
+        gj.add(&clock, 3);
+        store.save(id::GuildId(1), &gj).unwrap();
+        let saved = fs::read(store.path(id::GuildId(1))).unwrap();
+
+        // a crash while writing the next save leaves a truncated temporary file
+        let tmp_path = store.path(id::GuildId(1)).with_extension("json.tmp");
+        fs::write(&tmp_path, &saved[..saved.len() / 2]).unwrap();
+        assert_eq!(fs::read(store.path(id::GuildId(1))).unwrap(), saved);
+        assert!(store.load(id::GuildId(1)).is_ok());
+        assert_eq!(store.guilds().unwrap(), [id::GuildId(1)]);
+
+        gj.add(&clock, 1);
+        store.save(id::GuildId(1), &gj).unwrap();
+        assert!(!tmp_path.exists());
+        let loaded = serde_json::to_value(store.load(id::GuildId(1)).unwrap()).unwrap();
+        assert_eq!(loaded["current"], 4);
+        fs::remove_dir_all(&store.data_dir).unwrap();
+    }
+}
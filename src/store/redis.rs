@@ -0,0 +1,187 @@
+//! Guild data in Redis, shared by several instances.
+
+use std::io;
+use std::sync::Mutex;
+
+use redis::Commands;
+use serenity::model::id;
+
+use super::Store;
+use crate::joins::GuildJoins;
+
+/// Prefix of the keys of guild data, followed by the guild ID.
+const GUILD_KEY_PREFIX: &str = "boredphoton:guild:";
+/// Prefix of the keys of unreadable guild data moved aside by `quarantine`.
+const CORRUPT_KEY_PREFIX: &str = "boredphoton:corrupt:";
+
+/// Saves are retried this many times while other instances keep saving the same guild.
+const MAX_SAVE_ATTEMPTS: u32 = 8;
+
+/// Stores the serialized data of each guild under a Redis key.
+///
+/// Saves merge the hourly counts already saved by other instances
+/// and are only applied if no other instance saved the guild in the meantime,
+/// using `WATCH` and `MULTI`.
+pub struct RedisStore {
+    conn: Mutex<redis::Connection>,
+}
+
+fn redis_error(err: redis::RedisError) -> io::Error {
+    io::Error::other(err)
+}
+
+fn guild_key(guild: id::GuildId) -> String {
+    format!("{}{}", GUILD_KEY_PREFIX, guild)
+}
+
+impl RedisStore {
+    /// Connects to the Redis server at `url`, e.g. `redis://127.0.0.1/`.
+    pub fn open(url: &str) -> io::Result<Self> {
+        let conn = redis::Client::open(url)
+            .and_then(|client| client.get_connection())
+            .map_err(redis_error)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+}
+
+impl Store for RedisStore {
+    fn load(&self, guild: id::GuildId) -> io::Result<GuildJoins> {
+        let data: Option<Vec<u8>> = self
+            .conn
+            .lock()
+            .unwrap()
+            .get(guild_key(guild))
+            .map_err(redis_error)?;
+        match data {
+            Some(data) => Ok(serde_json::from_slice(&data)?),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no data for guild {}", guild),
+            )),
+        }
+    }
+
+    fn save(&self, guild: id::GuildId, gj: &GuildJoins) -> io::Result<()> {
+        let key = guild_key(guild);
+        let mut conn = self.conn.lock().unwrap();
+        for _ in 0..MAX_SAVE_ATTEMPTS {
+            redis::cmd("WATCH")
+                .arg(&key)
+                .query::<()>(&mut *conn)
+                .map_err(redis_error)?;
+            let saved: Option<Vec<u8>> = conn.get(&key).map_err(redis_error)?;
+            let mut merged = gj.clone();
+            if let Some(saved) = saved {
+                match serde_json::from_slice(&saved) {
+                    Ok(saved) => merged.merge_counts(&saved),
+                    Err(err) => log::warn!(
+                        "Overwriting unreadable saved data of guild {}: {}",
+                        guild,
+                        err
+                    ),
+                }
+            }
+            let data = serde_json::to_vec(&merged)?;
+            // `EXEC` returns nil if another instance changed the key since `WATCH`
+            let applied: Option<()> = redis::pipe()
+                .atomic()
+                .set(&key, data)
+                .ignore()
+                .query(&mut *conn)
+                .map_err(redis_error)?;
+            if applied.is_some() {
+                return Ok(());
+            }
+        }
+        Err(io::Error::other(format!(
+            "saves of guild {} kept conflicting with other instances",
+            guild
+        )))
+    }
+
+    fn quarantine(&self, guild: id::GuildId) -> io::Result<String> {
+        let corrupt_key = format!("{}{}", CORRUPT_KEY_PREFIX, guild);
+        self.conn
+            .lock()
+            .unwrap()
+            .rename::<_, _, ()>(guild_key(guild), &corrupt_key)
+            .map_err(redis_error)?;
+        Ok(corrupt_key)
+    }
+
+    fn guilds(&self) -> io::Result<Vec<id::GuildId>> {
+        let mut conn = self.conn.lock().unwrap();
+        let keys: Vec<String> = conn
+            .scan_match(format!("{}*", GUILD_KEY_PREFIX))
+            .map_err(redis_error)?
+            .collect();
+        Ok(keys
+            .iter()
+            .filter_map(|key| key.strip_prefix(GUILD_KEY_PREFIX)?.parse().ok())
+            .map(id::GuildId)
+            .collect())
+    }
+
+    fn delete(&self, guild: id::GuildId) -> io::Result<()> {
+        self.conn
+            .lock()
+            .unwrap()
+            .del::<_, ()>(guild_key(guild))
+            .map_err(redis_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    /// Connects to the server at `REDIS_URL`, e.g. a Redis test container started with
+    /// `docker run -p 6379:6379 redis` and `REDIS_URL=redis://127.0.0.1/`.
+    fn test_store() -> RedisStore {
+        let url = std::env::var("REDIS_URL").expect("REDIS_URL must be set");
+        RedisStore::open(&url).unwrap()
+    }
+
+    #[test]
+    #[ignore = "needs a Redis server at REDIS_URL"]
+    fn concurrent_saves_keep_each_others_counts() {
+        // an ID unlikely to be used by anything else on the server
+        let guild = id::GuildId(u64::from(std::process::id()) << 20 | 0xb0);
+        let (a, b) = (test_store(), test_store());
+        a.delete(guild).unwrap();
+        assert_eq!(a.load(guild).err().unwrap().kind(), io::ErrorKind::NotFound);
+
+        let clock = MockClock::new(3600 * 100);
+        let mut first = GuildJoins::new(4, 0.5, &clock);
+        first.add(&clock, 3);
+        let mut second = GuildJoins::new(4, 0.5, &clock);
+        clock.advance(3600);
+        second.add(&clock, 2);
+
+        let threads: Vec<_> = vec![(a, first), (b, second)]
+            .into_iter()
+            .map(|(store, gj)| {
+                std::thread::spawn(move || {
+                    for _ in 0..20 {
+                        store.save(guild, &gj).unwrap();
+                    }
+                    store
+                })
+            })
+            .collect();
+        let stores: Vec<_> = threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .collect();
+
+        // neither instance overwrote the hour counted only by the other
+        let mut saved = stores[0].load(guild).unwrap().restore(4, 0.5, &clock, 0);
+        assert_eq!(saved.history(&clock, 1), [Some(3)]);
+        assert_eq!(serde_json::to_value(&saved).unwrap()["current"], 2);
+        assert!(stores[0].guilds().unwrap().contains(&guild));
+        stores[0].delete(guild).unwrap();
+    }
+}
@@ -0,0 +1,302 @@
+//! Guild data in a SQLite database, with one row per guild.
+
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+use rusqlite::{params, Connection, OptionalExtension};
+use serenity::model::id;
+
+use super::{BackupName, JsonStore, Store};
+use crate::joins::GuildJoins;
+
+/// Stores the serialized data of each guild in a row of the `guilds` table.
+///
+/// Backups are copies of the table in the `backups` table.
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+fn sql_error(err: rusqlite::Error) -> io::Error {
+    io::Error::other(err)
+}
+
+impl SqliteStore {
+    /// Opens the database at `path`, creating it if it does not exist.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Self::from_connection(Connection::open(path).map_err(sql_error)?)
+    }
+
+    fn from_connection(conn: Connection) -> io::Result<Self> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS guilds (id INTEGER PRIMARY KEY, data BLOB NOT NULL);
+            CREATE TABLE IF NOT EXISTS corrupt_guilds (id INTEGER NOT NULL, data BLOB NOT NULL);
+            CREATE TABLE IF NOT EXISTS backups (
+                name TEXT NOT NULL,
+                id INTEGER NOT NULL,
+                data BLOB NOT NULL,
+                PRIMARY KEY (name, id)
+            );",
+        )
+        .map_err(sql_error)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Imports the `{guild}.json` files under `data_dir`,
+    /// replacing the stored data of the same guilds.
+    ///
+    /// Returns the number of guilds imported.
+    pub fn import_json(&self, data_dir: &Path) -> io::Result<usize> {
+        let json = JsonStore::new(data_dir.to_owned());
+        let guilds = json.guilds()?;
+        for &guild in &guilds {
+            self.save(guild, &json.load(guild)?)?;
+        }
+        Ok(guilds.len())
+    }
+
+    fn backup_names(conn: &Connection) -> io::Result<Vec<BackupName>> {
+        let mut stmt = conn
+            .prepare("SELECT DISTINCT name FROM backups")
+            .map_err(sql_error)?;
+        let names = stmt
+            .query_map([], |row| row.get::<_, String>(0))
+            .map_err(sql_error)?;
+        let mut parsed = Vec::new();
+        for name in names {
+            parsed.extend(BackupName::parse(&name.map_err(sql_error)?));
+        }
+        parsed.sort();
+        Ok(parsed)
+    }
+}
+
+/// SQLite integers are signed, so IDs are stored with the same bits.
+fn row_id(guild: id::GuildId) -> i64 {
+    guild.0 as i64
+}
+
+impl Store for SqliteStore {
+    fn load(&self, guild: id::GuildId) -> io::Result<GuildJoins> {
+        let data: Option<Vec<u8>> = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT data FROM guilds WHERE id = ?1",
+                params![row_id(guild)],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(sql_error)?;
+        match data {
+            Some(data) => Ok(serde_json::from_slice(&data)?),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no data for guild {}", guild),
+            )),
+        }
+    }
+
+    fn save(&self, guild: id::GuildId, gj: &GuildJoins) -> io::Result<()> {
+        let data = serde_json::to_vec(gj)?;
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT OR REPLACE INTO guilds (id, data) VALUES (?1, ?2)",
+                params![row_id(guild), data],
+            )
+            .map_err(sql_error)?;
+        Ok(())
+    }
+
+    fn quarantine(&self, guild: id::GuildId) -> io::Result<String> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(sql_error)?;
+        tx.execute(
+            "INSERT INTO corrupt_guilds (id, data) SELECT id, data FROM guilds WHERE id = ?1",
+            params![row_id(guild)],
+        )
+        .map_err(sql_error)?;
+        tx.execute("DELETE FROM guilds WHERE id = ?1", params![row_id(guild)])
+            .map_err(sql_error)?;
+        tx.commit().map_err(sql_error)?;
+        Ok("the corrupt_guilds table".into())
+    }
+
+    fn guilds(&self) -> io::Result<Vec<id::GuildId>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT id FROM guilds").map_err(sql_error)?;
+        let ids = stmt
+            .query_map([], |row| row.get::<_, i64>(0))
+            .map_err(sql_error)?;
+        ids.map(|id| Ok(id::GuildId(id.map_err(sql_error)? as u64)))
+            .collect()
+    }
+
+    fn delete(&self, guild: id::GuildId) -> io::Result<()> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM guilds WHERE id = ?1", params![row_id(guild)])
+            .map_err(sql_error)?;
+        Ok(())
+    }
+
+    fn backup(&self) -> io::Result<String> {
+        let secs = UNIX_EPOCH.elapsed().map_err(io::Error::other)?.as_secs();
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(sql_error)?;
+        let seq = Self::backup_names(&tx)?
+            .iter()
+            .filter(|name| name.secs == secs)
+            .map(|name| name.seq + 1)
+            .max()
+            .unwrap_or(0);
+        let name = BackupName { secs, seq }.to_string();
+        tx.execute(
+            "INSERT INTO backups (name, id, data) SELECT ?1, id, data FROM guilds",
+            params![name],
+        )
+        .map_err(sql_error)?;
+        tx.commit().map_err(sql_error)?;
+        Ok(name)
+    }
+
+    fn restore(&self, name: &str) -> io::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(sql_error)?;
+        let restored = tx
+            .execute(
+                "INSERT OR REPLACE INTO guilds (id, data) SELECT id, data FROM backups WHERE name = ?1",
+                params![name],
+            )
+            .map_err(sql_error)?;
+        if restored == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no backup named {}", name),
+            ));
+        }
+        tx.commit().map_err(sql_error)
+    }
+
+    fn prune_backups(&self, keep: usize) -> io::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        let names = Self::backup_names(&conn)?;
+        let excess = names.len().saturating_sub(keep);
+        for name in &names[..excess] {
+            conn.execute(
+                "DELETE FROM backups WHERE name = ?1",
+                params![name.to_string()],
+            )
+            .map_err(sql_error)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{self, Clock as _};
+
+    fn memory_store() -> SqliteStore {
+        SqliteStore::from_connection(Connection::open_in_memory().unwrap()).unwrap()
+    }
+
+    fn guild_joins() -> GuildJoins {
+        GuildJoins::new(24, 0.1, &clock::MockClock::new(3600 * 1000))
+    }
+
+    #[test]
+    fn saves_and_loads_guilds() {
+        let store = memory_store();
+        let gj = guild_joins();
+        assert_eq!(
+            store.load(id::GuildId(1)).err().unwrap().kind(),
+            io::ErrorKind::NotFound
+        );
+        // IDs above `i64::MAX` are stored with the same bits
+        for &guild in &[id::GuildId(1), id::GuildId(u64::MAX)] {
+            store.save(guild, &gj).unwrap();
+            assert_eq!(
+                serde_json::to_value(store.load(guild).unwrap()).unwrap(),
+                serde_json::to_value(&gj).unwrap()
+            );
+        }
+        let mut guilds = store.guilds().unwrap();
+        guilds.sort();
+        assert_eq!(guilds, [id::GuildId(1), id::GuildId(u64::MAX)]);
+
+        store.delete(id::GuildId(1)).unwrap();
+        store.delete(id::GuildId(1)).unwrap();
+        assert_eq!(store.guilds().unwrap(), [id::GuildId(u64::MAX)]);
+    }
+
+    #[test]
+    fn quarantines_guilds() {
+        let store = memory_store();
+        store.save(id::GuildId(1), &guild_joins()).unwrap();
+        store.quarantine(id::GuildId(1)).unwrap();
+        assert!(store.guilds().unwrap().is_empty());
+    }
+
+    #[test]
+    fn restores_backups() {
+        let store = memory_store();
+        store.save(id::GuildId(1), &guild_joins()).unwrap();
+        let first = store.backup().unwrap();
+        store.save(id::GuildId(2), &guild_joins()).unwrap();
+        let second = store.backup().unwrap();
+        assert_ne!(first, second);
+
+        store.delete(id::GuildId(1)).unwrap();
+        store.delete(id::GuildId(2)).unwrap();
+        store.restore(&first).unwrap();
+        assert_eq!(store.guilds().unwrap(), [id::GuildId(1)]);
+        assert_eq!(
+            store.restore("1").unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+
+        store.prune_backups(1).unwrap();
+        let conn = store.conn.lock().unwrap();
+        let names: Vec<_> = SqliteStore::backup_names(&conn)
+            .unwrap()
+            .iter()
+            .map(|name| name.to_string())
+            .collect();
+        assert_eq!(names, [second]);
+    }
+
+    #[test]
+    fn imports_json_files() {
+        let data_dir = std::env::temp_dir().join(format!(
+            "boredphoton-import-{}-{}",
+            std::process::id(),
+            clock::SystemClock.now_secs()
+        ));
+        std::fs::create_dir_all(&data_dir).unwrap();
+        let json = JsonStore::new(data_dir.clone());
+        let gj = guild_joins();
+        json.save(id::GuildId(1), &gj).unwrap();
+        json.save(id::GuildId(2), &gj).unwrap();
+        std::fs::write(data_dir.join("monitoring.json"), b"[]").unwrap();
+
+        let store = memory_store();
+        assert_eq!(store.import_json(&data_dir).unwrap(), 2);
+        let mut guilds = store.guilds().unwrap();
+        guilds.sort();
+        assert_eq!(guilds, [id::GuildId(1), id::GuildId(2)]);
+        assert_eq!(
+            serde_json::to_value(store.load(id::GuildId(2)).unwrap()).unwrap(),
+            serde_json::to_value(&gj).unwrap()
+        );
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+}
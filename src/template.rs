@@ -0,0 +1,137 @@
+use std::fmt;
+
+/// Placeholders available in alert templates.
+pub const PLACEHOLDERS: &[&str] = &[
+    "mention", "level", "guild", "current", "uq", "mean", "median", "stats",
+];
+
+/// A message with `{placeholder}`s, where `{{` and `}}` are literal braces.
+#[derive(Clone)]
+pub struct Template {
+    template: String,
+}
+
+#[derive(Debug)]
+pub enum TemplateError {
+    UnknownPlaceholder(String),
+    Unclosed,
+    UnmatchedBrace,
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::UnknownPlaceholder(name) => write!(
+                f,
+                "unknown placeholder `{{{}}}`, expected one of {}",
+                name,
+                PLACEHOLDERS.join(", ")
+            ),
+            Self::Unclosed => f.write_str("unclosed `{`"),
+            Self::UnmatchedBrace => f.write_str("unmatched `}`, write `}}` for a literal brace"),
+        }
+    }
+}
+
+impl Template {
+    /// Checks that the template only contains known placeholders.
+    pub fn parse(template: String) -> Result<Self, TemplateError> {
+        expand(&template, |name| {
+            PLACEHOLDERS.contains(&name).then(String::new)
+        })?;
+        Ok(Self { template })
+    }
+
+    /// Replaces each placeholder with its value from `value`.
+    pub fn render(&self, value: impl Fn(&str) -> String) -> String {
+        expand(&self.template, |name| Some(value(name)))
+            .expect("the template was validated in parse")
+    }
+}
+
+fn expand(template: &str, value: impl Fn(&str) -> Option<String>) -> Result<String, TemplateError> {
+    let mut out = String::with_capacity(template.len());
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                out.push('{');
+            }
+            '{' => {
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some('}') => break,
+                        Some(c) => name.push(c),
+                        None => return Err(TemplateError::Unclosed),
+                    }
+                }
+                match value(&name) {
+                    Some(value) => out += &value,
+                    None => return Err(TemplateError::UnknownPlaceholder(name)),
+                }
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                out.push('}');
+            }
+            '}' => return Err(TemplateError::UnmatchedBrace),
+            c => out.push(c),
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn all_placeholders_are_rendered() {
+        let template: String = PLACEHOLDERS
+            .iter()
+            .map(|name| format!("{}={{{}}} ", name, name))
+            .collect();
+        let rendered = Template::parse(template)
+            .unwrap()
+            .render(|name| name.to_uppercase());
+        assert_eq!(
+            rendered,
+            "mention=MENTION level=LEVEL guild=GUILD current=CURRENT uq=UQ \
+             mean=MEAN median=MEDIAN stats=STATS "
+        );
+    }
+
+    #[test]
+    fn doubled_braces_are_literal() {
+        let template = Template::parse("{{{current}}} {{}}".into()).unwrap();
+        assert_eq!(template.render(|_| "5".into()), "{5} {}");
+    }
+
+    #[test]
+    fn unknown_placeholders_are_rejected() {
+        match Template::parse("joins: {joins}".into()) {
+            Err(TemplateError::UnknownPlaceholder(name)) => assert_eq!(name, "joins"),
+            _ => panic!("unknown placeholder accepted"),
+        }
+        let err = Template::parse("{}".into()).err().unwrap();
+        assert!(err.to_string().starts_with("unknown placeholder `{}`"));
+    }
+
+    #[test]
+    fn unbalanced_braces_are_rejected() {
+        assert!(matches!(
+            Template::parse("{current".into()),
+            Err(TemplateError::Unclosed)
+        ));
+        assert!(matches!(
+            Template::parse("current}".into()),
+            Err(TemplateError::UnmatchedBrace)
+        ));
+        assert!(matches!(
+            Template::parse("{current}}".into()),
+            Err(TemplateError::UnmatchedBrace)
+        ));
+    }
+}
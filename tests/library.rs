@@ -0,0 +1,49 @@
+//! Uses the detection as a library, without running the bot.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use boredphoton::clock::Clock;
+use boredphoton::joins::{GuildJoinsMap, PercentileMethod, Severity, ThresholdConfig};
+use boredphoton::store::JsonStore;
+use serenity::model::id::GuildId;
+
+/// A clock moved by the test, shared with the map.
+struct ManualClock(Arc<AtomicU64>);
+
+impl Clock for ManualClock {
+    fn now_secs(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+#[test]
+fn joins_observed_by_another_bot_are_detected_and_persisted() {
+    let data_dir = std::env::temp_dir().join(format!("boredphoton-library-{}", std::process::id()));
+    std::fs::create_dir_all(&data_dir).unwrap();
+    let now = Arc::new(AtomicU64::new(3600 * 100_000));
+    let map = GuildJoinsMap::new(
+        Box::new(JsonStore::new(data_dir.clone())),
+        Box::new(ManualClock(Arc::clone(&now))),
+        720,
+        0.01,
+        PercentileMethod::default(),
+        None,
+        Duration::ZERO,
+    );
+    let guild = GuildId(1);
+    let threshold = ThresholdConfig::default();
+
+    for _ in 0..48 {
+        let stat = map.add(guild, 2).unwrap();
+        assert_eq!(stat.severity(&threshold), Severity::Normal);
+        now.fetch_add(3600, Ordering::SeqCst);
+    }
+    let stat = map.add(guild, 40).unwrap();
+    assert_ne!(stat.severity(&threshold), Severity::Normal);
+
+    map.save().unwrap();
+    assert!(data_dir.join("1.json").exists());
+    std::fs::remove_dir_all(&data_dir).unwrap();
+}